@@ -0,0 +1,28 @@
+use {
+    crate::position::Position,
+    serde::{Deserialize, Serialize},
+};
+
+/// Which character an anchor sticks to, which decides where it ends up when text is inserted right at it.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Gravity {
+    /// Sticks to the character before the anchor, so text inserted at the anchor ends up after it.
+    Left,
+    /// Sticks to the character after the anchor, so text inserted at the anchor ends up before it.
+    Right,
+}
+
+/// A point in the document that moves along with the text around it, e.g. for bookmarks, diagnostics or breakpoints.
+/// Anchors are bound to the position identifier of a character, so they keep their place while other parts of the
+/// document change. Use `Document::anchor` to create one, and `Document::resolve` to find where it currently is.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Anchor {
+    pub position: Position,
+    pub gravity: Gravity,
+}
+
+impl Anchor {
+    pub fn new(position: Position, gravity: Gravity) -> Self {
+        Self { position, gravity }
+    }
+}
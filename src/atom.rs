@@ -1,4 +1,4 @@
-use crate::{position::Position, id::Id};
+use crate::position::Position;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
@@ -31,6 +31,12 @@ impl Atom {
         }
     }
 
+    /// The site that authored this atom, which is carried by the last `Id` of its position.
+    /// An atom without a position (which should never happen) has no author.
+    pub fn site(&self) -> Option<i64> {
+        self.position.0.last().map(|id| id.site)
+    }
+
     pub fn create(c: char, site: i64, c1: &Atom, c2: &Atom) -> Self {
         Self {
             position: Position::create(site, &c1.position.0, &c2.position.0),
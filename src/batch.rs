@@ -0,0 +1,94 @@
+use {
+    crate::node::Event,
+    std::{mem, time::Duration},
+    tokio::time::Instant,
+};
+
+/// The most events that will be held in a single batch before it's sent regardless of its window.
+pub const MAX_EVENTS: usize = 512;
+
+/// Collects the events headed to a peer so that they can be sent as a single message.
+/// A batch is due once `window` has passed since its first event was queued.
+#[derive(Debug)]
+pub struct Batch {
+    events: Vec<Event>,
+    started: Option<Instant>,
+    window: Duration,
+}
+
+impl Batch {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            events: Vec::new(),
+            started: None,
+            window,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        if self.events.is_empty() {
+            self.started = Some(Instant::now());
+        }
+
+        self.events.push(event);
+    }
+
+    /// The time at which the batch has to be sent, if it holds any events.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|started| started + self.window)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.deadline().is_some_and(|deadline| deadline <= now)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.events.len() >= MAX_EVENTS
+    }
+
+    /// Empties the batch, returning the event that should be sent in its place.
+    /// A single event is sent as is, since wrapping it would only add overhead.
+    pub fn take(&mut self) -> Option<Event> {
+        self.started = None;
+
+        match self.events.len() {
+            0 => None,
+            1 => self.events.pop(),
+            _ => Some(Event::Batch {
+                events: mem::take(&mut self.events),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Batch;
+    use crate::node::Event;
+    use std::time::Duration;
+
+    fn rejected() -> Event {
+        Event::Rejected {
+            reason: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_take() {
+        let mut batch = Batch::new(Duration::from_millis(10));
+
+        assert!(batch.take().is_none());
+        assert!(batch.deadline().is_none());
+
+        batch.push(rejected());
+
+        assert!(batch.deadline().is_some());
+        assert!(matches!(batch.take(), Some(Event::Rejected { .. })));
+
+        batch.push(rejected());
+        batch.push(rejected());
+
+        assert!(matches!(batch.take(), Some(Event::Batch { ref events }) if events.len() == 2));
+        assert!(batch.take().is_none());
+    }
+}
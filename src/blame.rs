@@ -0,0 +1,148 @@
+use {
+    crate::{
+        atom::Atom,
+        range::{Point, Range},
+    },
+    serde::Serialize,
+    std::collections::HashMap,
+};
+
+/// A span of text that was written by a single site.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Run {
+    pub site: i64,
+    pub name: String,
+    pub range: Range,
+    pub text: String,
+}
+
+/// How many characters a site wrote.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Contribution {
+    pub site: i64,
+    pub name: String,
+    pub characters: usize,
+}
+
+/// Who wrote the text of a range.
+#[derive(Debug, Serialize)]
+pub struct Blame {
+    /// The text of the range, split into runs whenever the author changes.
+    pub runs: Vec<Run>,
+    /// Every author of the range, from the one who wrote the most characters to the one who wrote the least.
+    pub contributions: Vec<Contribution>,
+}
+
+/// Attributes the text of `range` (or of the whole document if there's none) to the sites that wrote it.
+/// `atoms` are every atom of the document in order, and `name` gives the display name of a site.
+/// The author of a character is the site at the end of its position, i.e. the one that inserted it.
+pub fn blame(atoms: &[Atom], range: Option<&Range>, name: impl Fn(i64) -> String) -> Blame {
+    let mut runs: Vec<Run> = Vec::new();
+    let mut characters: HashMap<i64, usize> = HashMap::new();
+    let mut point = Point::new(0, 0);
+    let within = |point: &Point| match range {
+        Some(range) => {
+            let at = (point.row, point.column);
+            (range.start.row, range.start.column) <= at && at < (range.end.row, range.end.column)
+        }
+        None => true,
+    };
+
+    for atom in atoms {
+        let next = match atom.val {
+            '\n' => Point::new(point.row + 1, 0),
+            _ => point.clone() + (0, 1),
+        };
+
+        if let (true, Some(site)) = (within(&point), atom.site()) {
+            *characters.entry(site).or_insert(0) += 1;
+
+            match runs.last_mut() {
+                Some(run) if run.site == site => {
+                    run.range.end = next.clone();
+                    run.text.push(atom.val);
+                }
+                _ => runs.push(Run {
+                    site,
+                    name: name(site),
+                    range: Range {
+                        start: point,
+                        end: next.clone(),
+                    },
+                    text: atom.val.to_string(),
+                }),
+            }
+        }
+
+        point = next;
+    }
+
+    let mut contributions: Vec<Contribution> = characters
+        .into_iter()
+        .map(|(site, characters)| Contribution {
+            site,
+            name: name(site),
+            characters,
+        })
+        .collect();
+
+    contributions.sort_by(|a, b| b.characters.cmp(&a.characters).then(a.site.cmp(&b.site)));
+
+    Blame {
+        runs,
+        contributions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blame, Contribution};
+    use crate::{
+        atom::Atom,
+        id::Id,
+        position::Position,
+        range::{Point, Range},
+    };
+
+    #[test]
+    fn test_blame() {
+        // Site 1 wrote "ab\n", and site 2 wrote "cd" in between "a" and "b".
+        let atoms: Vec<Atom> = vec![('a', 1), ('c', 2), ('d', 2), ('b', 1), ('\n', 1)]
+            .into_iter()
+            .zip(1..)
+            .map(|((val, site), digit)| Atom::new(Position::new(&[Id::new(digit, site)]), 0, val))
+            .collect();
+        let name = |site: i64| format!("site {}", site);
+        let res = blame(&atoms, None, name);
+
+        assert_eq!(res.runs.len(), 3);
+        assert_eq!(res.runs[1].text, "cd");
+        assert_eq!(res.runs[1].range, Range::new((0, 1), (0, 3)));
+        assert_eq!(res.runs[2].range.end, Point::new(1, 0));
+        assert_eq!(
+            res.contributions,
+            vec![
+                Contribution {
+                    site: 1,
+                    name: "site 1".to_string(),
+                    characters: 3
+                },
+                Contribution {
+                    site: 2,
+                    name: "site 2".to_string(),
+                    characters: 2
+                }
+            ]
+        );
+
+        let res = blame(&atoms, Some(&Range::new((0, 2), (0, 4))), name);
+
+        assert_eq!(
+            res.runs
+                .iter()
+                .map(|run| run.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["d", "b"]
+        );
+    }
+}
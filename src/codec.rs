@@ -0,0 +1,275 @@
+use {
+    crate::{atom::Atom, id::Id, position::Position, protocol::MAX_MESSAGE},
+    flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression},
+    serde::{de, Deserialize, Deserializer, Serialize, Serializer},
+    std::{
+        convert::TryFrom,
+        io::{self, Read, Write},
+    },
+};
+
+/// Payloads smaller than this aren't worth compressing.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Compresses `buf` using deflate.
+pub fn compress(buf: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(buf)
+        .and_then(|_| encoder.finish())
+        .expect("Unable to compress payload.")
+}
+
+/// Decompresses a payload that was produced by `compress`.
+/// A payload that decompresses to more than `MAX_MESSAGE` bytes is refused, so that a small message can't make us
+/// allocate without bounds.
+pub fn decompress(buf: &[u8]) -> io::Result<Vec<u8>> {
+    // One byte more than is allowed is read, so that a payload that's too large can be told apart from one that fits.
+    let mut decoder = DeflateDecoder::new(buf).take(MAX_MESSAGE as u64 + 1);
+    let mut res = Vec::new();
+
+    decoder.read_to_end(&mut res)?;
+
+    if res.len() > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Payload decompresses to more than {} bytes.", MAX_MESSAGE),
+        ));
+    }
+
+    Ok(res)
+}
+
+/// Compact encoding for a sequence of atoms, such as the ones carried by a single operation.
+/// - Integers are written as LEB128 varints (sites are zigzag encoded first), so small values take a single byte.
+/// - Each distinct site is written once per message, and `Id`s refer to it by its index.
+/// - Each position only stores the `Id`s that follow the prefix it shares with the previous atom's position.
+///
+/// Use it with `#[serde(with = "crate::codec::compact")]` on a `Vec<Atom>`.
+pub mod compact {
+    use super::*;
+
+    pub fn serialize<S>(atoms: &[Atom], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        encode(atoms).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Atom>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let buf = Vec::<u8>::deserialize(deserializer)?;
+        decode(&buf).ok_or_else(|| de::Error::custom("Malformed compact atoms."))
+    }
+
+    pub fn encode(atoms: &[Atom]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut sites: Vec<i64> = Vec::new();
+
+        for atom in atoms {
+            for id in &atom.position.0 {
+                if !sites.contains(&id.site) {
+                    sites.push(id.site);
+                }
+            }
+        }
+
+        write_varint(&mut buf, sites.len() as u64);
+        for site in &sites {
+            write_varint(&mut buf, zigzag(*site));
+        }
+
+        write_varint(&mut buf, atoms.len() as u64);
+
+        let mut prev: &[Id] = &[];
+        for atom in atoms {
+            let ids = &atom.position.0[..];
+            let shared = prev.iter().zip(ids).take_while(|(a, b)| a == b).count();
+
+            write_varint(&mut buf, shared as u64);
+            write_varint(&mut buf, (ids.len() - shared) as u64);
+
+            for id in &ids[shared..] {
+                let site = sites.iter().position(|site| *site == id.site).unwrap();
+                write_varint(&mut buf, id.digit);
+                write_varint(&mut buf, site as u64);
+            }
+
+            write_varint(&mut buf, atom.clock);
+            write_varint(&mut buf, atom.val as u64);
+
+            prev = ids;
+        }
+
+        buf
+    }
+
+    /// Decodes atoms produced by `encode`, returning `None` if `buf` is malformed.
+    pub fn decode(mut buf: &[u8]) -> Option<Vec<Atom>> {
+        let buf = &mut buf;
+        let sites = (0..read_varint(buf)?)
+            .map(|_| read_varint(buf).map(unzigzag))
+            .collect::<Option<Vec<_>>>()?;
+        let len = read_varint(buf)?;
+        let mut atoms: Vec<Atom> = Vec::new();
+
+        for _ in 0..len {
+            let prev = atoms.last().map_or(&[][..], |atom| &atom.position.0[..]);
+            let shared = read_varint(buf)? as usize;
+            let suffix = read_varint(buf)?;
+
+            if shared > prev.len() {
+                return None;
+            }
+
+            let mut ids = prev[..shared].to_vec();
+
+            for _ in 0..suffix {
+                let digit = read_varint(buf)?;
+                let site = *sites.get(read_varint(buf)? as usize)?;
+                ids.push(Id::new(digit, site));
+            }
+
+            let clock = read_varint(buf)?;
+            let val = u32::try_from(read_varint(buf)?)
+                .ok()
+                .and_then(char::from_u32)?;
+
+            atoms.push(Atom::new(Position(ids), clock, val));
+        }
+
+        Some(atoms)
+    }
+
+    fn write_varint(buf: &mut Vec<u8>, mut val: u64) {
+        while val >= 0x80 {
+            buf.push(val as u8 | 0x80);
+            val >>= 7;
+        }
+
+        buf.push(val as u8);
+    }
+
+    fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+        let mut res = 0;
+        let mut shift = 0;
+
+        loop {
+            let (byte, rest) = buf.split_first()?;
+            *buf = rest;
+
+            if shift >= 64 {
+                return None;
+            }
+
+            res |= u64::from(byte & 0x7f) << shift;
+
+            if byte & 0x80 == 0 {
+                return Some(res);
+            }
+
+            shift += 7;
+        }
+    }
+
+    fn zigzag(val: i64) -> u64 {
+        ((val << 1) ^ (val >> 63)) as u64
+    }
+
+    fn unzigzag(val: u64) -> i64 {
+        (val >> 1) as i64 ^ -((val & 1) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compact, compress, decompress};
+    use crate::{
+        atom::Atom,
+        document::{PAGE_MAX, PAGE_MIN},
+        id::Id,
+        position::Position,
+        protocol::MAX_MESSAGE,
+    };
+    use bincode::{deserialize, serialize};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Lines(#[serde(with = "super::compact")] Vec<Atom>);
+
+    /// Simulates a few sites typing: mostly appending, with the occasional edit in the middle of a line.
+    fn typing_trace(len: usize) -> Vec<Atom> {
+        let mut atoms: Vec<Atom> = Vec::new();
+
+        for i in 0..len {
+            let site = (i / 40 % 3) as i64 + 1;
+            let index = if i % 17 == 0 {
+                atoms.len() / 2
+            } else {
+                atoms.len()
+            };
+            let min = vec![Id::new(PAGE_MIN, site)];
+            let max = vec![Id::new(PAGE_MAX, site)];
+            let before = index.checked_sub(1).map_or(&min, |i| &atoms[i].position.0);
+            let after = atoms.get(index).map_or(&max, |atom| &atom.position.0);
+            let position = Position::create(site, before, after);
+
+            atoms.insert(index, Atom::new(position, i as u64, 'a'));
+        }
+
+        atoms
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let lines = Lines(typing_trace(500));
+        let buf = serialize(&lines).unwrap();
+        let encoded = compact::encode(&lines.0);
+
+        assert_eq!(deserialize::<Lines>(&buf).unwrap(), lines);
+        assert!(compact::decode(&encoded[..encoded.len() / 2]).is_none());
+    }
+
+    #[test]
+    fn test_compact_size() {
+        let atoms = typing_trace(500);
+        let batched = (
+            serialize(&atoms).unwrap().len(),
+            serialize(&Lines(atoms.clone())).unwrap().len(),
+        );
+        let keystrokes = atoms.iter().fold((0, 0), |(bincode, compact), atom| {
+            (
+                bincode + serialize(&vec![atom.clone()]).unwrap().len(),
+                compact + serialize(&Lines(vec![atom.clone()])).unwrap().len(),
+            )
+        });
+
+        // A batch shares the prefixes of its positions, so it's far smaller than plain bincode.
+        assert!(batched.1 * 4 < batched.0);
+        assert!(batched.1 < atoms.len() * 20);
+        // A single atom has nothing to share, but varints and interned sites still save a quarter.
+        assert!(keystrokes.1 * 4 < keystrokes.0 * 3);
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let buf = serialize(&typing_trace(50)).unwrap();
+        let compressed = compress(&buf);
+
+        assert!(compressed.len() < buf.len());
+        assert_eq!(decompress(&compressed).unwrap(), buf);
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let fits = compress(&vec![0; MAX_MESSAGE]);
+        let bomb = compress(&vec![0; MAX_MESSAGE + 1]);
+
+        // Zeros compress so well that the payload is tiny, yet it would be too large once decompressed.
+        assert!(bomb.len() < MAX_MESSAGE / 100);
+        assert_eq!(decompress(&fits).unwrap().len(), MAX_MESSAGE);
+        assert!(decompress(&bomb).is_err());
+    }
+}
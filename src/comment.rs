@@ -0,0 +1,390 @@
+use {
+    crate::{
+        anchor::{Anchor, Gravity},
+        document::Document,
+        id::Id,
+        range::Range,
+    },
+    serde::{Deserialize, Serialize},
+};
+
+/// The most changes that are held back waiting for their thread, so that a peer can't make us hold on to changes for
+/// threads that it never starts. The oldest ones are dropped first.
+const MAX_PENDING: usize = 1024;
+
+/// A single message in a thread.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Comment {
+    /// Uniquely identifies the comment. The site is the site that wrote it.
+    pub id: Id,
+    pub author: String,
+    pub text: String,
+}
+
+/// A discussion about a span of the document.
+/// The span is held by anchors rather than rows and columns, so it stays on the same text while the document changes.
+/// Its start sticks to the first character and its end to the last one, so deleting part of the span only shrinks it,
+/// and deleting all of it leaves an empty span where the text used to be.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Thread {
+    /// Uniquely identifies the thread. The site is the site that started it.
+    pub id: Id,
+    pub start: Anchor,
+    pub end: Anchor,
+    pub comments: Vec<Comment>,
+    pub resolved: bool,
+}
+
+impl Thread {
+    /// The site that started the thread.
+    pub fn site(&self) -> i64 {
+        self.id.site
+    }
+
+    /// The current span of the thread.
+    pub fn range(&self, document: &Document) -> Range {
+        Range {
+            start: document.resolve(&self.start),
+            end: document.resolve(&self.end),
+        }
+    }
+
+    /// The changes that rebuild the thread, which a site that already has part of it can apply as well.
+    pub fn changes(&self) -> Vec<Change> {
+        let mut changes = vec![Change::Start(self.clone())];
+
+        changes.extend(self.comments.iter().map(|comment| Change::Reply {
+            thread: self.id.clone(),
+            comment: comment.clone(),
+        }));
+
+        if self.resolved {
+            changes.push(Change::Resolve {
+                thread: self.id.clone(),
+            });
+        }
+
+        changes
+    }
+
+    /// The thread as it's shown to the editor.
+    pub fn view(&self, document: &Document) -> View {
+        View {
+            id: self.id.clone(),
+            range: self.range(document),
+            resolved: self.resolved,
+            comments: self.comments.clone(),
+        }
+    }
+}
+
+/// A thread as it's shown to the editor, with its span resolved to rows and columns.
+#[derive(Clone, Debug, Serialize)]
+pub struct View {
+    pub id: Id,
+    pub range: Range,
+    pub resolved: bool,
+    pub comments: Vec<Comment>,
+}
+
+/// A change to the threads, which is replicated to every peer.
+/// Applying the same change twice has no effect, and threads are never removed, so changes can be applied in any
+/// order. Changes to a thread that doesn't exist yet are held back until it's started.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Change {
+    Start(Thread),
+    Reply { thread: Id, comment: Comment },
+    Resolve { thread: Id },
+}
+
+impl Change {
+    /// The thread that the change applies to.
+    pub fn thread(&self) -> &Id {
+        match self {
+            Change::Start(thread) => &thread.id,
+            Change::Reply { thread, .. } | Change::Resolve { thread } => thread,
+        }
+    }
+
+    /// The site that made the change, if it can be told from the change itself.
+    pub fn author(&self) -> Option<i64> {
+        match self {
+            Change::Start(thread) => Some(thread.site()),
+            Change::Reply { comment, .. } => Some(comment.id.site),
+            Change::Resolve { .. } => None,
+        }
+    }
+}
+
+/// Every thread in the document.
+#[derive(Debug)]
+pub struct Threads {
+    site: i64,
+    /// A Lamport clock, used to create the ids of threads and comments.
+    clock: u64,
+    threads: Vec<Thread>,
+    /// Replies and resolutions that arrived before the thread they refer to.
+    pending: Vec<Change>,
+}
+
+impl Threads {
+    pub fn new(site: i64) -> Self {
+        Self {
+            site,
+            clock: 0,
+            threads: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Starts a thread about the text from `range.start` until `range.end`.
+    pub fn start(
+        &mut self,
+        document: &Document,
+        range: &Range,
+        author: &str,
+        text: &str,
+    ) -> Change {
+        let thread = Thread {
+            id: self.next_id(),
+            start: document.anchor(&range.start, Gravity::Right),
+            end: document.anchor(&range.end, Gravity::Left),
+            comments: vec![self.comment(author, text)],
+            resolved: false,
+        };
+
+        self.threads.push(thread.clone());
+
+        Change::Start(thread)
+    }
+
+    /// Replies to `thread`, or returns `None` if it doesn't exist.
+    pub fn reply(&mut self, thread: &Id, author: &str, text: &str) -> Option<Change> {
+        self.get(thread)?;
+
+        let change = Change::Reply {
+            thread: thread.clone(),
+            comment: self.comment(author, text),
+        };
+
+        self.apply(change.clone());
+
+        Some(change)
+    }
+
+    /// Marks `thread` as resolved, or returns `None` if it doesn't exist.
+    pub fn resolve(&mut self, thread: &Id) -> Option<Change> {
+        let change = Change::Resolve {
+            thread: self.get(thread)?.id.clone(),
+        };
+
+        self.apply(change.clone());
+
+        Some(change)
+    }
+
+    /// Applies a change made by any site, returning the thread that changed.
+    /// Returns `None` if nothing changed. A change to a thread that doesn't exist yet is kept until the thread is
+    /// started, since events from different sites may arrive in any order.
+    pub fn apply(&mut self, change: Change) -> Option<&Thread> {
+        self.witness(&change);
+
+        let i = self
+            .threads
+            .iter()
+            .position(|thread| thread.id == *change.thread());
+
+        match (change, i) {
+            (Change::Start(thread), None) => {
+                let id = thread.id.clone();
+                let (pending, rest) = self
+                    .pending
+                    .drain(..)
+                    .partition(|change| *change.thread() == id);
+
+                self.pending = rest;
+                self.threads.push(thread);
+
+                let thread = self.threads.last_mut()?;
+
+                for change in pending {
+                    Self::update(thread, change);
+                }
+
+                Some(thread)
+            }
+            (Change::Start(_), Some(_)) => None,
+            (change, None) => {
+                if self.pending.len() == MAX_PENDING {
+                    self.pending.remove(0);
+                }
+
+                self.pending.push(change);
+                None
+            }
+            (change, Some(i)) => {
+                let thread = &mut self.threads[i];
+
+                if Self::update(thread, change) {
+                    Some(thread)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: &Id) -> Option<&Thread> {
+        self.threads.iter().find(|thread| thread.id == *id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.iter()
+    }
+
+    /// Applies a reply or resolution to `thread`, returning whether it changed.
+    fn update(thread: &mut Thread, change: Change) -> bool {
+        match change {
+            Change::Reply { comment, .. } if !thread.comments.contains(&comment) => {
+                thread.comments.push(comment);
+                // Replies from different sites may arrive in any order, so every site sorts them the same way.
+                thread
+                    .comments
+                    .sort_by_key(|comment| (comment.id.digit, comment.id.site));
+                true
+            }
+            Change::Resolve { .. } if !thread.resolved => {
+                thread.resolved = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn comment(&mut self, author: &str, text: &str) -> Comment {
+        Comment {
+            id: self.next_id(),
+            author: author.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    /// Moves the clock past every id in `change`, so that our next comment sorts after the ones we've seen.
+    fn witness(&mut self, change: &Change) {
+        let digit = match change {
+            Change::Start(thread) => thread
+                .comments
+                .iter()
+                .map(|comment| comment.id.digit)
+                .fold(thread.id.digit, u64::max),
+            Change::Reply { comment, .. } => comment.id.digit,
+            Change::Resolve { .. } => 0,
+        };
+
+        self.clock = self.clock.max(digit);
+    }
+
+    fn next_id(&mut self) -> Id {
+        self.clock += 1;
+        Id::new(self.clock, self.site)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Threads, MAX_PENDING};
+    use crate::{document::Document, id::Id, range::Range};
+
+    #[test]
+    fn test_replicate() {
+        let doc = Document::new(1);
+        let range = Range::new((0, 0), (0, 0));
+        let mut ours = Threads::new(1);
+        let mut theirs = Threads::new(2);
+
+        let start = ours.start(&doc, &range, "mark", "Why?");
+        let id = start.thread().clone();
+
+        assert!(theirs.apply(start.clone()).is_some());
+        assert!(theirs.apply(start).is_none());
+
+        let reply = theirs.reply(&id, "anna", "Because.").unwrap();
+        let resolve = theirs.resolve(&id).unwrap();
+
+        assert!(ours.apply(resolve).is_some());
+        assert!(ours.apply(reply.clone()).is_some());
+        assert!(ours.apply(reply).is_none());
+        assert_eq!(ours.get(&id), theirs.get(&id));
+        assert!(ours.get(&id).unwrap().resolved);
+        assert_eq!(ours.get(&id).unwrap().comments[1].author, "anna");
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let doc = Document::new(1);
+        let range = Range::new((0, 0), (0, 0));
+        let mut ours = Threads::new(1);
+        let mut theirs = Threads::new(2);
+        let mut late = Threads::new(3);
+
+        let start = ours.start(&doc, &range, "mark", "Why?");
+        let id = start.thread().clone();
+
+        theirs.apply(start.clone());
+
+        let reply = theirs.reply(&id, "anna", "Because.").unwrap();
+        let resolve = theirs.resolve(&id).unwrap();
+
+        // The reply and resolution overtook the thread they belong to.
+        assert!(late.apply(reply).is_none());
+        assert!(late.apply(resolve).is_none());
+        assert!(late.get(&id).is_none());
+        assert_eq!(late.apply(start), theirs.get(&id));
+    }
+
+    #[test]
+    fn test_unknown_thread() {
+        let mut threads = Threads::new(1);
+        let id = Id::new(1, 2);
+
+        assert!(threads.reply(&id, "mark", "?").is_none());
+        assert!(threads.resolve(&id).is_none());
+        assert!(threads.apply(Change::Resolve { thread: id }).is_none());
+    }
+
+    #[test]
+    fn test_pending_limit() {
+        let mut threads = Threads::new(1);
+
+        for digit in 0..=MAX_PENDING as u64 {
+            let thread = Id::new(digit, 2);
+            threads.apply(Change::Resolve { thread });
+        }
+
+        // The oldest change was dropped to make room.
+        assert_eq!(threads.pending.len(), MAX_PENDING);
+        assert_eq!(*threads.pending[0].thread(), Id::new(1, 2));
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let doc = Document::new(1);
+        let range = Range::new((0, 0), (0, 0));
+        let mut ours = Threads::new(1);
+        let mut theirs = Threads::new(2);
+
+        let start = ours.start(&doc, &range, "mark", "Why?");
+        let id = start.thread().clone();
+
+        theirs.apply(start);
+        ours.reply(&id, "mark", "Anyone?");
+        ours.resolve(&id);
+
+        // A site that only has the start of the thread catches up on the rest.
+        for change in ours.get(&id).unwrap().changes() {
+            theirs.apply(change);
+        }
+
+        assert_eq!(ours.get(&id), theirs.get(&id));
+    }
+}
@@ -1,4 +1,4 @@
-use {clap::Clap, serde::Deserialize, std::fs::read_to_string, toml::from_str};
+use {crate::role::Role, clap::Clap, serde::Deserialize, std::fs::read_to_string, toml::from_str};
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Mark P. <markrepedersen@gmail.com>")]
@@ -13,10 +13,69 @@ struct Opts {
     #[clap(short, long)]
     addr: Option<String>,
 
-    /// Specifies the client IP/port combinations.
-    /// - Each combination must be of the form "<addr>:<port>".
+    /// Specifies the site of this node, which must be different from the site of every other node in the session.
+    /// - This must be given if no config file is given, and takes precedence over the config file otherwise.
+    #[clap(long)]
+    site: Option<i64>,
+
+    /// Specifies the file holding this node's signing keypair.
+    /// - If the file doesn't exist, then a new keypair will be generated and saved there.
+    #[clap(short, long)]
+    keypair: Option<String>,
+
+    /// Trusts the public key of another site, whose operations are rejected otherwise.
+    /// - Each key must be of the form "<site>:<base64 key>", and the option may be given once per site.
+    /// - These keys are trusted along with the ones in the config file.
     #[clap(short, long)]
-    clients: Option<Vec<String>>,
+    trusted: Vec<String>,
+
+    /// Speaks the frontend protocol over stdin and stdout instead of connecting to the editor.
+    /// - This is meant for editor plugins that spawn the node as a child process.
+    #[clap(long)]
+    stdio: bool,
+
+    /// Specifies a file to write logs to instead of stderr.
+    #[clap(long)]
+    log: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Shares a file from disk, writing the changes made to it back to the file.
+    Share {
+        /// The file to share.
+        path: String,
+    },
+    /// Applies a unified diff to a file on disk. A node sharing the file picks up the change.
+    Patch {
+        /// The file holding the diff.
+        diff: String,
+        /// The file to patch. By default, this is the file named by the diff.
+        #[clap(long)]
+        file: Option<String>,
+        /// The number of context lines that may be ignored on either side of a hunk that doesn't match.
+        #[clap(long, default_value = "2")]
+        fuzz: usize,
+    },
+    /// Prints a document as it was at a past moment, rebuilt from the write-ahead log.
+    Show {
+        /// The directory of the write-ahead log.
+        wal: String,
+        /// The moment to go back to: a time in milliseconds since the Unix epoch, or a version such as "1:12,2:7".
+        #[clap(long)]
+        at: String,
+        /// The document to show. By default, this is the default document.
+        #[clap(long, default_value = "")]
+        document: String,
+    },
+}
+
+/// The command given on the command line, if any.
+pub fn command() -> Option<Command> {
+    Opts::parse().command
 }
 
 #[derive(Deserialize, Debug)]
@@ -26,12 +85,8 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(host: String, port: u16) -> Self {
-        Self { host, port }
-    }
-
-    pub fn parse(v: &String) -> Self {
-        let split: Vec<&str> = v.split(":").collect();
+    pub fn parse(v: &str) -> Self {
+        let split: Vec<&str> = v.split(':').collect();
 
         if split.len() < 2 {
             panic!("Error parsing config file.");
@@ -46,11 +101,125 @@ impl Client {
     }
 }
 
+/// How the node talks to the editor.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frontend {
+    /// Connect to an editor that is listening on a TCP port.
+    Tcp { host: String, port: u16 },
+    /// Use stdin and stdout. Nothing else may be written to stdout, so logs go to stderr or a file.
+    Stdio,
+    /// Listen on a Unix domain socket, which only the current user may connect to, and wait for the editor.
+    Unix { path: String },
+    /// Listen for a browser based editor connecting over a WebSocket. Requires the "websocket" feature.
+    /// Only pages served from one of `origins` (or from the node's own address) may connect.
+    #[serde(rename = "websocket")]
+    WebSocket {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        origins: Vec<String>,
+    },
+    /// Listen for Neovim connecting over msgpack-rpc and share its current buffer. Requires the "neovim" feature.
+    Neovim { host: String, port: u16 },
+}
+
+impl Default for Frontend {
+    fn default() -> Self {
+        Frontend::Tcp {
+            host: "localhost".to_string(),
+            port: 2001,
+        }
+    }
+}
+
+/// The public key of a site whose operations we accept.
+#[derive(Deserialize, Debug)]
+pub struct TrustedKey {
+    pub site: i64,
+    /// The base64 encoded ed25519 public key.
+    pub key: String,
+}
+
+impl TrustedKey {
+    /// Parses a key given on the command line as "<site>:<base64 key>".
+    pub fn parse(v: &str) -> Result<Self, String> {
+        let mut parts = v.splitn(2, ':');
+        let site = parts.next().and_then(|site| site.trim().parse().ok());
+
+        match (site, parts.next()) {
+            (Some(site), Some(key)) => Ok(TrustedKey {
+                site,
+                key: key.trim().to_string(),
+            }),
+            _ => Err(format!(
+                "Invalid trusted key {:?}, expected \"<site>:<base64 key>\".",
+                v
+            )),
+        }
+    }
+}
+
+/// The role given to a site whenever it joins the session.
+#[derive(Deserialize, Debug)]
+pub struct Assignment {
+    pub site: i64,
+    pub role: Role,
+}
+
 /// Represents the contents of a client's config file. Information within will include the following:
-/// - A list of any other clients that this client knows about
+/// - The site of this node, which tells its operations apart from those of every other node
+/// - A list of any other clients that this client joins when it starts
+/// - The keypair used to sign operations and the public keys of trusted sites
+/// - The role this node requests and, for the owner, the roles handed out to everyone else
+/// - How outgoing events are batched and whether they may be compressed
+/// - How to reach the editor and where to write logs
+/// - How this node is shown to other participants
+/// - Which file is shared and how often it's saved
+/// - Where operations are logged so that they survive a crash
 #[derive(Deserialize)]
 pub struct Config {
     pub addr: Client,
+    /// Uniquely identifies this node in the session.
+    pub site: i64,
+    /// The nodes whose session this node joins when it starts.
+    #[serde(default)]
+    pub peers: Vec<Client>,
+    #[serde(default)]
+    pub frontend: Frontend,
+    /// The file to write logs to. By default, logs are written to stderr.
+    pub log: Option<String>,
+    pub keypair: Option<String>,
+    #[serde(default)]
+    pub trusted: Vec<TrustedKey>,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub default_role: Role,
+    #[serde(default)]
+    pub roles: Vec<Assignment>,
+    /// The number of milliseconds that outgoing events are held back so that they can be sent together.
+    #[serde(default = "Config::default_batch_window")]
+    pub batch_window: u64,
+    #[serde(default = "Config::default_compression")]
+    pub compression: bool,
+    /// The name shown to other participants. By default, this is the name of the current user.
+    pub name: Option<String>,
+    /// The colour of this node's cursor, e.g. "#4363d8". By default, one is picked from a palette.
+    pub colour: Option<String>,
+    /// The file that is shared as the default document, which is also set by the "share" command.
+    pub share: Option<String>,
+    /// The number of seconds between writing the shared file back to disk. It's always written when the node stops.
+    #[serde(default = "Config::default_save_interval")]
+    pub save_interval: u64,
+    /// The number of milliseconds between checking whether the shared file was changed outside of the editor.
+    #[serde(default = "Config::default_watch_interval")]
+    pub watch_interval: u64,
+    /// The directory that every operation is logged to, so that the documents can be recovered if the node crashes.
+    pub wal: Option<String>,
+    /// The number of seconds between snapshots of the documents, after which the log they cover is deleted.
+    #[serde(default = "Config::default_snapshot_interval")]
+    pub snapshot_interval: u64,
 }
 
 impl Config {
@@ -60,8 +229,51 @@ impl Config {
                 .addr
                 .expect("<addr> argument must be specified if no config file is given."),
         );
+        let site = opts
+            .site
+            .expect("<site> argument must be specified if no config file is given.");
 
-        Ok(Config { addr })
+        Ok(Config {
+            addr,
+            site,
+            peers: Vec::new(),
+            frontend: Frontend::default(),
+            log: None,
+            keypair: opts.keypair,
+            trusted: Vec::new(),
+            role: Role::default(),
+            default_role: Role::default(),
+            roles: Vec::new(),
+            batch_window: Self::default_batch_window(),
+            compression: Self::default_compression(),
+            name: None,
+            colour: None,
+            share: None,
+            save_interval: Self::default_save_interval(),
+            watch_interval: Self::default_watch_interval(),
+            wal: None,
+            snapshot_interval: Self::default_snapshot_interval(),
+        })
+    }
+
+    fn default_batch_window() -> u64 {
+        20
+    }
+
+    fn default_compression() -> bool {
+        true
+    }
+
+    fn default_save_interval() -> u64 {
+        5
+    }
+
+    fn default_watch_interval() -> u64 {
+        1000
+    }
+
+    fn default_snapshot_interval() -> u64 {
+        60
     }
 
     fn parse_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
@@ -70,11 +282,40 @@ impl Config {
     }
 
     /// Parses the contents of a config file.
+    /// The `--site`, `--stdio` and `--log` arguments, as well as the file given to the "share" command, take precedence over the
+    /// config file. Keys given with `--trusted` are added to the ones in the config file.
     pub fn parse() -> Result<Self, Box<dyn std::error::Error>> {
-        let opts: Opts = Opts::parse();
-        match opts.config {
-            Some(path) => Self::parse_file(&path),
-            None => Self::parse_args(opts),
+        let mut opts: Opts = Opts::parse();
+        let (site, stdio, log) = (opts.site, opts.stdio, opts.log.clone());
+        let trusted = opts
+            .trusted
+            .iter()
+            .map(|v| TrustedKey::parse(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        let command = opts.command.take();
+        let mut config = match opts.config {
+            Some(ref path) => Self::parse_file(path)?,
+            None => Self::parse_args(opts)?,
+        };
+
+        if let Some(site) = site {
+            config.site = site;
+        }
+
+        if stdio {
+            config.frontend = Frontend::Stdio;
         }
+
+        if log.is_some() {
+            config.log = log;
+        }
+
+        config.trusted.extend(trusted);
+
+        if let Some(Command::Share { path }) = command {
+            config.share = Some(path);
+        }
+
+        Ok(config)
     }
 }
@@ -0,0 +1,305 @@
+use {
+    crate::range::{Point, Range},
+    std::ops,
+};
+
+/// A span that differs between two sequences: `old` is replaced by `new`.
+/// Either span may be empty, for a pure insertion or deletion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hunk {
+    pub old: ops::Range<usize>,
+    pub new: ops::Range<usize>,
+}
+
+/// Hunks of lines that span more characters than this are replaced as a whole, rather than being compared character by
+/// character, which takes time proportional to their length times the number of characters that changed.
+pub const REFINE_LIMIT: usize = 10_000;
+
+/// Finds the shortest edit script that turns `a` into `b` using Myers' algorithm, returning the spans that differ in
+/// order. See "An O(ND) Difference Algorithm and Its Variations" (Myers, 1986).
+/// This is the linear space variant, which splits the problem at the middle of the edit script and solves both halves
+/// on their own, so memory only grows with the length of the sequences rather than with the number of edits.
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Hunk> {
+    let mut hunks = Vec::new();
+
+    split(a, b, 0, 0, &mut hunks);
+
+    hunks
+}
+
+/// Appends the hunks that turn `a` into `b` to `hunks`, where `a` starts at `x` and `b` at `y` in the whole sequences.
+fn split<T: PartialEq>(a: &[T], b: &[T], x: usize, y: usize, hunks: &mut Vec<Hunk>) {
+    let prefix = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    let (a, b, x, y) = (&a[prefix..], &b[prefix..], x + prefix, y + prefix);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    if a.is_empty() || b.is_empty() {
+        if !a.is_empty() || !b.is_empty() {
+            push(hunks, x..x + a.len(), y..y + b.len());
+        }
+
+        return;
+    }
+
+    let (start, end) = middle_snake(a, b);
+
+    split(&a[..start.0], &b[..start.1], x, y, hunks);
+    split(&a[end.0..], &b[end.1..], x + end.0, y + end.1, hunks);
+}
+
+/// Finds the snake (a run of matches) in the middle of the shortest edit script, by searching from both ends at once
+/// until the searches overlap. Returns where the snake starts and ends.
+/// `a` and `b` must not be empty, and must differ in their first and last elements.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> ((usize, usize), (usize, usize)) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // The furthest x reached on each diagonal k (which is x - y), from the start and from the end respectively.
+    // Going backwards, x and y count from the end of the sequences, so its diagonal k is the diagonal delta - k going
+    // forwards.
+    let mut forward = vec![0; 2 * offset as usize + 1];
+    let mut backward = vec![0; 2 * offset as usize + 1];
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let start = (x, x - k);
+
+            while x < n && x - k < m && a[x as usize] == b[(x - k) as usize] {
+                x += 1;
+            }
+
+            forward[i] = x;
+
+            // With an odd delta, the searches can only meet after a forward step.
+            let reverse = delta - k;
+            if delta % 2 != 0 && reverse.abs() < d && x + backward[(reverse + offset) as usize] >= n
+            {
+                let start = (start.0 as usize, start.1 as usize);
+                return (start, (x as usize, (x - k) as usize));
+            }
+        }
+
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && backward[i - 1] < backward[i + 1]) {
+                backward[i + 1]
+            } else {
+                backward[i - 1] + 1
+            };
+            let end = (n - x, m - (x - k));
+
+            while x < n && x - k < m && a[(n - x - 1) as usize] == b[(m - (x - k) - 1) as usize] {
+                x += 1;
+            }
+
+            backward[i] = x;
+
+            let reverse = delta - k;
+            if delta % 2 == 0 && reverse.abs() <= d && x + forward[(reverse + offset) as usize] >= n
+            {
+                let start = ((n - x) as usize, (m - (x - k)) as usize);
+                return (start, (end.0 as usize, end.1 as usize));
+            }
+        }
+    }
+
+    unreachable!("The searches always meet within (n + m) / 2 steps.")
+}
+
+/// Appends a hunk, merging it into the last one if they're next to each other.
+fn push(hunks: &mut Vec<Hunk>, old: ops::Range<usize>, new: ops::Range<usize>) {
+    match hunks.last_mut() {
+        Some(hunk) if hunk.old.end == old.start && hunk.new.end == new.start => {
+            hunk.old.end = old.end;
+            hunk.new.end = new.end;
+        }
+        _ => hunks.push(Hunk { old, new }),
+    }
+}
+
+/// The edits that turn `old` into `new`, each being the range of the text to replace and the text that replaces it.
+/// Lines are compared first, and the characters of the lines that changed are then compared, so that an edit only
+/// covers the characters that actually changed. Hunks longer than `REFINE_LIMIT` are replaced whole instead.
+///
+/// The edits are returned from the end of the text to the start, so that each one can be applied as is after the
+/// ones before it.
+pub fn edits(old: &str, new: &str) -> Vec<(Range, String)> {
+    let (old_lines, new_lines) = (lines(old), lines(new));
+    let mut res = Vec::new();
+
+    for hunk in diff(&old_lines, &new_lines) {
+        let a: Vec<char> = old_lines[hunk.old.clone()].concat().chars().collect();
+        let b: Vec<char> = new_lines[hunk.new].concat().chars().collect();
+        let start = Point::new(hunk.old.start, 0);
+        let hunks = if a.len() + b.len() > REFINE_LIMIT {
+            vec![Hunk {
+                old: 0..a.len(),
+                new: 0..b.len(),
+            }]
+        } else {
+            diff(&a, &b)
+        };
+
+        for hunk in hunks {
+            let range = Range {
+                start: advance(start.clone(), &a[..hunk.old.start]),
+                end: advance(start.clone(), &a[..hunk.old.end]),
+            };
+
+            res.push((range, b[hunk.new].iter().collect()));
+        }
+    }
+
+    res.reverse();
+    res
+}
+
+/// Splits `text` into lines, each of which keeps its "\n".
+pub fn lines(text: &str) -> Vec<&str> {
+    let mut res = Vec::new();
+    let mut rest = text;
+
+    while let Some(i) = rest.find('\n') {
+        res.push(&rest[..=i]);
+        rest = &rest[i + 1..];
+    }
+
+    if !rest.is_empty() {
+        res.push(rest);
+    }
+
+    res
+}
+
+/// The point after moving past `text`, starting at `point`.
+fn advance(mut point: Point, text: &[char]) -> Point {
+    for c in text {
+        if *c == '\n' {
+            point.row += 1;
+            point.column = 0;
+        } else {
+            point.column += 1;
+        }
+    }
+
+    point
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, edits, Hunk, REFINE_LIMIT};
+    use crate::range::{Point, Range};
+
+    /// Applies the edits to `text` the way the document would.
+    fn apply(text: &str, edits: Vec<(Range, String)>) -> String {
+        let offset = |text: &str, point: &Point| {
+            let row: usize = text
+                .split('\n')
+                .take(point.row)
+                .map(|line| line.chars().count() + 1)
+                .sum();
+            row + point.column
+        };
+
+        edits
+            .into_iter()
+            .fold(text.to_string(), |text, (range, new)| {
+                let mut chars: Vec<char> = text.chars().collect();
+                let (start, end) = (offset(&text, &range.start), offset(&text, &range.end));
+
+                chars.splice(start..end, new.chars());
+                chars.into_iter().collect()
+            })
+    }
+
+    #[test]
+    fn test_diff() {
+        let a: Vec<char> = "ABCABBA".chars().collect();
+        let b: Vec<char> = "CBABAC".chars().collect();
+        let hunks = diff(&a, &b);
+        let changed: usize = hunks
+            .iter()
+            .map(|hunk| hunk.old.len() + hunk.new.len())
+            .sum();
+
+        // The shortest edit script for this pair is known to have 5 steps.
+        assert_eq!(changed, 5);
+        assert_eq!(diff(&a, &a), vec![]);
+        assert_eq!(
+            diff(&[] as &[char], &a[..2]),
+            vec![Hunk {
+                old: 0..0,
+                new: 0..2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_edits() {
+        let old = "fn main() {\nprintln!(\"hi\");\n}\n";
+        let new = "fn main() {\n    println!(\"hello\");\n}\n\nfn other() {}\n";
+        let res = edits(old, new);
+
+        assert_eq!(
+            res.last(),
+            Some(&(Range::new((1, 0), (1, 0)), "    ".to_string()))
+        );
+        assert_eq!(apply(old, res), new);
+
+        for (old, new) in [("", "a\nb"), ("a\nb", ""), ("a\r\nb", "a\nb\n"), ("x", "x")] {
+            assert_eq!(apply(old, edits(old, new)), new);
+        }
+    }
+
+    #[test]
+    fn test_shortest() {
+        // Pairs whose shortest edit scripts are known, including ones where the searches meet going either way.
+        let pairs = [
+            ("ABCABBA", "CBABAC", 5),
+            ("abcd", "acbd", 2),
+            ("a", "b", 2),
+            ("xaxbx", "aybya", 6),
+            ("kitten", "sitting", 5),
+        ];
+
+        for (a, b, len) in pairs.iter() {
+            let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+            let hunks = diff(&a, &b);
+            let changed: usize = hunks
+                .iter()
+                .map(|hunk| hunk.old.len() + hunk.new.len())
+                .sum();
+
+            assert_eq!(changed, *len);
+            assert!(hunks
+                .windows(2)
+                .all(|pair| pair[0].old.end < pair[1].old.start));
+        }
+    }
+
+    #[test]
+    fn test_large_hunk() {
+        let old = "a".repeat(REFINE_LIMIT);
+        let new = "b".repeat(REFINE_LIMIT);
+        let res = edits(&old, &new);
+
+        assert_eq!(
+            res,
+            vec![(Range::new((0, 0), (0, REFINE_LIMIT)), new.clone())]
+        );
+        assert_eq!(apply(&old, res), new);
+    }
+}
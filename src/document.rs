@@ -1,18 +1,24 @@
-use crate::{atom::Atom, id::Id, position::Position, range::Point, range::Range};
-use std::collections::hash_map::{Entry, HashMap};
+use crate::{
+    anchor::{Anchor, Gravity},
+    atom::Atom,
+    id::Id,
+    lock::Lock,
+    position::Position,
+    range::Point,
+    range::Range,
+};
+use std::collections::HashMap;
 
 pub const NIL: char = '\0';
 pub const PAGE_MIN: u64 = 0;
 pub const PAGE_MAX: u64 = u64::MAX;
 
-pub struct RemoteInsert {}
-
-pub struct LocalInsert {}
-
 #[derive(Debug)]
 pub struct Document {
     nodes: HashMap<usize, Vec<Atom>>,
     site: i64,
+    locks: Vec<Lock>,
+    lock_clock: u64,
 }
 
 impl Document {
@@ -22,168 +28,427 @@ impl Document {
         Self {
             nodes: HashMap::new(),
             site,
+            locks: Vec::new(),
+            lock_clock: 0,
         }
     }
 
-    /// Inserts all characters in `lines` from `start` until `end`.
-    /// # Note
-    /// Since only the start and end are specified, we'll have to start inserting from the end until the beginning to cover some edge cases.
-    /// i.e. start at the end position and keep iterating until the beginning of the line is encountered. Once it is, then
-    /// go to the previous line and keep iterating until the start position.
-    pub fn local_insert(&mut self, range: &Range, lines: &[char]) {}
+    /// Inserts all characters in `lines` at `range.start`, returning the atoms that were created for them so that
+    /// they can be replicated to the other sites.
+    /// Each character is placed between the one before it and the atom that followed `range.start`, so that the
+    /// characters keep their order.
+    ///
+    /// Nothing is inserted if `range.start` lies inside of a locked span.
+    pub fn local_insert(&mut self, range: &Range, lines: &[char]) -> Option<Vec<Atom>> {
+        if lines.is_empty() || self.locked_point(&range.start).is_some() {
+            return None;
+        }
+
+        let mut atoms = self.atoms();
+        let at = self.offset(&range.start);
+        let next = atoms.get(at).cloned().unwrap_or_else(|| self.virtual_max());
+        let mut prev = at
+            .checked_sub(1)
+            .map(|i| atoms[i].clone())
+            .unwrap_or_else(|| self.virtual_min());
+        let mut res = Vec::with_capacity(lines.len());
+
+        for c in lines {
+            let atom = Atom::create(*c, self.site, &prev, &next);
+            res.push(atom.clone());
+            prev = atom;
+        }
 
-    /// Deletes all atoms from `start` until `end`.
+        atoms.splice(at..at, res.iter().cloned());
+        self.load(&atoms);
+
+        Some(res)
+    }
+
+    /// Deletes all atoms from `start` until `end`, returning them so that the deletion can be replicated.
     /// # Note
     /// Entire lines may be deleted, changing subsequent row numbers.
-    pub fn local_delete(&mut self, range: &Range) {
-        let mut curr = range.start;
-        let updated_row = None;
-
-        while let Some(mut nodes) = self.line(curr.row) {
-            match curr.row {
-                0 => {
-                    match range.end.column {
-                        0 => nodes.splice(range.start.column..range.end.column, Vec::new()),
-                        _ => nodes.splice(range.start.column.., Vec::new()),
-                    };
-                }
-                val if val < range.end.row => {
-                    nodes.clear();
-                }
-                val if val == range.end.row => {
-                    nodes.splice(0..range.end.column, Vec::new());
-                }
-                _ => break,
-            }
+    /// Nothing is deleted if any part of `range` is locked or if the range is empty.
+    pub fn local_delete(&mut self, range: &Range) -> Option<Vec<Atom>> {
+        if self.locked_range(range).is_some() {
+            return None;
+        }
 
-            if nodes.is_empty() {
-                updated_row = Some(curr.row);
-            }
+        let mut atoms = self.atoms();
+        let (start, end) = (self.offset(&range.start), self.offset(&range.end));
+
+        if start >= end {
+            return None;
         }
-    }
 
-    /// Inserts all values in `lines` at the position of its head.
-    pub fn remote_insert(&mut self, lines: &[Atom]) -> Option<(Vec<char>, Range)> {
-        let head = lines.first()?;
+        let res: Vec<Atom> = atoms.drain(start..end).collect();
+        self.load(&atoms);
 
-        for (row, nodes) in &self.nodes {
-            if let Err(i) = nodes.binary_search(head) {
-                nodes.splice(i..i, lines.iter().cloned());
+        Some(res)
+    }
 
-                return Some(Range::new((i, i), (i + lines.len(), i)));
+    /// Inserts every atom of `lines` at its position, returning the inserted characters and where they went.
+    /// Atoms that are already part of the document are skipped, so the same insert can be applied twice.
+    pub fn remote_insert(&mut self, lines: &[Atom]) -> Option<(Vec<char>, Range)> {
+        let mut atoms = self.atoms();
+        let mut start = None;
+        let mut text = Vec::new();
+
+        for atom in lines {
+            if let Err(i) = atoms.binary_search(atom) {
+                start.get_or_insert(i);
+                atoms.insert(i, atom.clone());
+                text.push(atom.val);
             }
         }
 
-        None
+        let start = self.point_at(start?);
+        self.load(&atoms);
+
+        Some((
+            text,
+            Range::new((start.row, start.column), (start.row, start.column)),
+        ))
     }
 
-    /// Deletes from the first value matching `start_val` until `end_val`.
+    /// Deletes every atom of `lines` that is still part of the document, returning the deleted characters and the
+    /// range that they spanned.
     pub fn remote_delete(&mut self, lines: &[Atom]) -> Option<(Vec<char>, Range)> {
-        for (row, nodes) in &self.nodes {
-            if let Ok(start) = nodes.binary_search(lines.first()?) {
-                if let Ok(end) = nodes.binary_search(lines.last()?) {
-                    nodes.splice(start..end, Vec::new());
-                    return Some(());
-                }
+        let mut atoms = self.atoms();
+        let mut found = Vec::new();
+
+        for atom in lines {
+            if let Ok(i) = atoms.binary_search(atom) {
+                found.push(i);
             }
         }
 
-        None
+        let (first, last) = (*found.iter().min()?, *found.iter().max()?);
+        let (start, end) = (self.point_at(first), self.point_at(last + 1));
+        let mut text = Vec::with_capacity(found.len());
+
+        found.sort_unstable();
+        for i in found.into_iter().rev() {
+            text.push(atoms.remove(i).val);
+        }
+        text.reverse();
+        self.load(&atoms);
+
+        Some((text, Range { start, end }))
     }
 
-    /// A client makes a local coordinate change and the following steps are performed:
-    /// - Finds the `col`-th and (`col`+1)-th position identifier.
-    /// - Inserts a new position identifier between them.
-    fn point_insert(&mut self, c: char, point: Point) {
-        let row = point.row;
-        let col = point.column;
-        let prev = self.node(row, col);
-        let next = self.node(row, col + 1);
-        let node = Atom::create(c, self.site, &prev, &next);
+    /// Locks the atoms from `range.start` until `range.end`.
+    /// The lock is returned so that it can be replicated to the other sites.
+    /// Nothing is locked if the range is empty.
+    pub fn local_lock(&mut self, range: &Range) -> Option<Lock> {
+        let (start, end) = self.span(range)?;
+
+        self.lock_clock += 1;
 
-        self.insert_or_update_row(row, col + 1, node)
+        let lock = Lock::new(
+            Id::new(self.lock_clock, self.site),
+            start.position,
+            end.position,
+        );
+
+        self.locks.push(lock.clone());
+
+        Some(lock)
     }
 
-    /// Inserts `val` by first searching for its correct position n and then inserting it between the n-th and (n+1)th node.
-    fn insert_val(&mut self, atom: &Atom) -> Option<Range> {
-        for (row, nodes) in &self.nodes {
-            match nodes.binary_search(atom) {
-                Ok(_) => panic!("Document atoms should always be unique."),
-                Err(i) => {
-                    nodes.insert(i, atom.to_owned());
-                    return Some(Range::new((i, i), (i, i)));
-                }
-            }
+    /// Applies a lock that was placed by another site.
+    pub fn remote_lock(&mut self, lock: Lock) {
+        if !self.locks.contains(&lock) {
+            self.locks.push(lock);
+        }
+    }
+
+    /// Removes the lock identified by `id`, returning it if it existed.
+    pub fn unlock(&mut self, id: &Id) -> Option<Lock> {
+        let i = self.locks.iter().position(|lock| lock.id == *id)?;
+        Some(self.locks.remove(i))
+    }
+
+    /// Finds the lock that a character inserted at `point` would end up in, if any.
+    /// A new character lies between the `col`-th and (`col`+1)-th atom, so it's only locked if both of them are.
+    pub fn locked_point(&self, point: &Point) -> Option<&Lock> {
+        let prev = self.node(point.row, point.column);
+        let next = self.node(point.row, point.column + 1);
+
+        self.locks
+            .iter()
+            .find(|lock| lock.contains(&prev.position) && lock.contains(&next.position))
+    }
+
+    /// Finds the lock that protects any part of `range`, if any.
+    pub fn locked_range(&self, range: &Range) -> Option<&Lock> {
+        let (start, end) = self.span(range)?;
+
+        self.locks
+            .iter()
+            .find(|lock| lock.overlaps(&start.position, &end.position))
+    }
+
+    /// Finds a lock that wasn't placed by `site` and that any atom of `lines` lies inside of, if any.
+    /// This is used for remote edits, where inserted atoms already have their positions.
+    pub fn locked_atoms(&self, lines: &[Atom], site: i64) -> Option<&Lock> {
+        self.locks.iter().find(|lock| {
+            lock.site() != site && lines.iter().any(|atom| lock.contains(&atom.position))
+        })
+    }
+
+    /// The locks on the document, e.g. to catch up a site that joins late.
+    pub fn locks(&self) -> &[Lock] {
+        &self.locks
+    }
+
+    /// The first and last atom of `range`, or `None` if the range is empty.
+    /// The end of a range is exclusive, so the last atom is the one right before it.
+    fn span(&self, range: &Range) -> Option<(Atom, Atom)> {
+        let (start, end) = (self.offset(&range.start), self.offset(&range.end));
+
+        if start >= end {
+            return None;
         }
 
-        None
+        let (first, last) = (self.point_at(start), self.point_at(end - 1));
+
+        Some((
+            self.node(first.row, first.column),
+            self.node(last.row, last.column),
+        ))
     }
 
-    /// Deletes `val` by first searching for its correct position and then deleting it.
-    /// If `val` does not exist inside `nodes`, then return `None`.
-    fn delete_val(&mut self, val: &Atom) -> Option<Range> {
-        for (row, nodes) in &self.nodes {
-            if let Ok(i) = nodes.binary_search(val) {
-                self.nodes.remove(&i);
-                return Some(Range::new((i, i), (i, i)));
+    /// Gets the position identifier of the atom at `point`, which stays attached to the same character while the
+    /// document changes around it.
+    pub fn position(&self, point: &Point) -> Position {
+        self.node(point.row, point.column).position
+    }
+
+    /// Finds the current point of the atom identified by `position`.
+    /// If the atom has since been deleted, then this is the point at which it would be if it were inserted again.
+    pub fn point(&self, position: &Position) -> Point {
+        self.locate(position).0
+    }
+
+    /// Creates an anchor at `point`, bound to the character before it (`Gravity::Left`) or after it
+    /// (`Gravity::Right`).
+    pub fn anchor(&self, point: &Point, gravity: Gravity) -> Anchor {
+        let atom = match gravity {
+            Gravity::Left => self.before(point),
+            Gravity::Right => self.after(point),
+        };
+
+        Anchor::new(atom.position, gravity)
+    }
+
+    /// Finds the current point of `anchor`.
+    /// If its character was deleted, then the anchor ends up where that character used to be, i.e. between the
+    /// characters that surrounded it.
+    pub fn resolve(&self, anchor: &Anchor) -> Point {
+        let (point, found) = self.locate(&anchor.position);
+
+        // A left anchor lies just after its character, which is gone if it was deleted.
+        let point = match anchor.gravity {
+            Gravity::Left if found => point + (0, 1),
+            _ => point,
+        };
+
+        // Just after a newline is the start of the next row.
+        match self.line(point.row) {
+            Some(nodes)
+                if point.column == nodes.len()
+                    && nodes.last().is_some_and(|atom| atom.val == '\n') =>
+            {
+                Point::new(point.row + 1, 0)
+            }
+            _ => point,
+        }
+    }
+
+    /// Finds the point of the atom identified by `position`, and whether it's still part of the document.
+    /// If it isn't, then the point is where it would be if it were inserted again.
+    fn locate(&self, position: &Position) -> (Point, bool) {
+        let mut row = 0;
+
+        while let Some(nodes) = self.line(row) {
+            let (column, found) = match nodes.binary_search_by(|atom| atom.position.cmp(position)) {
+                Ok(column) => (column, true),
+                Err(column) => (column, false),
+            };
+            let next = self.line(row + 1).and_then(|nodes| nodes.first());
+
+            // The atom belongs to this row unless it comes after every atom on it and the next row starts before it.
+            if column < nodes.len() || next.is_none_or(|atom| atom.position > *position) {
+                return (Point::new(row, column), found);
             }
+
+            row += 1;
         }
 
-        None
+        (Point::new(0, 0), false)
     }
 
-    /// Receives a local request to delete an atom from the document.
-    /// A client deletes a character at index i and the following steps are performed:
-    /// - Find the (i+1)-th character in the document (i + 1 since we don't count the virtual nodes).
-    /// - Record its position identifer and then deletes it from the document.
-    fn point_delete(&mut self, row: usize, col: usize) -> Option<Atom> {
-        self.nodes
-            .get(&row)
-            .and_then(|mut nodes| Some(nodes.remove(col)))
+    /// The atom just before `point`, which is the last atom of the previous row at the start of a row.
+    fn before(&self, point: &Point) -> Atom {
+        let prev = match point.column.checked_sub(1) {
+            Some(column) => self
+                .line(point.row)
+                .and_then(|nodes| nodes.get(column).or_else(|| nodes.last())),
+            None => point
+                .row
+                .checked_sub(1)
+                .and_then(|row| self.line(row))
+                .and_then(|nodes| nodes.last()),
+        };
+
+        prev.cloned().unwrap_or_else(|| self.virtual_min())
     }
 
-    /// Either inserts into an already existing row or creates a new one and inserts into that one.
-    fn insert_or_update_row(&mut self, row: usize, col: usize, val: Atom) {
-        match self.nodes.entry(row) {
-            Entry::Occupied(entry) => {
-                let nodes = entry.get();
-                nodes.insert(col, val);
+    /// The atom just after `point`, which is the first atom of the next row at the end of a row.
+    fn after(&self, point: &Point) -> Atom {
+        let next = self
+            .line(point.row)
+            .and_then(|nodes| nodes.get(point.column))
+            .or_else(|| self.line(point.row + 1).and_then(|nodes| nodes.first()));
+
+        next.cloned().unwrap_or_else(|| self.virtual_max())
+    }
+
+    /// The number of atoms before `point`, i.e. its index among all atoms of the document.
+    /// Columns past the end of a row are clamped to it.
+    fn offset(&self, point: &Point) -> usize {
+        let before: usize = (0..point.row)
+            .map(|row| self.line(row).map_or(0, Vec::len))
+            .sum();
+
+        before + point.column.min(self.line(point.row).map_or(0, Vec::len))
+    }
+
+    /// The point of the `offset`-th atom of the document, or of the end of the document if there's none.
+    fn point_at(&self, offset: usize) -> Point {
+        let mut row = 0;
+        let mut offset = offset;
+
+        while let Some(nodes) = self.line(row) {
+            if offset < nodes.len() {
+                return Point::new(row, offset);
             }
-            Entry::Vacant(entry) => {
-                let mut nodes = entry.insert(Vec::new());
-                nodes.push(val);
+
+            offset -= nodes.len();
+            row += 1;
+        }
+
+        match row.checked_sub(1).and_then(|row| self.line(row)) {
+            Some(nodes) if nodes.last().is_some_and(|atom| atom.val != '\n') => {
+                Point::new(row - 1, nodes.len())
             }
+            _ => Point::new(row, 0),
         }
     }
 
     /// Gets the content of the document by aggregating all of the nodes together into a single string.
     /// An empty document will produce an empty string.
-    fn content(&self) -> String {
+    pub fn content(&self) -> String {
         let mut row = 0;
         let mut res = String::new();
 
         while let Some(nodes) = self.nodes.get(&row) {
-            nodes.iter().fold(res, |mut acc, c| {
-                if c.val != NIL {
-                    acc.push(c.val);
-                }
-                acc
-            });
+            res.extend(
+                nodes
+                    .iter()
+                    .filter(|atom| atom.val != NIL)
+                    .map(|atom| atom.val),
+            );
+
+            row += 1;
+        }
+
+        res
+    }
 
+    /// Gets every atom of the document in order, e.g. to send a snapshot of it to another site.
+    pub fn atoms(&self) -> Vec<Atom> {
+        let mut row = 0;
+        let mut res = Vec::new();
+
+        while let Some(nodes) = self.line(row) {
+            res.extend(nodes.iter().filter(|atom| atom.val != NIL).cloned());
             row += 1;
         }
 
         res
     }
 
+    /// Finds the atoms of `lines` that aren't part of the document, e.g. to catch up on a snapshot from another site.
+    /// They're grouped into runs that end up next to each other, so that each run can be inserted with
+    /// `remote_insert`.
+    pub fn missing(&self, lines: &[Atom]) -> Vec<Vec<Atom>> {
+        let atoms = self.atoms();
+        let mut res: Vec<(usize, Vec<Atom>)> = Vec::new();
+
+        for atom in lines {
+            let i = match atoms.binary_search(atom) {
+                Ok(_) => continue,
+                Err(i) => i,
+            };
+
+            match res.last_mut() {
+                Some((at, run)) if *at == i => run.push(atom.clone()),
+                _ => res.push((i, vec![atom.clone()])),
+            }
+        }
+
+        res.into_iter().map(|(_, run)| run).collect()
+    }
+
+    /// Replaces the content of the document by `atoms`, which must be in order.
+    /// A new row is started after every newline.
+    pub fn load(&mut self, atoms: &[Atom]) {
+        self.nodes.clear();
+
+        let mut row = Vec::new();
+
+        for atom in atoms {
+            row.push(atom.clone());
+
+            if atom.val == '\n' {
+                self.nodes.insert(self.nodes.len(), row);
+                row = Vec::new();
+            }
+        }
+
+        if !row.is_empty() {
+            self.nodes.insert(self.nodes.len(), row);
+        }
+    }
+
+    /// Replaces the content of the document by `text`, e.g. when sharing a file that already exists.
+    /// Each character gets a single digit position, and the digits are spread evenly between `PAGE_MIN` and
+    /// `PAGE_MAX`, so that there's room for later inserts anywhere without the positions growing.
+    pub fn seed(&mut self, text: &str) {
+        let len = text.chars().count() as u64;
+        let step = (PAGE_MAX - PAGE_MIN) / (len + 1);
+        let atoms: Vec<Atom> = text
+            .chars()
+            .zip(1..)
+            .map(|(c, i)| {
+                let id = Id::new(PAGE_MIN + step * i, self.site);
+                Atom::new(Position::new(&[id]), 0, c)
+            })
+            .collect();
+
+        self.load(&atoms);
+    }
+
     #[inline]
     fn virtual_min(&self) -> Atom {
-        Atom::new(Position::new(&vec![Id::new(PAGE_MIN, self.site)]), 0, NIL)
+        Atom::new(Position::new(&[Id::new(PAGE_MIN, self.site)]), 0, NIL)
     }
 
     #[inline]
     fn virtual_max(&self) -> Atom {
-        Atom::new(Position::new(&vec![Id::new(PAGE_MAX, self.site)]), 0, NIL)
+        Atom::new(Position::new(&[Id::new(PAGE_MAX, self.site)]), 0, NIL)
     }
 
     /// Get the line for the corresponding `row`.
@@ -216,33 +481,38 @@ impl Document {
 mod tests {
     use super::Atom;
     use super::Document;
+    use super::Gravity;
     use super::Id;
     use super::Point;
     use super::Position;
+    use super::Range;
     use super::PAGE_MAX;
     use super::PAGE_MIN;
+    use std::slice;
 
     fn is_sorted(doc: &Document) -> bool {
-        let mut row = 0;
-        let mut flattened_doc = Vec::new();
+        doc.atoms().windows(2).all(|window| window[0] < window[1])
+    }
 
-        while let Some(ref mut nodes) = doc.nodes.get(&row) {
-            flattened_doc.append(nodes);
-            row += 1;
-        }
+    /// Types `c` at `point`, as the editor would.
+    fn insert(doc: &mut Document, c: char, point: Point) {
+        let range = Range {
+            start: point.clone(),
+            end: point,
+        };
 
-        flattened_doc.windows(2).all(|window| window[0] < window[1])
+        doc.local_insert(&range, &[c]);
     }
 
     #[test]
     fn test_simple_insert_by_position() {
         let mut doc = Document::new(0);
 
-        doc.point_insert('a', Point::new(0, 0));
+        insert(&mut doc, 'a', Point::new(0, 0));
 
         assert_eq!(doc.nodes.len(), 1);
 
-        let digit = doc.line(0).unwrap()[1].position.0[0].digit;
+        let digit = doc.line(0).unwrap()[0].position.0[0].digit;
 
         assert!(PAGE_MIN < digit && digit < PAGE_MAX);
     }
@@ -251,17 +521,17 @@ mod tests {
     fn test_consecutive_inserts() {
         let mut doc = Document::new(0);
 
-        doc.point_insert('h', Point::new(0, 0));
-        doc.point_insert('e', Point::new(0, 1));
-        doc.point_insert('l', Point::new(0, 2));
-        doc.point_insert('l', Point::new(0, 3));
-        doc.point_insert('o', Point::new(0, 4));
-        doc.point_insert(' ', Point::new(0, 5));
-        doc.point_insert('w', Point::new(0, 6));
-        doc.point_insert('o', Point::new(0, 7));
-        doc.point_insert('r', Point::new(0, 8));
-        doc.point_insert('l', Point::new(0, 9));
-        doc.point_insert('d', Point::new(0, 10));
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 1));
+        insert(&mut doc, 'l', Point::new(0, 2));
+        insert(&mut doc, 'l', Point::new(0, 3));
+        insert(&mut doc, 'o', Point::new(0, 4));
+        insert(&mut doc, ' ', Point::new(0, 5));
+        insert(&mut doc, 'w', Point::new(0, 6));
+        insert(&mut doc, 'o', Point::new(0, 7));
+        insert(&mut doc, 'r', Point::new(0, 8));
+        insert(&mut doc, 'l', Point::new(0, 9));
+        insert(&mut doc, 'd', Point::new(0, 10));
 
         assert_eq!(doc.content(), "hello world");
         assert!(is_sorted(&doc));
@@ -271,203 +541,311 @@ mod tests {
     fn test_insert_by_value() {
         let mut doc = Document::new(0);
 
-        doc.point_insert('h', Point::new(0, 0));
-        doc.point_insert('e', Point::new(0, 1));
-        doc.point_insert('l', Point::new(0, 2));
-        doc.point_insert('l', Point::new(0, 3));
-        doc.point_insert('o', Point::new(0, 4));
-        doc.point_insert(' ', Point::new(0, 5));
-        doc.point_insert('w', Point::new(0, 6));
-        doc.point_insert('o', Point::new(0, 7));
-        doc.point_insert('r', Point::new(0, 8));
-        doc.point_insert('l', Point::new(0, 9));
-        doc.point_insert('d', Point::new(0, 10));
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 1));
+        insert(&mut doc, 'l', Point::new(0, 2));
+        insert(&mut doc, 'l', Point::new(0, 3));
+        insert(&mut doc, 'o', Point::new(0, 4));
+        insert(&mut doc, ' ', Point::new(0, 5));
+        insert(&mut doc, 'w', Point::new(0, 6));
+        insert(&mut doc, 'o', Point::new(0, 7));
+        insert(&mut doc, 'r', Point::new(0, 8));
+        insert(&mut doc, 'l', Point::new(0, 9));
+        insert(&mut doc, 'd', Point::new(0, 10));
 
-        let space = doc.point_delete(0, 6).unwrap().to_owned();
+        let space = doc.local_delete(&Range::new((0, 5), (0, 6))).unwrap();
 
-        doc.insert_val(&space);
+        assert_eq!(doc.content(), "helloworld");
+
+        doc.remote_insert(&space);
 
         assert_eq!(doc.content(), "hello world");
     }
 
+    fn complex_atoms() -> Vec<Atom> {
+        vec![
+            Atom::new(Position(vec![Id::new(1, 0)]), 0, 'h'),
+            Atom::new(Position(vec![Id::new(1, 0), Id::new(4, 0)]), 0, 'h'),
+            Atom::new(
+                Position(vec![Id::new(1, 0), Id::new(6, 0), Id::new(3, 1)]),
+                0,
+                '\n',
+            ),
+            Atom::new(Position(vec![Id::new(1, 0), Id::new(7, 0)]), 0, 'h'),
+            Atom::new(Position(vec![Id::new(1, 1)]), 0, 'h'),
+            Atom::new(Position(vec![Id::new(1, 1), Id::new(1, 1)]), 0, 'h'),
+        ]
+    }
+
     #[test]
     fn test_insert_by_index_complex() {
         let mut doc = Document::new(0);
-        let mut first_row = doc.nodes.insert(0, Vec::new()).unwrap();
-        let mut second_row = doc.nodes.insert(1, Vec::new()).unwrap();
 
-        first_row.push(Atom::new(Position(vec![Id::new(1, 0)]), 0, 'h'));
+        doc.load(&complex_atoms());
 
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(4, 0)]),
-            0,
-            'h',
-        ));
+        insert(&mut doc, 'a', Point::new(0, 0));
+        insert(&mut doc, 'a', Point::new(0, 1));
+        insert(&mut doc, 'b', Point::new(0, 4));
+        insert(&mut doc, 'c', Point::new(1, 3));
 
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(6, 0), Id::new(3, 1)]),
-            0,
-            'h',
-        ));
+        assert_eq!(doc.content(), "aahhb\nhhhc");
+        assert!(is_sorted(&doc));
+    }
 
-        second_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(7, 0)]),
-            0,
-            'h',
-        ));
+    #[test]
+    fn test_delete_by_value_complex() {
+        let mut doc = Document::new(0);
+        let atoms = complex_atoms();
+        let deleted_node = atoms[2].to_owned();
 
-        second_row.push(Atom::new(Position(vec![Id::new(1, 1)]), 0, 'h'));
+        doc.load(&atoms);
 
-        second_row.push(Atom::new(
-            Position(vec![Id::new(1, 1), Id::new(1, 1)]),
-            0,
-            'h',
-        ));
+        let (text, range) = doc
+            .remote_delete(std::slice::from_ref(&deleted_node))
+            .unwrap();
 
-        doc.point_insert('a', Point::new(0, 0));
-        doc.point_insert('a', Point::new(0, 1));
-        doc.point_insert('b', Point::new(0, 6));
-        doc.point_insert('c', Point::new(1, 3));
+        assert_eq!(text, vec!['\n']);
+        assert_eq!(range, Range::new((0, 2), (1, 0)));
+        assert!(!doc.atoms().contains(&deleted_node));
+        assert!(doc.remote_delete(&[deleted_node]).is_none());
+    }
 
-        assert_eq!(is_sorted(&doc), true);
+    #[test]
+    fn test_delete_by_value() {
+        let mut doc = Document::new(0);
+
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 1));
+        insert(&mut doc, 'l', Point::new(0, 2));
+        insert(&mut doc, 'l', Point::new(0, 3));
+        insert(&mut doc, 'o', Point::new(0, 4));
+        insert(&mut doc, ' ', Point::new(0, 5));
+        insert(&mut doc, 'w', Point::new(0, 6));
+        insert(&mut doc, 'o', Point::new(0, 7));
+        insert(&mut doc, 'r', Point::new(0, 8));
+        insert(&mut doc, 'l', Point::new(0, 9));
+        insert(&mut doc, 'd', Point::new(0, 10));
+
+        let space = doc.line(0).unwrap().get(5).unwrap().to_owned();
+
+        doc.remote_delete(&[space]);
+
+        assert_eq!(doc.content(), "helloworld");
     }
+
     #[test]
-    fn test_delete_by_value_complex() {
+    fn test_interleaved_inserts() {
         let mut doc = Document::new(0);
 
-        let mut first_row = doc.nodes.insert(0, Vec::new()).unwrap();
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'o', Point::new(0, 0));
+        insert(&mut doc, ' ', Point::new(0, 0));
+        insert(&mut doc, 'w', Point::new(0, 0));
+        insert(&mut doc, 'o', Point::new(0, 0));
+        insert(&mut doc, 'r', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'd', Point::new(0, 0));
 
-        let deleted_node = Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(6, 0), Id::new(3, 1)]),
-            0,
-            'h',
-        );
+        assert_eq!(doc.content(), "dlrow olleh");
+        assert!(is_sorted(&doc));
+    }
 
-        first_row.push(Atom::new(Position(vec![Id::new(1, 0)]), 0, 'h'));
+    #[test]
+    fn test_delete() {
+        let mut doc = Document::new(0);
 
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(4, 0)]),
-            0,
-            'h',
-        ));
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'o', Point::new(0, 0));
+        insert(&mut doc, ' ', Point::new(0, 0));
+        insert(&mut doc, 'w', Point::new(0, 0));
+        insert(&mut doc, 'o', Point::new(0, 0));
+        insert(&mut doc, 'r', Point::new(0, 0));
+        insert(&mut doc, 'l', Point::new(0, 0));
+        insert(&mut doc, 'd', Point::new(0, 0));
 
-        first_row.push(deleted_node.to_owned());
+        let content = doc.content();
+        let index_of_space = content
+            .find(' ')
+            .expect("Content should contain a space character");
 
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(7, 0)]),
-            0,
-            'h',
-        ));
+        doc.local_delete(&Range::new((0, index_of_space), (0, index_of_space + 1)));
 
-        first_row.push(Atom::new(Position(vec![Id::new(1, 1)]), 0, 'h'));
+        assert_eq!(doc.content(), "dlrowolleh");
+        assert!(is_sorted(&doc));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut doc = Document::new(0);
+
+        insert(&mut doc, 'h', Point::new(0, 0));
+        insert(&mut doc, 'e', Point::new(0, 1));
+        insert(&mut doc, 'l', Point::new(0, 2));
+        insert(&mut doc, 'l', Point::new(0, 3));
+        insert(&mut doc, 'o', Point::new(0, 4));
+
+        let lock = doc.local_lock(&Range::new((0, 2), (0, 4))).unwrap();
+        let inside = doc.line(0).unwrap()[3].to_owned();
 
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 1), Id::new(1, 1)]),
-            0,
-            'h',
-        ));
+        assert!(doc.locked_point(&Point::new(0, 2)).is_some());
+        assert!(doc.locked_point(&Point::new(0, 0)).is_none());
+        assert!(doc.locked_range(&Range::new((0, 3), (0, 4))).is_some());
+        assert!(doc.locked_range(&Range::new((0, 0), (0, 1))).is_none());
+        // The end of the range is exclusive, so the "o" after it isn't locked.
+        assert!(doc.locked_range(&Range::new((0, 4), (0, 5))).is_none());
+        assert!(doc.local_delete(&Range::new((0, 3), (0, 4))).is_none());
 
-        doc.delete_val(&deleted_node);
+        // Remote edits inside of the lock are refused by the node, unless they come from the site that placed it.
+        assert_eq!(doc.locked_atoms(slice::from_ref(&inside), 1), Some(&lock));
+        assert_eq!(doc.locked_atoms(slice::from_ref(&inside), 0), None);
+        assert!(doc.remote_delete(&[inside]).is_some());
+        assert_eq!(doc.content(), "helo");
 
-        assert_eq!(first_row.contains(&deleted_node), false);
+        doc.unlock(&lock.id);
+
+        assert!(doc.locked_point(&Point::new(0, 2)).is_none());
     }
 
     #[test]
-    fn test_delete_by_value() {
+    fn test_resolve_position() {
         let mut doc = Document::new(0);
+        let atom = |digit, val| Atom::new(Position(vec![Id::new(digit, 0)]), 0, val);
 
-        doc.point_insert('h', Point::new(0, 0));
-        doc.point_insert('e', Point::new(0, 1));
-        doc.point_insert('l', Point::new(0, 2));
-        doc.point_insert('l', Point::new(0, 3));
-        doc.point_insert('o', Point::new(0, 4));
-        doc.point_insert(' ', Point::new(0, 5));
-        doc.point_insert('w', Point::new(0, 6));
-        doc.point_insert('o', Point::new(0, 7));
-        doc.point_insert('r', Point::new(0, 8));
-        doc.point_insert('l', Point::new(0, 9));
-        doc.point_insert('d', Point::new(0, 10));
+        doc.nodes
+            .insert(0, vec![atom(2, 'a'), atom(4, 'b'), atom(6, '\n')]);
+        doc.nodes.insert(1, vec![atom(8, 'c')]);
 
-        let space = doc.line(0).unwrap().get(6).unwrap().to_owned();
+        let b = doc.position(&Point::new(0, 1));
 
-        doc.delete_val(&space);
+        assert_eq!(doc.point(&b), Point::new(0, 1));
+        assert_eq!(doc.point(&atom(8, 'c').position), Point::new(1, 0));
 
-        assert_eq!(doc.content(), "helloworld");
+        doc.nodes.get_mut(&0).unwrap().insert(0, atom(1, 'x'));
+
+        assert_eq!(doc.point(&b), Point::new(0, 2));
+
+        doc.nodes.get_mut(&0).unwrap().remove(2);
+
+        assert_eq!(doc.point(&b), Point::new(0, 2));
+        assert_eq!(doc.point(&atom(9, 'd').position), Point::new(1, 1));
     }
 
     #[test]
-    fn test_interleaved_inserts() {
+    fn test_anchor() {
         let mut doc = Document::new(0);
+        let atom = |digit, val| Atom::new(Position(vec![Id::new(digit, 0)]), 0, val);
 
-        doc.point_insert('h', Point::new(0, 0));
-        doc.point_insert('e', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('o', Point::new(0, 0));
-        doc.point_insert(' ', Point::new(0, 0));
-        doc.point_insert('w', Point::new(0, 0));
-        doc.point_insert('o', Point::new(0, 0));
-        doc.point_insert('r', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('d', Point::new(0, 0));
+        doc.nodes
+            .insert(0, vec![atom(2, 'a'), atom(4, 'b'), atom(6, '\n')]);
+        doc.nodes.insert(1, vec![atom(8, 'c')]);
 
-        assert_eq!(doc.content(), "dlrow olleh");
-        assert_eq!(is_sorted(&doc), true);
+        let left = doc.anchor(&Point::new(0, 1), Gravity::Left);
+        let right = doc.anchor(&Point::new(0, 1), Gravity::Right);
+        let end = doc.anchor(&Point::new(1, 1), Gravity::Right);
+        let start = doc.anchor(&Point::new(1, 0), Gravity::Left);
+
+        assert_eq!(doc.resolve(&left), Point::new(0, 1));
+        assert_eq!(doc.resolve(&right), Point::new(0, 1));
+        assert_eq!(doc.resolve(&end), Point::new(1, 1));
+        assert_eq!(doc.resolve(&start), Point::new(1, 0));
+
+        // Typing at the anchors pushes the right one along, but not the left one.
+        doc.nodes.get_mut(&0).unwrap().insert(1, atom(3, 'x'));
+
+        assert_eq!(doc.resolve(&left), Point::new(0, 1));
+        assert_eq!(doc.resolve(&right), Point::new(0, 2));
+
+        // Deleting the characters that they're bound to leaves them where those characters were.
+        doc.nodes.get_mut(&0).unwrap().remove(0);
+
+        assert_eq!(doc.resolve(&left), Point::new(0, 0));
+
+        doc.nodes.get_mut(&0).unwrap().remove(1);
+
+        assert_eq!(doc.resolve(&right), Point::new(0, 1));
+        assert_eq!(doc.resolve(&start), Point::new(1, 0));
     }
 
     #[test]
-    fn test_delete() {
+    fn test_load() {
         let mut doc = Document::new(0);
+        let atom = |digit, val| Atom::new(Position(vec![Id::new(digit, 1)]), 0, val);
+        let atoms = vec![atom(2, 'a'), atom(4, '\n'), atom(6, 'b')];
 
-        doc.point_insert('h', Point::new(0, 0));
-        doc.point_insert('e', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('o', Point::new(0, 0));
-        doc.point_insert(' ', Point::new(0, 0));
-        doc.point_insert('w', Point::new(0, 0));
-        doc.point_insert('o', Point::new(0, 0));
-        doc.point_insert('r', Point::new(0, 0));
-        doc.point_insert('l', Point::new(0, 0));
-        doc.point_insert('d', Point::new(0, 0));
+        doc.load(&atoms);
 
-        let content = doc.content();
-        let index_of_space = content
-            .find(' ')
-            .expect("Content should contain a space character");
+        assert_eq!(doc.nodes.len(), 2);
+        assert_eq!(doc.point(&atoms[2].position), Point::new(1, 0));
+        assert_eq!(doc.atoms(), atoms);
+    }
 
-        doc.point_delete(0, index_of_space + 1);
+    #[test]
+    fn test_missing() {
+        let mut doc = Document::new(0);
+        let atom = |digit, val| Atom::new(Position(vec![Id::new(digit, 1)]), 0, val);
+        let atoms = vec![atom(2, 'a'), atom(4, 'b'), atom(6, 'c'), atom(8, 'd')];
 
-        assert_eq!(doc.content(), "dlrowolleh");
-        assert_eq!(is_sorted(&doc), true);
+        doc.load(&[atoms[1].clone()]);
+
+        assert_eq!(
+            doc.missing(&atoms),
+            vec![
+                vec![atoms[0].clone()],
+                vec![atoms[2].clone(), atoms[3].clone()]
+            ]
+        );
+        assert!(Document::new(0).missing(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut doc = Document::new(1);
+
+        doc.seed("ab\nc");
+
+        let atoms = doc.atoms();
+
+        assert_eq!(doc.nodes.len(), 2);
+        assert_eq!(atoms.len(), 4);
+        assert!(atoms.iter().all(|atom| atom.position.0.len() == 1));
+        assert!(atoms.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(atoms[0].position[0].digit > PAGE_MIN);
+        assert!(atoms[3].position[0].digit < PAGE_MAX);
     }
 
     #[test]
     fn test_insert_by_range() {
         let mut doc = Document::new(0);
-        let mut first_row = doc.nodes.insert(0, Vec::new()).unwrap();
-        let mut second_row = doc.nodes.insert(1, Vec::new()).unwrap();
-
-        first_row.push(Atom::new(Position(vec![Id::new(1, 0)]), 0, 'h'));
-
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(4, 0)]),
-            0,
-            'h',
-        ));
-
-        first_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(6, 0), Id::new(3, 1)]),
-            0,
-            'h',
-        ));
-
-        second_row.push(Atom::new(
-            Position(vec![Id::new(1, 0), Id::new(7, 0)]),
-            0,
-            'h',
-        ));
-
-        second_row.push(Atom::new(Position(vec![Id::new(1, 1)]), 0, 'h'));
+        let lines: Vec<char> = "ab\ncd".chars().collect();
+        let atoms = doc
+            .local_insert(&Range::new((0, 0), (0, 0)), &lines)
+            .unwrap();
+
+        assert_eq!(atoms.len(), 5);
+        assert_eq!(doc.content(), "ab\ncd");
+        assert_eq!(doc.nodes.len(), 2);
+        assert!(is_sorted(&doc));
+
+        let deleted = doc.local_delete(&Range::new((0, 1), (1, 1))).unwrap();
+
+        assert_eq!(
+            deleted.iter().map(|atom| atom.val).collect::<String>(),
+            "b\nc"
+        );
+        assert_eq!(doc.content(), "ad");
+        assert!(doc.local_delete(&Range::new((0, 1), (0, 1))).is_none());
+
+        let (text, range) = doc.remote_insert(&deleted).unwrap();
+
+        assert_eq!(text, vec!['b', '\n', 'c']);
+        assert_eq!(range, Range::new((0, 1), (0, 1)));
+        assert_eq!(doc.content(), "ab\ncd");
+        assert!(doc.remote_insert(&deleted).is_none());
     }
 }
@@ -0,0 +1,368 @@
+use {
+    crate::{
+        comment::View,
+        history::Moment,
+        id::Id,
+        patch,
+        range::Range,
+        role::Role,
+        tree::{self, Kind},
+        workspace::DocumentId,
+    },
+    serde::{Deserialize, Serialize},
+    serde_json::{json, Value},
+};
+
+/// The request isn't valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The request is valid JSON, but not a valid JSON-RPC request.
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was understood, but refused, e.g. because the text is locked or the editor is a viewer.
+pub const REJECTED: i64 = -32000;
+
+const METHODS: &[&str] = &[
+    "open", "close", "insert", "delete", "lock", "unlock", "set_role", "cursor", "comment",
+    "reply", "resolve", "comments", "create", "rename", "remove", "files", "patch", "history",
+    "blame",
+];
+
+/// A call made by the editor.
+/// See the "Frontend protocol" section of the README for the messages on the wire.
+/// Calls about a document name it with `document`, which is the default document if left out.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Call {
+    /// Opens a document, subscribing to its changes, and gets its current content.
+    Open {
+        #[serde(default)]
+        document: DocumentId,
+    },
+    /// Closes a document, so that its changes are no longer received.
+    Close { document: DocumentId },
+    /// Inserts `text` at `range.start`.
+    Insert {
+        #[serde(default)]
+        document: DocumentId,
+        range: Range,
+        text: String,
+    },
+    /// Deletes the text from `range.start` until `range.end`.
+    Delete {
+        #[serde(default)]
+        document: DocumentId,
+        range: Range,
+    },
+    /// Locks the text from `range.start` until `range.end`.
+    Lock {
+        #[serde(default)]
+        document: DocumentId,
+        range: Range,
+    },
+    /// Removes a lock.
+    Unlock {
+        #[serde(default)]
+        document: DocumentId,
+        lock: Id,
+    },
+    /// Changes the role of another participant. Only the owner may do this.
+    SetRole { site: i64, role: Role },
+    /// Moves the cursor, selecting the text from `range.start` (the anchor) until `range.end` (the cursor).
+    Cursor {
+        #[serde(default)]
+        document: DocumentId,
+        range: Range,
+    },
+    /// Starts a thread about the text from `range.start` until `range.end`.
+    Comment {
+        #[serde(default)]
+        document: DocumentId,
+        range: Range,
+        text: String,
+    },
+    /// Adds a comment to a thread.
+    Reply {
+        #[serde(default)]
+        document: DocumentId,
+        thread: Id,
+        text: String,
+    },
+    /// Marks a thread as resolved.
+    Resolve {
+        #[serde(default)]
+        document: DocumentId,
+        thread: Id,
+    },
+    /// Lists every thread.
+    Comments {
+        #[serde(default)]
+        document: DocumentId,
+    },
+    /// Creates a file, or a directory if `kind` is "directory", in an existing directory.
+    Create {
+        path: String,
+        #[serde(default)]
+        kind: Kind,
+    },
+    /// Renames or moves a file or directory.
+    Rename { from: String, to: String },
+    /// Deletes a file or directory, along with everything in it.
+    Remove { path: String },
+    /// Lists every file and directory.
+    Files {},
+    /// Applies a unified diff, ignoring up to `fuzz` lines of context around hunks that don't match.
+    Patch {
+        #[serde(default)]
+        document: DocumentId,
+        diff: String,
+        #[serde(default = "Call::default_fuzz")]
+        fuzz: usize,
+    },
+    /// Exports the changes between two versions as a unified diff, or as one patch per site if `by_site` is set.
+    /// `from` defaults to the empty document, and `to` to the current version.
+    History {
+        #[serde(default)]
+        document: DocumentId,
+        from: Option<Moment>,
+        to: Option<Moment>,
+        #[serde(default)]
+        by_site: bool,
+    },
+    /// Tells who wrote the text of `range`, or of the whole document if there's no range.
+    Blame {
+        #[serde(default)]
+        document: DocumentId,
+        range: Option<Range>,
+    },
+}
+
+impl Call {
+    fn default_fuzz() -> usize {
+        patch::DEFAULT_FUZZ
+    }
+}
+
+/// A request from the editor.
+/// Requests without an `id` are notifications and don't receive a response.
+#[derive(Debug)]
+pub struct Request {
+    pub id: Option<Value>,
+    pub call: Call,
+}
+
+/// The error object of a failed request.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Failure {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn rejected(reason: impl Into<String>) -> Self {
+        Self::new(REJECTED, reason)
+    }
+}
+
+impl Request {
+    /// Parses a single line sent by the editor.
+    /// If the request is invalid, then the failure is returned along with the request's `id` (if it could be read),
+    /// so that the editor can still be told what went wrong.
+    pub fn parse(line: &str) -> Result<Self, (Option<Value>, Failure)> {
+        let message: Value = serde_json::from_str(line)
+            .map_err(|e| (None, Failure::new(PARSE_ERROR, e.to_string())))?;
+        let id = message.get("id").cloned();
+
+        if message.get("jsonrpc") != Some(&json!("2.0")) {
+            return Err((id, Failure::new(INVALID_REQUEST, "Expected JSON-RPC 2.0.")));
+        }
+
+        let method = match message.get("method") {
+            Some(Value::String(method)) => method,
+            _ => return Err((id, Failure::new(INVALID_REQUEST, "Missing method."))),
+        };
+
+        if !METHODS.contains(&method.as_str()) {
+            let reason = format!("Unknown method: {}.", method);
+            return Err((id, Failure::new(METHOD_NOT_FOUND, reason)));
+        }
+
+        let params = message.get("params").cloned().unwrap_or_else(|| json!({}));
+
+        match serde_json::from_value(json!({ "method": method, "params": params })) {
+            Ok(call) => Ok(Self { id, call }),
+            Err(e) => Err((id, Failure::new(INVALID_PARAMS, e.to_string()))),
+        }
+    }
+}
+
+/// Serializes the response to a request as a single line.
+pub fn response(id: Value, result: Result<Value, Failure>) -> String {
+    let message = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    };
+
+    format!("{}\n", message)
+}
+
+/// A message sent to the editor without it having asked for it.
+#[derive(Debug)]
+pub enum Notification {
+    /// Another site changed the document: the text within `range` is replaced by `text`.
+    Change {
+        document: DocumentId,
+        range: Range,
+        text: String,
+    },
+    /// Something that the editor didn't ask for went wrong, like a peer refusing one of our edits.
+    Rejected { reason: String },
+    /// The cursor of another participant moved, or the text around it changed.
+    /// As with `Call::Cursor`, `range.end` is the cursor and may come before `range.start`.
+    Presence {
+        document: DocumentId,
+        site: i64,
+        name: String,
+        colour: String,
+        range: Range,
+    },
+    /// A thread was started, replied to or resolved by another participant.
+    Thread { document: DocumentId, thread: View },
+    /// Another participant created, renamed or deleted a file or directory. Carries every file and directory.
+    Files { files: Vec<tree::View> },
+}
+
+impl Notification {
+    /// Serializes the notification as a single line.
+    pub fn to_line(&self) -> String {
+        let (method, params) = match self {
+            Notification::Change {
+                document,
+                range,
+                text,
+            } => (
+                "change",
+                json!({ "document": document, "range": range, "text": text }),
+            ),
+            Notification::Rejected { reason } => ("rejected", json!({ "reason": reason })),
+            Notification::Presence {
+                document,
+                site,
+                name,
+                colour,
+                range,
+            } => (
+                "presence",
+                json!({
+                    "document": document,
+                    "site": site,
+                    "name": name,
+                    "colour": colour,
+                    "range": range,
+                }),
+            ),
+            Notification::Thread { document, thread } => {
+                ("thread", json!({ "document": document, "thread": thread }))
+            }
+            Notification::Files { files } => ("files", json!({ "files": files })),
+        };
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+
+        format!("{}\n", message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        response, Call, Failure, Kind, Notification, Request, INVALID_PARAMS, METHOD_NOT_FOUND,
+    };
+    use crate::range::Range;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_parse_request() {
+        let line = r#"{"jsonrpc":"2.0","id":1,"method":"insert","params":{"range":{"start":{"row":0,"column":2},"end":{"row":0,"column":2}},"text":"hi"}}"#;
+        let request = Request::parse(line).unwrap();
+
+        assert_eq!(request.id, Some(json!(1)));
+        assert!(matches!(
+            request.call,
+            Call::Insert { ref document, ref range, ref text }
+                if document.is_empty() && range.start.column == 2 && text == "hi"
+        ));
+
+        let request = Request::parse(r#"{"jsonrpc":"2.0","method":"open"}"#).unwrap();
+
+        assert_eq!(request.id, None);
+        assert!(matches!(request.call, Call::Open { ref document } if document.is_empty()));
+
+        let request =
+            Request::parse(r#"{"jsonrpc":"2.0","method":"open","params":{"document":"a.rs"}}"#)
+                .unwrap();
+
+        assert!(matches!(request.call, Call::Open { ref document } if document == "a.rs"));
+
+        let request =
+            Request::parse(r#"{"jsonrpc":"2.0","method":"create","params":{"path":"a.rs"}}"#)
+                .unwrap();
+
+        assert!(matches!(
+            request.call,
+            Call::Create {
+                kind: Kind::File,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Request::parse(r#"{"jsonrpc":"2.0","method":"files"}"#)
+                .unwrap()
+                .call,
+            Call::Files {}
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_request() {
+        let (id, failure) =
+            Request::parse(r#"{"jsonrpc":"2.0","id":"a","method":"nope"}"#).unwrap_err();
+
+        assert_eq!(id, Some(json!("a")));
+        assert_eq!(failure.code, METHOD_NOT_FOUND);
+
+        let (_, failure) =
+            Request::parse(r#"{"jsonrpc":"2.0","id":2,"method":"delete","params":{}}"#)
+                .unwrap_err();
+
+        assert_eq!(failure.code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_outgoing_messages_are_lines() {
+        let ok = response(json!(1), Ok(Value::Null));
+        let err = response(json!(2), Err(Failure::rejected("locked")));
+        let change = Notification::Change {
+            document: String::new(),
+            range: Range::new((0, 0), (0, 1)),
+            text: String::new(),
+        }
+        .to_line();
+
+        for line in &[&ok, &err, &change] {
+            assert!(line.ends_with('\n'));
+            assert_eq!(line.matches('\n').count(), 1);
+        }
+
+        let err: Value = serde_json::from_str(&err).unwrap();
+
+        assert_eq!(err["error"]["code"], json!(-32000));
+        assert_eq!(err["error"]["message"], json!("locked"));
+    }
+}
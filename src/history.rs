@@ -0,0 +1,377 @@
+use {
+    crate::{
+        atom::Atom,
+        patch,
+        signature::Operation,
+        wal::{Entry, Snapshot},
+    },
+    serde::Deserialize,
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// A version of a document, given by the number of the last operation of each site that it includes.
+/// Every site numbers its own operations on a document from 1, and signs the number along with the operation, so a
+/// version means the same on every site. Sites that aren't in it have none of their operations included.
+pub type Version = BTreeMap<i64, u64>;
+
+/// Identifies a version of a document, either directly or by when it was current.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Moment {
+    /// The version that was current at this time, in milliseconds since the Unix epoch.
+    Time(u64),
+    Version(Version),
+}
+
+impl Moment {
+    /// Parses a moment given on the command line, which is either a time in milliseconds since the Unix epoch, or a
+    /// version given as "site:count" pairs separated by commas, e.g. "1:12,2:7".
+    pub fn parse(text: &str) -> Option<Self> {
+        if let Ok(time) = text.parse() {
+            return Some(Moment::Time(time));
+        }
+
+        text.split(',')
+            .map(|pair| {
+                let mut parts = pair.splitn(2, ':');
+                let site = parts.next()?.trim().parse().ok()?;
+                let count = parts.next()?.trim().parse().ok()?;
+
+                Some((site, count))
+            })
+            .collect::<Option<Version>>()
+            .map(Moment::Version)
+    }
+}
+
+/// An operation that was applied to the document.
+#[derive(Clone, Debug)]
+struct Record {
+    site: i64,
+    /// The number that `site` gave the operation.
+    seq: u64,
+    /// When the operation was applied here, in milliseconds since the Unix epoch.
+    time: u64,
+    operation: Operation,
+    atoms: Vec<Atom>,
+}
+
+/// Every operation that was applied to a document, in the order that they were applied, so that any earlier version of
+/// it can be told apart from the current one.
+#[derive(Debug, Default)]
+pub struct History {
+    /// The atoms of the snapshot that the history starts from, whose own history is gone.
+    base: Vec<Atom>,
+    /// The version of that snapshot and when it was taken, before which no version can be told apart.
+    since: (Version, u64),
+    records: Vec<Record>,
+    version: Version,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the history from a snapshot of the document at `version`, which was taken at `time`.
+    /// Every version includes the snapshot, since the operations before it aren't known anymore.
+    pub fn restore(version: Version, atoms: Vec<Atom>, time: u64) -> Self {
+        Self {
+            base: atoms,
+            since: (version.clone(), time),
+            records: Vec::new(),
+            version,
+        }
+    }
+
+    /// Rebuilds the history of `document` from a snapshot of the log and the entries that came after it.
+    /// Versions from before the snapshot can't be told apart from it anymore.
+    pub fn replay(document: &str, snapshot: Option<&Snapshot>, entries: &[Entry]) -> Self {
+        let mut res = snapshot
+            .into_iter()
+            .flat_map(|snapshot| &snapshot.documents)
+            .find(|image| image.document == document)
+            .map_or_else(Self::new, |image| {
+                let time = snapshot.map_or(0, |snapshot| snapshot.time);
+                Self::restore(image.version.clone(), image.atoms.clone(), time)
+            });
+
+        for entry in entries.iter().filter(|entry| entry.document == document) {
+            res.record(
+                entry.site,
+                entry.seq,
+                entry.operation,
+                &entry.atoms,
+                entry.time,
+            );
+        }
+
+        res
+    }
+
+    /// Records that `site` inserted or deleted `atoms` at `time`, in the operation that it numbered `seq`.
+    /// Operations of a site may arrive out of order, or not at all, so the version only ever moves forward.
+    pub fn record(&mut self, site: i64, seq: u64, operation: Operation, atoms: &[Atom], time: u64) {
+        let last = self.version.entry(site).or_insert(0);
+
+        *last = seq.max(*last);
+        self.records.push(Record {
+            site,
+            seq,
+            time,
+            operation,
+            atoms: atoms.to_vec(),
+        });
+    }
+
+    /// The number that `site` gives its next operation, which is what this site uses for its own operations.
+    pub fn next(&self, site: i64) -> u64 {
+        self.version.get(&site).copied().unwrap_or(0) + 1
+    }
+
+    /// The current version.
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// The version that `moment` identifies, or `None` if it's before the snapshot that the history starts from, since
+    /// the operations before the snapshot are gone.
+    pub fn resolve(&self, moment: &Moment) -> Option<Version> {
+        let (since, at) = &self.since;
+
+        match moment {
+            Moment::Version(version) => {
+                let before = since
+                    .iter()
+                    .any(|(site, seq)| version.get(site).copied().unwrap_or(0) < *seq);
+
+                Some(version.clone()).filter(|_| !before)
+            }
+            Moment::Time(time) if time < at => None,
+            Moment::Time(time) => {
+                let mut version = since.clone();
+
+                for record in self
+                    .records
+                    .iter()
+                    .take_while(|record| record.time <= *time)
+                {
+                    let last = version.entry(record.site).or_insert(0);
+                    *last = record.seq.max(*last);
+                }
+
+                Some(version)
+            }
+        }
+    }
+
+    /// The atoms of the document at `version`, in order.
+    pub fn atoms(&self, version: &Version) -> Vec<Atom> {
+        let mut atoms: BTreeSet<Atom> = self.base.iter().cloned().collect();
+
+        for record in self
+            .records
+            .iter()
+            .filter(|record| includes(version, record))
+        {
+            for atom in &record.atoms {
+                match record.operation {
+                    Operation::Insert => atoms.insert(atom.clone()),
+                    Operation::Delete => atoms.remove(atom),
+                };
+            }
+        }
+
+        atoms.into_iter().collect()
+    }
+
+    /// The content of the document at `version`.
+    pub fn text(&self, version: &Version) -> String {
+        self.atoms(version).iter().map(|atom| atom.val).collect()
+    }
+
+    /// The changes from `from` to `to` as a unified diff of `path`, which is empty if there are none.
+    pub fn diff(&self, path: &str, from: &Version, to: &Version) -> String {
+        patch::unified(path, &self.text(from), &self.text(to))
+    }
+
+    /// The changes from `from` to `to` as one patch per site, in the order of their ids.
+    /// Each patch only has the changes made by its site, and applies on top of the patches before it.
+    pub fn patches(&self, path: &str, from: &Version, to: &Version) -> Vec<(i64, String)> {
+        let mut version = from.clone();
+        let mut text = self.text(&version);
+        let mut res = Vec::new();
+
+        for (&site, &seq) in to {
+            if seq <= version.get(&site).copied().unwrap_or(0) {
+                continue;
+            }
+
+            version.insert(site, seq);
+
+            let next = self.text(&version);
+
+            if next != text {
+                res.push((site, patch::unified(path, &text, &next)));
+            }
+
+            text = next;
+        }
+
+        res
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, which is when operations are recorded.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// Whether `version` includes the operation of `record`.
+fn includes(version: &Version, record: &Record) -> bool {
+    record.seq <= version.get(&record.site).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, Moment, Version};
+    use crate::{
+        atom::Atom,
+        id::Id,
+        patch::Patch,
+        position::Position,
+        signature::Operation,
+        wal::{Entry, Image, Snapshot},
+    };
+
+    fn atoms(site: i64, text: &str, digits: &[u64]) -> Vec<Atom> {
+        text.chars()
+            .zip(digits)
+            .map(|(c, digit)| Atom::new(Position::new(&[Id::new(*digit, site)]), 0, c))
+            .collect()
+    }
+
+    /// Site 1 writes "a\nc\n", site 2 adds "b\n" in between, and site 1 deletes "c\n".
+    fn history() -> History {
+        let mut history = History::new();
+
+        history.record(
+            1,
+            1,
+            Operation::Insert,
+            &atoms(1, "a\nc\n", &[10, 20, 50, 60]),
+            100,
+        );
+        history.record(2, 1, Operation::Insert, &atoms(2, "b\n", &[30, 40]), 200);
+        history.record(1, 2, Operation::Delete, &atoms(1, "c\n", &[50, 60]), 300);
+        history
+    }
+
+    #[test]
+    fn test_text() {
+        let history = history();
+        let version: Version = vec![(1, 1), (2, 1)].into_iter().collect();
+
+        assert_eq!(history.text(history.version()), "a\nb\n");
+        assert_eq!(history.text(&version), "a\nb\nc\n");
+        assert_eq!(history.text(&Version::new()), "");
+        assert_eq!(history.resolve(&Moment::Time(250)), Some(version));
+        assert_eq!(history.resolve(&Moment::Time(0)), Some(Version::new()));
+    }
+
+    /// A site that missed another site's operation still agrees on what a version means.
+    #[test]
+    fn test_missed_operation() {
+        let mut history = History::new();
+
+        history.record(1, 1, Operation::Insert, &atoms(1, "a", &[10]), 100);
+        history.record(1, 3, Operation::Insert, &atoms(1, "c", &[30]), 300);
+        history.record(1, 2, Operation::Insert, &atoms(1, "b", &[20]), 400);
+
+        let version: Version = vec![(1, 2)].into_iter().collect();
+
+        assert_eq!(history.version(), &vec![(1, 3)].into_iter().collect());
+        assert_eq!(history.next(1), 4);
+        assert_eq!(history.text(&version), "ab");
+        assert_eq!(
+            history.resolve(&Moment::Time(300)),
+            Some(vec![(1, 3)].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let history = history();
+        let from = history.resolve(&Moment::Time(100)).unwrap();
+        let to = history.version();
+
+        assert_eq!(
+            history.diff("a.txt", &from, to),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n a\n-c\n+b\n"
+        );
+        assert_eq!(history.diff("a.txt", to, to), "");
+
+        let patches = history.patches("a.txt", &from, to);
+        let text = patches.iter().fold(history.text(&from), |text, (_, diff)| {
+            Patch::parse(diff).unwrap().apply(&text, 0).text
+        });
+
+        assert_eq!(
+            patches.iter().map(|(site, _)| *site).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(text, history.text(to));
+    }
+
+    #[test]
+    fn test_replay() {
+        let entry = |document: &str, site, seq, text, digits: &[u64], time| Entry {
+            document: document.to_string(),
+            site,
+            seq,
+            time,
+            operation: Operation::Insert,
+            atoms: atoms(site, text, digits),
+        };
+        let snapshot = Snapshot {
+            index: 1,
+            time: 150,
+            documents: vec![Image {
+                document: String::new(),
+                version: vec![(1, 1)].into_iter().collect(),
+                atoms: atoms(1, "a\n", &[10, 20]),
+            }],
+        };
+        let entries = vec![
+            entry("", 2, 1, "b\n", &[30, 40], 200),
+            entry("other.rs", 2, 1, "x", &[10], 250),
+            entry("", 1, 2, "c\n", &[50, 60], 300),
+        ];
+        let history = History::replay("", Some(&snapshot), &entries);
+
+        assert_eq!(history.text(history.version()), "a\nb\nc\n");
+        let text = |moment: &Moment| {
+            history
+                .resolve(moment)
+                .map(|version| history.text(&version))
+        };
+
+        assert_eq!(text(&Moment::Time(250)), Some("a\nb\n".to_string()));
+        assert_eq!(text(&Moment::Time(150)), Some("a\n".to_string()));
+        assert_eq!(
+            text(&Moment::parse("1:1, 2:1").unwrap()),
+            Some("a\nb\n".to_string())
+        );
+
+        // Versions from before the snapshot are gone.
+        assert_eq!(text(&Moment::Time(100)), None);
+        assert_eq!(text(&Moment::parse("2:1").unwrap()), None);
+        assert!(matches!(Moment::parse("300"), Some(Moment::Time(300))));
+        assert!(Moment::parse("1:x").is_none());
+    }
+}
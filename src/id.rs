@@ -18,19 +18,9 @@ impl Id {
 
 impl Ord for Id {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self.digit < other.digit {
-            return Ordering::Less;
-        } else if self.digit > other.digit {
-            return Ordering::Greater;
-        } else {
-            if self.site < other.site {
-                return Ordering::Less;
-            } else if self.site > other.site {
-                return Ordering::Greater;
-            } else {
-                return Ordering::Equal;
-            }
-        }
+        self.digit
+            .cmp(&other.digit)
+            .then(self.site.cmp(&other.site))
     }
 }
 
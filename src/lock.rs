@@ -0,0 +1,67 @@
+use {
+    crate::{id::Id, position::Position},
+    serde::{Deserialize, Serialize},
+};
+
+/// Protects the span of atoms from `start` until `end` (inclusive) from being edited.
+/// Since the span is anchored to position identifiers rather than rows and columns, it keeps covering the same
+/// text while other parts of the document change.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Lock {
+    /// Uniquely identifies the lock. The site is the site that placed it.
+    pub id: Id,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Lock {
+    pub fn new(id: Id, start: Position, end: Position) -> Self {
+        Self { id, start, end }
+    }
+
+    /// The site that placed the lock.
+    pub fn site(&self) -> i64 {
+        self.id.site
+    }
+
+    /// Whether `position` lies inside of the locked span.
+    pub fn contains(&self, position: &Position) -> bool {
+        self.start <= *position && *position <= self.end
+    }
+
+    /// Whether the span from `start` until `end` overlaps with the locked span.
+    pub fn overlaps(&self, start: &Position, end: &Position) -> bool {
+        *start <= self.end && self.start <= *end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lock;
+    use crate::{id::Id, position::Position};
+
+    fn pos(digits: &[u64]) -> Position {
+        Position(digits.iter().map(|d| Id::new(*d, 0)).collect())
+    }
+
+    #[test]
+    fn test_contains() {
+        let lock = Lock::new(Id::new(0, 0), pos(&[2]), pos(&[5]));
+
+        assert!(lock.contains(&pos(&[2])));
+        assert!(lock.contains(&pos(&[3, 7])));
+        assert!(lock.contains(&pos(&[5])));
+        assert!(!lock.contains(&pos(&[1, 9])));
+        assert!(!lock.contains(&pos(&[5, 1])));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let lock = Lock::new(Id::new(0, 0), pos(&[2]), pos(&[5]));
+
+        assert!(lock.overlaps(&pos(&[1]), &pos(&[2])));
+        assert!(lock.overlaps(&pos(&[3]), &pos(&[4])));
+        assert!(lock.overlaps(&pos(&[1]), &pos(&[9])));
+        assert!(!lock.overlaps(&pos(&[6]), &pos(&[9])));
+    }
+}
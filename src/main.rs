@@ -1,27 +1,179 @@
+mod anchor;
 mod atom;
+mod batch;
+mod blame;
+mod codec;
+mod comment;
 /**
 * This is a collaborative code editing application based on `https://hal.inria.fr/inria-00336191v3/document`.
 */
 mod config;
+mod diff;
 mod document;
+mod frontend;
+mod history;
 mod id;
+mod lock;
+#[cfg(feature = "neovim")]
+mod neovim;
 mod node;
+mod patch;
 mod position;
+mod presence;
+mod protocol;
 mod range;
+mod role;
+mod share;
+mod signature;
+mod tree;
+mod wal;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod workspace;
 
 use {
-    config::{Client, Config},
+    config::{Command, Config},
+    history::{History, Moment},
     node::Node,
+    patch::Patch,
+    presence::Identity,
+    protocol::Capabilities,
+    role::Roles,
+    share::Shared,
+    signature::Keys,
+    std::{
+        error::Error,
+        fs::{self, File},
+        io,
+        time::Duration,
+    },
+    wal::Log,
 };
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn Error>> {
+    match config::command() {
+        Some(Command::Patch { diff, file, fuzz }) => return patch(&diff, file.as_deref(), fuzz),
+        Some(Command::Show { wal, at, document }) => return show(&wal, &document, &at),
+        _ => {}
+    }
+
     let config = Config::parse()?;
-    let addr = Client::new("localhost".to_string(), 2000);
-    let client = Client::new("localhost".to_string(), 2001);
-    let mut node = Node::init(addr, client).await;
+
+    // Logs never go to stdout, since that's where the frontend protocol runs in stdio mode.
+    match config.log {
+        Some(ref path) => {
+            let file = File::create(path)?;
+            tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_writer(move || file.try_clone().expect("Unable to open log file."))
+                .init();
+        }
+        None => tracing_subscriber::fmt().with_writer(io::stderr).init(),
+    }
+
+    let client = node::Client::open(&config.frontend).await?;
+    let keys = Keys::load(config.site, config.keypair.as_deref(), &config.trusted)?;
+    let roles = Roles::new(config.site, config.role, config.default_role, &config.roles);
+    let capabilities = if config.compression {
+        Capabilities::supported()
+    } else {
+        Capabilities::supported().without(Capabilities::COMPRESSION)
+    };
+    let window = Duration::from_millis(config.batch_window);
+    let identity = Identity::new(config.site, config.name, config.colour);
+    let mut node = Node::init(
+        config.site,
+        config.addr,
+        client,
+        keys,
+        roles,
+        identity,
+        capabilities,
+        window,
+    )
+    .await;
+
+    if let Some(ref path) = config.wal {
+        let (log, snapshot, entries) = Log::open(path)?;
+        let interval = Duration::from_secs(config.snapshot_interval);
+
+        node.recover(log, snapshot, entries, interval);
+    }
+
+    if let Some(ref path) = config.share {
+        let (shared, text) = Shared::open(path)?;
+        let save = Duration::from_secs(config.save_interval);
+        let watch = Duration::from_millis(config.watch_interval);
+
+        node.share(shared, &text, save, watch).await;
+    }
+
+    for peer in &config.peers {
+        node.join(peer).await?;
+    }
 
     node.run().await?;
 
     Ok(())
 }
+
+/// Prints `document` as it was at the moment `at`, rebuilt from the write-ahead log in the directory `wal`.
+/// The log isn't changed, so this can be used while a node is appending to it.
+fn show(wal: &str, document: &str, at: &str) -> Result<(), Box<dyn Error>> {
+    let moment = Moment::parse(at).ok_or_else(|| format!("Invalid moment: {}.", at))?;
+    let (snapshot, entries) = Log::read(wal)?;
+    let history = History::replay(document, snapshot.as_ref(), &entries);
+    let version = history.resolve(&moment).ok_or_else(|| {
+        format!(
+            "{} is before the oldest snapshot in the log, so it can't be shown anymore.",
+            at
+        )
+    })?;
+
+    print!("{}", history.text(&version));
+
+    Ok(())
+}
+
+/// Applies the unified diff in the file `diff` to `file` (or the file that it names), keeping the encoding and line
+/// endings of the file. Hunks that don't match are reported, and the ones that do are still applied.
+fn patch(diff: &str, file: Option<&str>, fuzz: usize) -> Result<(), Box<dyn Error>> {
+    let patch = Patch::parse(&fs::read_to_string(diff)?)?;
+    let path = file
+        .or(patch.path.as_deref())
+        .ok_or("The diff doesn't name a file, so one has to be given with --file.")?;
+    let (mut shared, text) = Shared::open(path)?;
+    let outcome = patch.apply(&text, fuzz);
+
+    shared.save(&outcome.text)?;
+
+    for applied in outcome.applied.iter().filter(|applied| applied.fuzz > 0) {
+        eprintln!(
+            "Hunk {} applied at line {} with fuzz {}.",
+            applied.hunk + 1,
+            applied.line,
+            applied.fuzz
+        );
+    }
+
+    for rejected in &outcome.rejected {
+        eprintln!(
+            "Hunk {} rejected: {} {}",
+            rejected.hunk + 1,
+            rejected.header,
+            rejected.reason
+        );
+    }
+
+    if outcome.rejected.is_empty() {
+        Ok(())
+    } else {
+        let reason = format!(
+            "{} of {} hunks were rejected.",
+            outcome.rejected.len(),
+            patch.hunks.len()
+        );
+        Err(reason.into())
+    }
+}
@@ -0,0 +1,660 @@
+use {
+    crate::{range::Range, workspace::DEFAULT_DOCUMENT},
+    rmpv::{decode, encode, Value},
+    serde_json::{json, Value as Json},
+    std::{
+        collections::VecDeque,
+        io::{Error, ErrorKind},
+    },
+    tokio::{
+        io::{
+            self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+            DuplexStream, Lines, ReadHalf, WriteHalf,
+        },
+        select,
+    },
+    tracing::{error, info, instrument},
+};
+
+/// The kinds of msgpack-rpc messages.
+const REQUEST: u64 = 0;
+const RESPONSE: u64 = 1;
+const NOTIFICATION: u64 = 2;
+
+/// The node's copy of the lines in the Neovim buffer.
+/// Neovim reports and applies changes as whole lines, while the document works with ranges of characters, so the
+/// buffer is needed to translate between them.
+#[derive(Debug)]
+pub struct Buffer {
+    lines: Vec<String>,
+}
+
+impl Buffer {
+    pub fn new(content: &str) -> Self {
+        Self {
+            lines: content.split('\n').map(String::from).collect(),
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Replaces the lines from `first` until `last` by `replacement`, as reported by a `nvim_buf_lines_event`.
+    /// Returns the range of the document that was replaced, and the text that replaces it.
+    pub fn edit(&mut self, first: usize, last: usize, replacement: Vec<String>) -> (Range, String) {
+        let len = self.lines.len();
+        let last = last.min(len);
+        let first = first.min(last);
+
+        let (range, text) = if last < len {
+            let text = replacement
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect();
+            (Range::new((first, 0), (last, 0)), text)
+        } else if first > 0 {
+            // There's no line after the change, so it starts at the end of the line before it.
+            let start = (first - 1, width(&self.lines[first - 1]));
+            let end = (last - 1, width(&self.lines[last - 1]));
+            let text = replacement
+                .iter()
+                .map(|line| format!("\n{}", line))
+                .collect();
+            (Range::new(start, end), text)
+        } else {
+            let end = (len - 1, width(&self.lines[len - 1]));
+            (Range::new((0, 0), end), replacement.join("\n"))
+        };
+
+        self.lines.splice(first..last, replacement);
+
+        // A buffer always holds at least one line.
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
+        }
+
+        (range, text)
+    }
+
+    /// Replaces the text within `range` by `text`, as reported by a `change` notification.
+    /// Returns the lines from `first` until `last` that have to be replaced in Neovim, along with their replacement.
+    pub fn apply(&mut self, range: &Range, text: &str) -> (usize, usize, Vec<String>) {
+        let first = range.start.row.min(self.lines.len() - 1);
+        let last = range.end.row.min(self.lines.len() - 1).max(first);
+        let head: String = self.lines[first].chars().take(range.start.column).collect();
+        let tail: String = self.lines[last].chars().skip(range.end.column).collect();
+        let replacement: Vec<String> = format!("{}{}{}", head, text, tail)
+            .split('\n')
+            .map(String::from)
+            .collect();
+
+        self.lines.splice(first..=last, replacement.clone());
+
+        (first, last + 1, replacement)
+    }
+}
+
+fn width(line: &str) -> usize {
+    line.chars().count()
+}
+
+/// Relays between Neovim, which speaks msgpack-rpc, and the node's end of `pipe`, which speaks the frontend protocol.
+/// Once Neovim has connected (e.g. with `sockconnect("tcp", "localhost:2001", {"rpc": v:true})`), its current buffer
+/// is filled with the document and attached to, so that every change made to it is sent to the node.
+/// Changes made by other sites are applied to the buffer with `nvim_buf_set_lines`.
+#[instrument(level = "info", skip(nvim, pipe))]
+pub async fn bridge<S>(nvim: S, pipe: DuplexStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (nvim, to_nvim) = io::split(nvim);
+    let (node, to_node) = io::split(pipe);
+    let mut bridge = Bridge {
+        nvim,
+        input: Vec::new(),
+        to_nvim,
+        node: BufReader::new(node).lines(),
+        to_node,
+        handle: Value::Nil,
+        buffer: Buffer::new(""),
+        echoes: VecDeque::new(),
+        msgid: 0,
+        id: 0,
+    };
+
+    if let Err(e) = bridge.run().await {
+        error!("Connection to Neovim failed: {}.", e);
+    }
+}
+
+enum Next {
+    Nvim(Option<Value>),
+    Node(Option<String>),
+}
+
+struct Bridge<S> {
+    nvim: ReadHalf<S>,
+    /// Bytes read from Neovim that don't make up a whole message yet.
+    input: Vec<u8>,
+    to_nvim: WriteHalf<S>,
+    node: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    to_node: WriteHalf<DuplexStream>,
+    /// The Neovim buffer that is being shared.
+    handle: Value,
+    buffer: Buffer,
+    /// Changes that were made with `nvim_buf_set_lines`, which Neovim will report back like any other change.
+    echoes: VecDeque<(usize, usize, Vec<String>)>,
+    msgid: u64,
+    id: u64,
+}
+
+impl<S> Bridge<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn run(&mut self) -> io::Result<()> {
+        self.handle = self.call("nvim_get_current_buf", Vec::new()).await?;
+        self.sync().await?;
+
+        info!("Sharing Neovim buffer {}.", self.handle);
+
+        loop {
+            let next = select! {
+                message = recv(&mut self.nvim, &mut self.input) => Next::Nvim(message?),
+                line = self.node.next_line() => Next::Node(line?),
+            };
+
+            match next {
+                Next::Nvim(Some(message)) => {
+                    if !self.handle_nvim(message).await? {
+                        return self.to_node.shutdown().await;
+                    }
+                }
+                Next::Node(Some(line)) => self.handle_node(&line).await?,
+                Next::Nvim(None) => return self.to_node.shutdown().await,
+                Next::Node(None) => return Ok(()),
+            }
+        }
+    }
+
+    /// Replaces the content of the buffer with the document.
+    /// The buffer is detached while doing so, so that the change isn't reported back.
+    async fn sync(&mut self) -> io::Result<()> {
+        self.buffer = Buffer::new(&self.open().await?);
+        self.echoes.clear();
+
+        let lines = lines(self.buffer.lines());
+
+        self.call("nvim_buf_detach", vec![self.handle.clone()])
+            .await?;
+        self.call(
+            "nvim_buf_set_lines",
+            vec![
+                self.handle.clone(),
+                Value::from(0i64),
+                Value::from(-1i64),
+                Value::from(false),
+                lines,
+            ],
+        )
+        .await?;
+        self.call(
+            "nvim_buf_attach",
+            vec![
+                self.handle.clone(),
+                Value::from(false),
+                Value::Map(Vec::new()),
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Handles a message from Neovim, returning whether the buffer is still being shared.
+    async fn handle_nvim(&mut self, message: Value) -> io::Result<bool> {
+        let message = message.as_array().map(Vec::as_slice).unwrap_or_default();
+
+        match message {
+            [kind, method, Value::Array(args)] if kind.as_u64() == Some(NOTIFICATION) => {
+                match method.as_str() {
+                    Some("nvim_buf_lines_event") => self.lines_event(args).await?,
+                    Some("nvim_buf_detach_event") => return Ok(false),
+                    _ => {}
+                }
+            }
+            [kind, _, err, _] if kind.as_u64() == Some(RESPONSE) => {
+                if !err.is_nil() {
+                    error!("Neovim refused a change: {}.", err);
+                    self.sync().await?;
+                }
+            }
+            [kind, msgid, method, _] if kind.as_u64() == Some(REQUEST) => {
+                let err = Value::from(format!("Unknown method: {}.", method));
+                let response = vec![Value::from(RESPONSE), msgid.clone(), err, Value::Nil];
+                self.send(Value::Array(response)).await?;
+            }
+            _ => error!("Malformed message from Neovim."),
+        }
+
+        Ok(true)
+    }
+
+    /// Sends a change made in Neovim to the node, unless it's a change that was received from the node.
+    async fn lines_event(&mut self, args: &[Value]) -> io::Result<()> {
+        let change = match args {
+            [_, _, first, last, Value::Array(data), ..] => {
+                let data: Option<Vec<String>> = data
+                    .iter()
+                    .map(|line| line.as_str().map(String::from))
+                    .collect();
+
+                match (first.as_u64(), last.as_u64(), data) {
+                    (Some(first), Some(last), Some(data)) => (first as usize, last as usize, data),
+                    _ => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        if let Some(i) = self.echoes.iter().position(|echo| *echo == change) {
+            // Anything that was expected before the echo won't be reported anymore.
+            self.echoes.drain(..=i);
+            return Ok(());
+        }
+
+        let (first, last, replacement) = change;
+        let (range, text) = self.buffer.edit(first, last, replacement);
+
+        if range.start != range.end {
+            self.request("delete", json!({ "range": range })).await?;
+        }
+
+        if !text.is_empty() {
+            let range = Range {
+                start: range.start.clone(),
+                end: range.start,
+            };
+            self.request("insert", json!({ "range": range, "text": text }))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles a line from the node, applying the changes made by other sites to the buffer.
+    async fn handle_node(&mut self, line: &str) -> io::Result<()> {
+        let message: Json = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Malformed message from the node: {}.", e);
+                return Ok(());
+            }
+        };
+
+        match message["method"].as_str() {
+            // The buffer is always bound to the default document, so changes to other documents aren't ours.
+            Some("change") if message["params"]["document"] != DEFAULT_DOCUMENT => {}
+            Some("change") => {
+                let range: Range = match serde_json::from_value(message["params"]["range"].clone())
+                {
+                    Ok(range) => range,
+                    Err(_) => return Ok(()),
+                };
+                let text = message["params"]["text"].as_str().unwrap_or_default();
+                let (first, last, replacement) = self.buffer.apply(&range, text);
+                let args = vec![
+                    self.handle.clone(),
+                    Value::from(first as u64),
+                    Value::from(last as u64),
+                    Value::from(true),
+                    lines(&replacement),
+                ];
+
+                self.echoes.push_back((first, last, replacement));
+                self.request_nvim("nvim_buf_set_lines", args).await?;
+            }
+            Some("rejected") => {
+                let reason = message["params"]["reason"].as_str().unwrap_or_default();
+                self.notify_nvim("nvim_err_writeln", vec![Value::from(reason)])
+                    .await?;
+            }
+            _ => {
+                // The buffer no longer matches the document after a refused edit, so the document wins.
+                if let Some(reason) = message["error"]["message"].as_str() {
+                    let reason = format!("liveshare: {}", reason);
+                    self.notify_nvim("nvim_err_writeln", vec![Value::from(reason)])
+                        .await?;
+                    self.sync().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the content of the document, ignoring any changes that arrive before it since they're part of it.
+    async fn open(&mut self) -> io::Result<String> {
+        let request = json!({ "jsonrpc": "2.0", "id": "open", "method": "open" });
+
+        self.write_node(&request).await?;
+
+        while let Some(line) = self.node.next_line().await? {
+            let message: Json = serde_json::from_str(&line)?;
+
+            if message["id"] == json!("open") {
+                return Ok(message["result"]["content"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string());
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "The node disconnected.",
+        ))
+    }
+
+    /// Makes a request to Neovim and waits for its result.
+    /// Anything else that Neovim sends in the meantime is dropped.
+    async fn call(&mut self, method: &str, args: Vec<Value>) -> io::Result<Value> {
+        let msgid = self.request_nvim(method, args).await?;
+
+        while let Some(message) = recv(&mut self.nvim, &mut self.input).await? {
+            if let Some([kind, id, err, result]) = message.as_array().map(Vec::as_slice) {
+                if kind.as_u64() == Some(RESPONSE) && id.as_u64() == Some(msgid) {
+                    return match err {
+                        Value::Nil => Ok(result.clone()),
+                        err => Err(Error::other(format!("{} failed: {}", method, err))),
+                    };
+                }
+            }
+        }
+
+        Err(Error::new(ErrorKind::UnexpectedEof, "Neovim disconnected."))
+    }
+
+    async fn request_nvim(&mut self, method: &str, args: Vec<Value>) -> io::Result<u64> {
+        self.msgid += 1;
+
+        let request = vec![
+            Value::from(REQUEST),
+            Value::from(self.msgid),
+            Value::from(method),
+            Value::Array(args),
+        ];
+
+        self.send(Value::Array(request)).await?;
+
+        Ok(self.msgid)
+    }
+
+    async fn notify_nvim(&mut self, method: &str, args: Vec<Value>) -> io::Result<()> {
+        let notification = vec![
+            Value::from(NOTIFICATION),
+            Value::from(method),
+            Value::Array(args),
+        ];
+
+        self.send(Value::Array(notification)).await
+    }
+
+    async fn send(&mut self, message: Value) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        encode::write_value(&mut buf, &message).map_err(Error::other)?;
+
+        self.to_nvim.write_all(&buf).await?;
+        self.to_nvim.flush().await
+    }
+
+    /// Makes a request to the node. Only failures are acted upon, so the request isn't tracked.
+    async fn request(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.id += 1;
+
+        let request =
+            json!({ "jsonrpc": "2.0", "id": self.id, "method": method, "params": params });
+
+        self.write_node(&request).await
+    }
+
+    async fn write_node(&mut self, message: &Json) -> io::Result<()> {
+        self.to_node
+            .write_all(format!("{}\n", message).as_bytes())
+            .await
+    }
+}
+
+fn lines(lines: &[String]) -> Value {
+    Value::Array(
+        lines
+            .iter()
+            .map(|line| Value::from(line.as_str()))
+            .collect(),
+    )
+}
+
+/// Reads the next message from Neovim, or `None` once it has disconnected.
+/// msgpack messages aren't delimited, so bytes are read until a whole message can be decoded.
+async fn recv<R>(reader: &mut R, input: &mut Vec<u8>) -> io::Result<Option<Value>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0; 4096];
+
+    loop {
+        let mut rest = &input[..];
+
+        match decode::read_value(&mut rest) {
+            Ok(message) => {
+                let len = input.len() - rest.len();
+                input.drain(..len);
+                return Ok(Some(message));
+            }
+            Err(decode::Error::InvalidMarkerRead(ref e))
+            | Err(decode::Error::InvalidDataRead(ref e))
+                if e.kind() == ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+
+        let len = reader.read(&mut chunk).await?;
+
+        if len == 0 {
+            return Ok(None);
+        }
+
+        input.extend_from_slice(&chunk[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bridge, recv, Buffer, NOTIFICATION, REQUEST, RESPONSE};
+    use crate::range::Range;
+    use rmpv::{encode, Value};
+    use serde_json::Value as Json;
+    use tokio::io::{self, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+    fn lines(buffer: &Buffer) -> String {
+        buffer.lines().join("\n")
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut buffer = Buffer::new("a\nb\nc");
+
+        assert_eq!(
+            buffer.edit(1, 2, vec!["x".to_string(), "y".to_string()]),
+            (Range::new((1, 0), (2, 0)), "x\ny\n".to_string())
+        );
+        assert_eq!(lines(&buffer), "a\nx\ny\nc");
+
+        assert_eq!(
+            buffer.edit(3, 4, Vec::new()),
+            (Range::new((2, 1), (3, 1)), String::new())
+        );
+        assert_eq!(lines(&buffer), "a\nx\ny");
+
+        assert_eq!(
+            buffer.edit(3, 3, vec!["z".to_string()]),
+            (Range::new((2, 1), (2, 1)), "\nz".to_string())
+        );
+        assert_eq!(
+            buffer.edit(0, 4, Vec::new()),
+            (Range::new((0, 0), (3, 1)), String::new())
+        );
+        assert_eq!(buffer.lines(), &[String::new()]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut buffer = Buffer::new("hello\nworld");
+
+        assert_eq!(
+            buffer.apply(&Range::new((0, 5), (1, 0)), ", "),
+            (0, 2, vec!["hello, world".to_string()])
+        );
+        assert_eq!(
+            buffer.apply(&Range::new((0, 0), (0, 0)), "//\n"),
+            (0, 1, vec!["//".to_string(), "hello, world".to_string()])
+        );
+        assert_eq!(lines(&buffer), "//\nhello, world");
+    }
+
+    /// Messages aren't delimited, so one that arrives in pieces has to be put back together before it's decoded.
+    #[tokio::test]
+    async fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let request = Value::Array(vec![
+            Value::from(REQUEST),
+            Value::from(7u64),
+            Value::from("nvim_buf_set_lines"),
+            Value::Array(vec![
+                Value::from(0i64),
+                Value::from(-1i64),
+                Value::from(false),
+            ]),
+        ]);
+        let notification = Value::Array(vec![
+            Value::from(NOTIFICATION),
+            Value::from("nvim_err_writeln"),
+            Value::Array(vec![Value::from("liveshare: Text is locked by site 2.")]),
+        ]);
+        let mut buf = Vec::new();
+
+        encode::write_value(&mut buf, &request)?;
+        encode::write_value(&mut buf, &notification)?;
+
+        let (mut reader, mut writer) = io::duplex(4096);
+        let mut input = Vec::new();
+        let tail = buf.split_off(5);
+
+        writer.write_all(&buf).await?;
+        let sent = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            writer.write_all(&tail).await
+        });
+
+        assert_eq!(recv(&mut reader, &mut input).await?, Some(request));
+        assert_eq!(recv(&mut reader, &mut input).await?, Some(notification));
+
+        sent.await??;
+
+        assert_eq!(recv(&mut reader, &mut input).await?, None);
+
+        Ok(())
+    }
+
+    async fn respond<W>(nvim: &mut W, request: &[Value], result: Value) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let response = vec![
+            Value::from(RESPONSE),
+            request[1].clone(),
+            Value::Nil,
+            result,
+        ];
+        let mut buf = Vec::new();
+
+        encode::write_value(&mut buf, &Value::Array(response)).unwrap();
+        nvim.write_all(&buf).await
+    }
+
+    /// Plays the part of Neovim and of the node on either side of the bridge.
+    #[tokio::test]
+    async fn test_bridge() -> Result<(), Box<dyn std::error::Error>> {
+        let (nvim, remote) = io::duplex(4096);
+        let (node, pipe) = io::duplex(4096);
+        let (mut from_nvim, mut to_nvim) = io::split(nvim);
+        let (node, mut to_node) = io::split(node);
+        let mut node = BufReader::new(node).lines();
+        let mut input = Vec::new();
+        let handle = Value::Ext(0, vec![1]);
+
+        tokio::spawn(bridge(remote, pipe));
+
+        let request = recv(&mut from_nvim, &mut input).await?.unwrap();
+        let request = request.as_array().unwrap();
+
+        assert_eq!(request[2].as_str(), Some("nvim_get_current_buf"));
+
+        respond(&mut to_nvim, request, handle.clone()).await?;
+
+        let open: Json = serde_json::from_str(&node.next_line().await?.unwrap())?;
+
+        assert_eq!(open["method"], "open");
+
+        to_node
+            .write_all(
+                b"{\"jsonrpc\":\"2.0\",\"id\":\"open\",\"result\":{\"content\":\"a\\nb\"}}\n",
+            )
+            .await?;
+
+        for method in &["nvim_buf_detach", "nvim_buf_set_lines", "nvim_buf_attach"] {
+            let request = recv(&mut from_nvim, &mut input).await?.unwrap();
+            let request = request.as_array().unwrap();
+
+            assert_eq!(request[2].as_str(), Some(*method));
+
+            if *method == "nvim_buf_set_lines" {
+                assert_eq!(
+                    request[3].as_array().unwrap()[4],
+                    Value::Array(vec![Value::from("a"), Value::from("b")])
+                );
+            }
+
+            respond(&mut to_nvim, request, Value::from(true)).await?;
+        }
+
+        let event = vec![
+            Value::from(NOTIFICATION),
+            Value::from("nvim_buf_lines_event"),
+            Value::Array(vec![
+                handle,
+                Value::from(2u64),
+                Value::from(1u64),
+                Value::from(2u64),
+                Value::Array(vec![Value::from("c")]),
+                Value::from(false),
+            ]),
+        ];
+        let mut buf = Vec::new();
+        encode::write_value(&mut buf, &Value::Array(event))?;
+        to_nvim.write_all(&buf).await?;
+
+        let delete: Json = serde_json::from_str(&node.next_line().await?.unwrap())?;
+        let insert: Json = serde_json::from_str(&node.next_line().await?.unwrap())?;
+
+        assert_eq!(delete["method"], "delete");
+        assert_eq!(
+            serde_json::from_value::<Range>(delete["params"]["range"].clone())?,
+            Range::new((0, 1), (1, 1))
+        );
+        assert_eq!(insert["method"], "insert");
+        assert_eq!(insert["params"]["text"], "\nc");
+
+        Ok(())
+    }
+}
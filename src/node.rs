@@ -1,73 +1,523 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
 
 use {
-    crate::{atom::Atom, config, document::Document, range::Range},
-    bincode::{deserialize, serialize},
+    crate::{
+        atom::Atom,
+        batch::Batch,
+        blame,
+        comment::{Change, Thread},
+        config, diff,
+        frontend::{self, Call, Failure, Notification, Request},
+        history::{self, History, Moment, Version},
+        id::Id,
+        lock::Lock,
+        patch::{Patch, Rejected},
+        presence::{Identity, Presence},
+        protocol::{self, Capabilities, Envelope},
+        range::Range,
+        role::{Role, Roles},
+        share::Shared,
+        signature::{Keys, Operation},
+        tree,
+        wal::{Entry, Image, Log, Snapshot},
+        workspace::{DocumentId, File, Workspace, DEFAULT_DOCUMENT},
+    },
     serde::{Deserialize, Serialize},
-    serde_json::ser::to_vec,
-    std::io,
+    serde_json::{json, Value},
+    std::{
+        fmt, fs,
+        fs::Permissions,
+        os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    },
     tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        net::{TcpListener, TcpStream},
+        io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener, TcpStream, UnixListener,
+        },
+        select, task,
+        time::{self, Instant},
     },
     tracing::{error, info, instrument},
 };
 
+#[cfg(feature = "neovim")]
+use crate::neovim;
+#[cfg(feature = "websocket")]
+use crate::websocket;
+
+/// Events are sent between peers inside of an `Envelope`, which carries the protocol version.
+/// Requests made by the editor are described by `frontend::Call` instead.
+/// They are encoded with bincode, which can't read internally tagged enums, so the default (external) tagging is used.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(tag = "type")]
 pub enum Event {
-    RemoteInsert { id: i64, lines: Vec<Atom> },
-    RemoteDelete { id: i64, lines: Vec<Atom> },
-    Insert { lines: Vec<char>, range: Range },
-    Delete { range: Range },
+    /// The `seq`-th operation of site `id` on `document`.
+    RemoteInsert {
+        id: i64,
+        document: DocumentId,
+        seq: u64,
+        #[serde(with = "crate::codec::compact")]
+        lines: Vec<Atom>,
+        signature: Vec<u8>,
+    },
+    RemoteDelete {
+        id: i64,
+        document: DocumentId,
+        seq: u64,
+        #[serde(with = "crate::codec::compact")]
+        lines: Vec<Atom>,
+        signature: Vec<u8>,
+    },
+    /// Sent by a peer when it first connects, requesting a role.
+    Join {
+        id: i64,
+        role: Role,
+        signature: Vec<u8>,
+    },
+    /// The role that site `id` granted to `site`, either in response to `Join` or after a `SetRole`.
+    Granted {
+        id: i64,
+        site: i64,
+        role: Role,
+        signature: Vec<u8>,
+    },
+    /// Changes the role of `site`. Only accepted if `id` is the owner.
+    SetRole {
+        id: i64,
+        site: i64,
+        role: Role,
+        signature: Vec<u8>,
+    },
+    /// Tells the sender why its event was dropped.
+    Rejected { reason: String },
+    RemoteLock {
+        id: i64,
+        document: DocumentId,
+        lock: Lock,
+        signature: Vec<u8>,
+    },
+    RemoteUnlock {
+        id: i64,
+        document: DocumentId,
+        lock: Id,
+        signature: Vec<u8>,
+    },
+    /// Where the cursor of site `id` is.
+    RemotePresence {
+        id: i64,
+        document: DocumentId,
+        presence: Presence,
+        signature: Vec<u8>,
+    },
+    /// A thread of comments was started, replied to or resolved by site `id`.
+    Comment {
+        id: i64,
+        document: DocumentId,
+        change: Change,
+        signature: Vec<u8>,
+    },
+    /// A file or directory was created, renamed or deleted by site `id`.
+    Tree {
+        id: i64,
+        change: tree::Change,
+        signature: Vec<u8>,
+    },
+    /// Site `id` opened `document` and wants to be sent its changes.
+    Subscribe {
+        id: i64,
+        document: DocumentId,
+        signature: Vec<u8>,
+    },
+    /// Site `id` closed `document` and no longer wants its changes.
+    Unsubscribe {
+        id: i64,
+        document: DocumentId,
+        signature: Vec<u8>,
+    },
+    /// Everything that's in `document`, sent in response to `Subscribe` so that the subscriber catches up.
+    /// It's at `version`, i.e. it includes the operations of each site up to the one given there.
+    Snapshot {
+        id: i64,
+        document: DocumentId,
+        version: Version,
+        #[serde(with = "crate::codec::compact")]
+        lines: Vec<Atom>,
+        /// The locks on the document, which would otherwise only reach sites that were there when they were placed.
+        locks: Vec<Lock>,
+        /// The threads of comments on the document, for the same reason.
+        threads: Vec<Thread>,
+        signature: Vec<u8>,
+    },
+    /// Several events sent as a single message.
+    Batch { events: Vec<Event> },
+    /// A deflate compressed event.
+    Compressed { payload: Vec<u8> },
+}
+
+/// The size of the in-memory pipe between a bridged editor and the node.
+#[cfg(any(feature = "websocket", feature = "neovim"))]
+const BRIDGE_BUFFER: usize = 64 * 1024;
+
+/// How long to wait for new connections when there aren't any batches waiting to be sent.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// How long operations are held before they're committed to the log, so that a burst of them is written at once.
+const COMMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Event {
+    /// The capabilities a peer needs to understand this event.
+    pub fn required(&self) -> Capabilities {
+        match self {
+            Event::RemoteInsert { .. } | Event::RemoteDelete { .. } => Capabilities::SIGNATURES,
+            Event::Join { .. } | Event::Granted { .. } | Event::SetRole { .. } => {
+                Capabilities::ROLES
+            }
+            Event::RemoteLock { .. } | Event::RemoteUnlock { .. } => Capabilities::LOCKS,
+            Event::RemotePresence { .. } => Capabilities::PRESENCE,
+            Event::Comment { .. } => Capabilities::COMMENTS,
+            Event::Tree { .. } => Capabilities::FILES,
+            Event::Batch { .. } => Capabilities::BATCHING,
+            Event::Compressed { .. } => Capabilities::COMPRESSION,
+            _ => Capabilities::NONE,
+        }
+    }
+
+    /// The document that this event is about, if it's about one.
+    /// Subscriptions are about a document too, but they're sent before the peer has it open.
+    pub fn document(&self) -> Option<&str> {
+        match self {
+            Event::RemoteInsert { document, .. }
+            | Event::RemoteDelete { document, .. }
+            | Event::RemoteLock { document, .. }
+            | Event::RemoteUnlock { document, .. }
+            | Event::RemotePresence { document, .. }
+            | Event::Comment { document, .. }
+            | Event::Snapshot { document, .. } => Some(document),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Peer {
-    id: i64,
+    /// Where the peer's connection comes from, so that the peer can be dropped once it disconnects.
     addr: SocketAddr,
-    conn: TcpStream,
+    conn: OwnedWriteHalf,
+    /// The capabilities that both this node and the peer support.
+    capabilities: Capabilities,
+    /// Events waiting to be sent to the peer.
+    batch: Batch,
+    /// The documents that the peer has open. It's only sent the events for these documents.
+    subscriptions: HashSet<DocumentId>,
 }
 
 impl Peer {
     #[instrument(level = "info")]
-    pub fn new(id: i64, addr: SocketAddr, conn: TcpStream) -> Self {
-        Self { id, addr, conn }
+    pub fn new(
+        addr: SocketAddr,
+        conn: OwnedWriteHalf,
+        capabilities: Capabilities,
+        window: Duration,
+    ) -> Self {
+        Self {
+            addr,
+            conn,
+            capabilities,
+            batch: Batch::new(window),
+            subscriptions: vec![DEFAULT_DOCUMENT.to_string()].into_iter().collect(),
+        }
     }
 
     /// Send the event to the peer.
     #[instrument(level = "info")]
     pub async fn send(&mut self, event: &Event) -> io::Result<()> {
-        let buf = serialize(event).unwrap();
-        self.conn.write_all(&buf).await
+        let buf = Envelope::seal(event, self.capabilities);
+        protocol::send(&mut self.conn, &buf).await
+    }
+
+    /// Queues the event so that it's sent along with the next batch.
+    /// Peers that can't receive batches are sent the event right away.
+    #[instrument(level = "info")]
+    pub async fn queue(&mut self, event: Event) -> io::Result<()> {
+        if !self.capabilities.contains(Capabilities::BATCHING) {
+            return self.send(&event).await;
+        }
+
+        self.batch.push(event);
+
+        if self.batch.is_full() {
+            self.flush().await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends any queued events.
+    #[instrument(level = "info")]
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match self.batch.take() {
+            Some(event) => self.send(&event).await,
+            None => Ok(()),
+        }
     }
 }
 
+/// The sender of a message along with the connection that it arrived on.
+/// The connection is handed over to the peer once the sender is known.
 #[derive(Debug)]
-pub struct Client {
+struct Origin {
     addr: SocketAddr,
-    conn: TcpStream,
+    conn: Option<OwnedWriteHalf>,
+    version: u16,
+    /// The capabilities that both this node and the sender support.
+    capabilities: Capabilities,
+}
+
+impl Origin {
+    fn new(addr: SocketAddr, conn: Option<OwnedWriteHalf>) -> Self {
+        Self {
+            addr,
+            conn,
+            version: protocol::VERSION,
+            capabilities: Capabilities::NONE,
+        }
+    }
+}
+
+/// The connection to the editor, which speaks the newline delimited JSON-RPC protocol described in `frontend`.
+/// The protocol can run over any transport, such as a TCP connection or the standard streams.
+pub struct Client {
+    transport: String,
+    reader: Lines<BufReader<Box<dyn AsyncRead + Unpin + Send>>>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("transport", &self.transport)
+            .finish()
+    }
 }
 
 impl Client {
-    // Sends the event as a JSON payload to the frontend.
+    pub fn new<R, W>(transport: String, reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(reader);
+
+        Self {
+            transport,
+            reader: BufReader::new(reader).lines(),
+            writer: Box::new(writer),
+        }
+    }
+
+    /// Opens the connection to the editor using the configured transport.
+    #[instrument(level = "info")]
+    pub async fn open(frontend: &config::Frontend) -> io::Result<Self> {
+        match frontend {
+            config::Frontend::Tcp { host, port } => Self::connect(host, *port).await,
+            config::Frontend::Stdio => Ok(Self::stdio()),
+            config::Frontend::Unix { path } => Self::listen(path).await,
+            config::Frontend::WebSocket {
+                host,
+                port,
+                origins,
+            } => {
+                let listener = TcpListener::bind((host.as_str(), *port)).await?;
+                Self::websocket(listener, origins).await
+            }
+            config::Frontend::Neovim { host, port } => {
+                let listener = TcpListener::bind((host.as_str(), *port)).await?;
+                Self::neovim(listener).await
+            }
+        }
+    }
+
+    /// Connects to an editor that is listening on a TCP port.
+    #[instrument(level = "info")]
+    pub async fn connect(host: &str, port: u16) -> io::Result<Self> {
+        let conn = TcpStream::connect((host, port)).await?;
+        let transport = format!("tcp://{}", conn.peer_addr()?);
+        let (reader, writer) = conn.into_split();
+
+        Ok(Self::new(transport, reader, writer))
+    }
+
+    /// Listens on a Unix domain socket at `path` and waits for the editor to connect to it.
+    /// Only the user running the node may read or write to the socket, and connections from any other user are refused.
     #[instrument(level = "info")]
-    pub fn send(&self, range: &Range, event: &Event) {
-        let buf = to_vec(event).expect("Unable to serialize event.");
-        self.conn.write_all(&buf);
+    pub async fn listen(path: &str) -> io::Result<Self> {
+        // A socket left behind by a previous run would prevent us from binding.
+        // Anything else at `path`, or a socket of another user, is left alone, since it isn't ours to remove.
+        match fs::symlink_metadata(path) {
+            Ok(metadata)
+                if metadata.file_type().is_socket()
+                    && metadata.uid() == unsafe { libc::getuid() } =>
+            {
+                fs::remove_file(path)?
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket of ours.", path),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = UnixListener::bind(path)?;
+
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+        let owner = fs::metadata(path)?.uid();
+
+        info!("Waiting for the editor to connect to {}.", path);
+
+        loop {
+            let (conn, _) = listener.accept().await?;
+
+            // The connection may have been made before the permissions were restricted.
+            if conn.peer_cred()?.uid() != owner {
+                error!("Refused editor connection from another user.");
+                continue;
+            }
+
+            let (reader, writer) = conn.into_split();
+
+            return Ok(Self::new(format!("unix://{}", path), reader, writer));
+        }
     }
 
+    /// Waits for a browser to connect to `listener` over a WebSocket.
+    /// Each text message carries a single message of the frontend protocol.
+    /// Any page open in the user's browser could connect otherwise, so handshakes from pages that aren't served from
+    /// one of `origins` (or from the node's own address) are refused.
+    #[cfg(feature = "websocket")]
     #[instrument(level = "info")]
-    pub async fn connect(config: config::Client) -> Self {
-        if let Ok(conn) = TcpStream::connect((config.host, config.port)).await {
-            Self {
-                addr: conn.local_addr().unwrap(),
-                conn,
+    pub async fn websocket(listener: TcpListener, origins: &[String]) -> io::Result<Self> {
+        let local = listener.local_addr()?;
+
+        info!("Waiting for the editor to connect to ws://{}.", local);
+
+        loop {
+            let (conn, addr) = listener.accept().await?;
+            #[allow(clippy::result_large_err)]
+            let check = |request: &websocket::Request, response| {
+                websocket::check(request, response, local, origins)
+            };
+
+            match tokio_tungstenite::accept_hdr_async(conn, check).await {
+                Ok(ws) => {
+                    let (client, pipe) = Self::bridged(format!("ws://{}", addr));
+
+                    tokio::spawn(websocket::bridge(ws, pipe));
+
+                    return Ok(client);
+                }
+                Err(e) => error!("Refused WebSocket connection from {}: {}.", addr, e),
             }
-        } else {
-            panic!("Unable to connect to client editor.");
         }
     }
+
+    #[cfg(not(feature = "websocket"))]
+    pub async fn websocket(_: TcpListener, _: &[String]) -> io::Result<Self> {
+        Err(io::Error::other(
+            "This node was built without the \"websocket\" feature.",
+        ))
+    }
+
+    /// Waits for Neovim to connect to `listener`, and shares its current buffer.
+    /// Neovim speaks msgpack-rpc, which is translated to and from the frontend protocol.
+    #[cfg(feature = "neovim")]
+    #[instrument(level = "info")]
+    pub async fn neovim(listener: TcpListener) -> io::Result<Self> {
+        info!("Waiting for Neovim to connect to {}.", listener.local_addr()?);
+
+        let (conn, addr) = listener.accept().await?;
+        let (client, pipe) = Self::bridged(format!("nvim://{}", addr));
+
+        tokio::spawn(neovim::bridge(conn, pipe));
+
+        Ok(client)
+    }
+
+    #[cfg(not(feature = "neovim"))]
+    pub async fn neovim(_: TcpListener) -> io::Result<Self> {
+        Err(io::Error::other(
+            "This node was built without the \"neovim\" feature.",
+        ))
+    }
+
+    /// Creates a client for an editor that speaks some other protocol, along with the pipe that a bridge translating
+    /// that protocol should use. The node reads and writes the pipe like any other transport.
+    #[cfg(any(feature = "websocket", feature = "neovim"))]
+    fn bridged(transport: String) -> (Self, io::DuplexStream) {
+        let (local, remote) = io::duplex(BRIDGE_BUFFER);
+        let (reader, writer) = io::split(local);
+
+        (Self::new(transport, reader, writer), remote)
+    }
+
+    /// Uses stdin and stdout, for editors that spawn the node as a child process.
+    pub fn stdio() -> Self {
+        Self::new("stdio".to_string(), io::stdin(), io::stdout())
+    }
+
+    /// Reads the next line sent by the editor, or `None` once it has disconnected.
+    #[instrument(level = "info")]
+    pub async fn recv(&mut self) -> io::Result<Option<String>> {
+        self.reader.next_line().await
+    }
+
+    /// Responds to a request made by the editor.
+    #[instrument(level = "info")]
+    pub async fn respond(&mut self, id: Value, result: Result<Value, Failure>) -> io::Result<()> {
+        self.write(&frontend::response(id, result)).await
+    }
+
+    /// Sends a notification to the editor, such as a change made by another site.
+    #[instrument(level = "info")]
+    pub async fn notify(&mut self, notification: &Notification) -> io::Result<()> {
+        self.write(&notification.to_line()).await
+    }
+
+    /// Writes a line, flushing it right away since the editor is waiting on it.
+    async fn write(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await
+    }
+}
+
+/// A message read from the connection of a peer, or `None` once the peer has disconnected.
+type Message = (SocketAddr, Option<Vec<u8>>);
+
+/// What the node should do next.
+enum Next {
+    /// A peer connected.
+    Peer((TcpStream, SocketAddr)),
+    /// A peer sent a message, or disconnected.
+    Message(Message),
+    /// The editor sent a line, or disconnected.
+    Client(Option<String>),
+    /// A batch is due to be sent.
+    Flush,
+    /// The shared file is due to be written back to disk.
+    Save,
+    /// The shared file is due to be checked for changes made outside of the editor.
+    Watch,
+    /// A snapshot of the documents is due to be written, so that the log can be compacted.
+    Snapshot,
+    /// The operations appended to the log are due to be committed.
+    Commit,
 }
 
 /// A node will handle propagation of changes in its respective document.
@@ -82,16 +532,56 @@ pub struct Node {
     socket: TcpListener,
     client: Client,
     peers: HashMap<i64, Peer>,
-    document: Document,
+    /// The connections of peers that haven't told us their site yet.
+    connections: HashMap<SocketAddr, OwnedWriteHalf>,
+    /// The capabilities negotiated with each connection, from the first message that it carried.
+    negotiated: HashMap<SocketAddr, Capabilities>,
+    /// Messages read from every connection, by a task per connection.
+    inbox: flume::Receiver<Message>,
+    outbox: flume::Sender<Message>,
+    /// The documents that the editor has open.
+    workspace: Workspace,
+    keys: Keys,
+    roles: Roles,
+    /// How this node is shown to other participants.
+    identity: Identity,
+    /// Where our own cursor is and in which document, so that it can be shown to peers as they join.
+    presence: Option<(DocumentId, Presence)>,
+    /// The capabilities that this node is willing to use.
+    capabilities: Capabilities,
+    /// How long outgoing events are held back so that they can be sent together.
+    window: Duration,
+    /// The file on disk that the default document is written back to, if any.
+    shared: Option<Shared>,
+    /// How often the shared file is written back.
+    save_interval: Duration,
+    /// How often the shared file is checked for changes made outside of the editor.
+    watch_interval: Duration,
+    /// The log that every operation is appended to, if any, so that the documents survive a crash.
+    log: Option<Log>,
+    /// How often a snapshot of the documents is written to the log.
+    snapshot_interval: Duration,
 }
 
 impl Node {
-    /// Creates the node, creating client connections as necessary.
-    /// Any errors connecting will immediately terminate the initalization process.
+    /// Creates the node for `site`, which talks to the editor through `client`.
+    /// Any errors binding the local address will immediately terminate the initalization process.
+    #[allow(clippy::too_many_arguments)]
     #[instrument(level = "info")]
-    pub async fn init(addr: config::Client, client_addr: config::Client) -> Self {
+    pub async fn init(
+        site: i64,
+        addr: config::Client,
+        client: Client,
+        keys: Keys,
+        roles: Roles,
+        identity: Identity,
+        capabilities: Capabilities,
+        window: Duration,
+    ) -> Self {
         match TcpListener::bind((addr.host.clone(), addr.port)).await {
             Ok(socket) => {
+                let (outbox, inbox) = flume::unbounded();
+
                 info!(
                     "Started TCP listener on {}:{}.",
                     addr.host.clone(),
@@ -101,116 +591,2554 @@ impl Node {
                 Self {
                     host: addr.host,
                     port: addr.port,
-                    id: -1,
+                    id: site,
                     socket,
-                    client: Client::connect(client_addr).await,
+                    client,
                     peers: HashMap::new(),
-                    document: Document::new(-1),
+                    connections: HashMap::new(),
+                    negotiated: HashMap::new(),
+                    inbox,
+                    outbox,
+                    workspace: Workspace::new(site),
+                    keys,
+                    roles,
+                    identity,
+                    presence: None,
+                    capabilities,
+                    window,
+                    shared: None,
+                    save_interval: Duration::default(),
+                    watch_interval: Duration::default(),
+                    log: None,
+                    snapshot_interval: Duration::default(),
                 }
             }
-            Err(e) => panic!(format!(
-                "Error connecting to local address: {}:{}",
-                addr.host, addr.port
-            )),
+            Err(e) => panic!(
+                "Error connecting to local address {}:{}: {}",
+                addr.host, addr.port, e
+            ),
+        }
+    }
+
+    /// Shares a file from disk as the default document, which is written back to the file every `save` and when the
+    /// node stops. The file is checked for changes made outside of the editor every `watch`.
+    /// If the default document was recovered from the log, the file's content is applied to it as edits instead, like
+    /// a change made on disk, since the file may have been changed while the node was down.
+    #[instrument(level = "info")]
+    pub async fn share(&mut self, shared: Shared, text: &str, save: Duration, watch: Duration) {
+        if let Some(file) = self.workspace.get_mut(DEFAULT_DOCUMENT) {
+            if !file.document.atoms().is_empty() {
+                if let Err(e) = self.rewrite(DEFAULT_DOCUMENT, text).await {
+                    error!(
+                        "Error applying the shared file to the recovered document: {}.",
+                        e.message
+                    );
+                }
+            } else {
+                file.document.seed(text);
+
+                let atoms = file.document.atoms();
+                let seq = file.history.next(self.id);
+                record(
+                    &mut self.log,
+                    file,
+                    DEFAULT_DOCUMENT,
+                    self.id,
+                    seq,
+                    Operation::Insert,
+                    &atoms,
+                );
+            }
         }
+
+        self.shared = Some(shared);
+        self.save_interval = save;
+        self.watch_interval = watch;
+    }
+
+    /// Rebuilds the documents from the `snapshot` and the `entries` after it in `log`, which every operation is
+    /// appended to from now on. Every document in the log is opened again.
+    /// A snapshot is written every `interval` if there were operations since the last one, and when the node stops.
+    #[instrument(level = "info", skip(snapshot, entries))]
+    pub fn recover(
+        &mut self,
+        log: Log,
+        snapshot: Option<Snapshot>,
+        entries: Vec<Entry>,
+        interval: Duration,
+    ) {
+        for document in logged(snapshot.as_ref(), &entries) {
+            let history = History::replay(document, snapshot.as_ref(), &entries);
+            let file = self.workspace.open(document);
+
+            file.document.load(&history.atoms(history.version()));
+            file.history = history;
+        }
+
+        self.log = Some(log);
+        self.snapshot_interval = interval;
     }
 
     /// An event can come from one of two sources:
     /// - The client (editor frontend); or
     /// - connected peers (foreign replicated documents)
     /// # Client
-    /// Message from client -> Update local document state -> Propagate change(s) to connected peers
+    /// Request from client -> Update local document state -> Propagate change(s) to connected peers -> Respond to client
     /// # Peers
-    /// Message from peer -> Send character operation to messaging service -> Renders the new document state
+    /// Message from peer -> Verify its signature -> Send character operation to messaging service -> Renders the new document state
+    ///
+    /// Messages using a protocol version that we don't support are rejected.
+    /// A single message may carry a (compressed) batch of events, which are handled in order.
+    /// Outgoing batches are sent whenever their window elapses.
+    /// The shared file (if any) is saved periodically, and once more when the node stops. It's also checked
+    /// periodically for changes made outside of the editor.
+    /// A snapshot of the documents is written to the log (if any) periodically, and once more when the node stops.
+    /// The node stops once the editor disconnects.
     #[instrument(level = "info")]
     pub async fn run(&mut self) -> io::Result<()> {
         info!("[{}:{}] Running node...", self.host, self.port);
 
+        let mut save_at = Instant::now() + self.save_interval;
+        let mut watch_at = Instant::now() + self.watch_interval;
+        let mut snapshot_at = Instant::now() + self.snapshot_interval;
+        let mut commit_at = None;
+
         loop {
-            let (mut conn, addr) = self.socket.accept().await?;
-            let mut buf = Vec::new();
-
-            conn.read_to_end(&mut buf).await?;
-
-            match deserialize::<Event>(&buf) {
-                Ok(event) => match event {
-                    Event::Insert {
-                        ref lines,
-                        ref range,
-                    } => {
-                        if let Some(lines) = self.document.local_insert(range, lines) {
-                            let event = Event::RemoteInsert { id: self.id, lines };
-                            self.propagate(event).await;
-                        }
-                    }
+            // Operations are committed a little after the first one that wasn't, rather than one by one.
+            if commit_at.is_none() && self.log.as_ref().is_some_and(Log::uncommitted) {
+                commit_at = Some(Instant::now() + COMMIT_INTERVAL);
+            }
 
-                    Event::Delete { ref range } => {
-                        if let Some(lines) = self.document.local_delete(range) {
-                            let event = Event::RemoteDelete { id: self.id, lines };
-                            self.propagate(event).await;
-                        }
-                    }
+            let deadline = self.next_flush();
+            let next = select! {
+                accepted = self.socket.accept() => Next::Peer(accepted?),
+                message = self.inbox.recv_async() => Next::Message(message.expect("The node holds a sender.")),
+                line = self.client.recv() => Next::Client(line?),
+                _ = time::sleep_until(deadline) => Next::Flush,
+                _ = time::sleep_until(save_at), if self.shared.is_some() => Next::Save,
+                _ = time::sleep_until(watch_at), if self.shared.is_some() => Next::Watch,
+                _ = time::sleep_until(snapshot_at), if self.log.is_some() => Next::Snapshot,
+                _ = time::sleep_until(commit_at.unwrap_or(deadline)), if commit_at.is_some() => Next::Commit,
+            };
 
-                    Event::RemoteInsert { id, ref lines } => {
-                        self.add_peer(id, addr, conn);
-                        if let Some(ref range) = self.document.remote_insert(lines) {
-                            // send range and line contents
-                            self.client.send(range, lines);
-                        }
-                    }
+            match next {
+                Next::Peer((conn, addr)) => {
+                    self.connect(conn, addr);
+                }
+                Next::Message((addr, Some(buf))) => self.receive(addr, &buf).await,
+                Next::Message((addr, None)) => self.disconnect(addr),
+                Next::Client(Some(line)) => self.request(&line).await,
+                Next::Client(None) => {
+                    info!("Editor disconnected.");
+                    self.watch().await;
+                    self.save();
+                    self.commit().await;
+                    self.snapshot();
+                    return Ok(());
+                }
+                Next::Flush => self.flush().await,
+                Next::Save => {
+                    // Changes made on disk since the last check are picked up first, so that they aren't overwritten.
+                    self.watch().await;
+                    self.save();
+                    save_at = Instant::now() + self.save_interval;
+                }
+                Next::Watch => {
+                    self.watch().await;
+                    watch_at = Instant::now() + self.watch_interval;
+                }
+                Next::Snapshot => {
+                    self.commit().await;
+                    self.snapshot();
+                    snapshot_at = Instant::now() + self.snapshot_interval;
+                }
+                Next::Commit => {
+                    self.commit().await;
+                    commit_at = None;
+                }
+            }
+        }
+    }
 
-                    Event::RemoteDelete { id, ref lines } => {
-                        self.add_peer(id, addr, conn);
-                        if let Some(range) = self.document.remote_delete(lines) {
-                            self.client.send(range, lines);
-                        }
-                    }
-                },
-                Err(e) => error!("Error parsing message from peer: {}", e),
-            };
+    /// Writes the default document back to the shared file, if it changed since it was last written.
+    /// Call `watch` first, since the file isn't written while it holds changes that weren't picked up.
+    #[instrument(level = "info")]
+    fn save(&mut self) {
+        let (shared, file) = match (self.shared.as_mut(), self.workspace.get(DEFAULT_DOCUMENT)) {
+            (Some(shared), Some(file)) => (shared, file),
+            _ => return,
+        };
+
+        match shared.save(&file.document.content()) {
+            Ok(true) => info!("Saved {}.", shared.path().display()),
+            Ok(false) => {}
+            Err(e) => error!("Error saving shared file: {}.", e),
         }
     }
 
-    /// Add a peer to the network.
-    /// Peers are identified by their GUID.
-    /// If a peer is unidentified (i.e. their GUID is either -1 (unitialized) or unknown), then it will be added to the network.
-    /// Otherwise, it is ignored.
+    /// Commits the operations appended to the log, on a thread where waiting for the disk doesn't hold up other tasks.
+    async fn commit(&mut self) {
+        let mut log = match self.log.take() {
+            Some(log) if log.uncommitted() => log,
+            log => {
+                self.log = log;
+                return;
+            }
+        };
+
+        match task::spawn_blocking(move || (log.commit(), log)).await {
+            Ok((res, log)) => {
+                if let Err(e) = res {
+                    error!("Unable to commit operations to the log: {}.", e);
+                }
+
+                self.log = Some(log);
+            }
+            Err(e) => error!("Unable to commit operations to the log: {}.", e),
+        }
+    }
+
+    /// Writes a snapshot of every document in the log to it, if there were operations since the last one and they
+    /// were all committed. The log then drops the operations that the snapshot covers.
+    /// Open documents are taken as they are, and documents that were closed are rebuilt from the log, since they'd be
+    /// lost along with the operations otherwise.
     #[instrument(level = "info")]
-    fn add_peer(&mut self, id: i64, addr: SocketAddr, conn: TcpStream) {
-        if !self.peers.contains_key(&id) {
-            self.peers.insert(id, Peer::new(id, addr, conn));
+    fn snapshot(&mut self) {
+        let log = match self.log.as_mut() {
+            Some(log) if log.pending() > 0 && !log.uncommitted() => log,
+            _ => return,
+        };
+        let (previous, entries) = match log.contents() {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Error reading the log for a snapshot: {}.", e);
+                return;
+            }
+        };
+        let workspace = &self.workspace;
+        let open = workspace.documents().filter_map(|document| {
+            let file = workspace.get(document)?;
+
+            Some(Image {
+                document: document.clone(),
+                version: file.history.version().clone(),
+                atoms: file.document.atoms(),
+            })
+        });
+        let closed = logged(previous.as_ref(), &entries)
+            .into_iter()
+            .filter(|document| !workspace.is_open(document))
+            .map(|document| {
+                let history = History::replay(document, previous.as_ref(), &entries);
+
+                Image {
+                    document: document.to_string(),
+                    version: history.version().clone(),
+                    atoms: history.atoms(history.version()),
+                }
+            });
+        let documents = open.chain(closed).collect();
+
+        if let Err(e) = log.snapshot(documents) {
+            error!("Error writing snapshot: {}.", e);
         }
     }
 
-    /// Send the change to each client's respective thread.
+    /// Picks up changes made to the shared file outside of the editor, e.g. by `cargo fmt` or `git checkout`.
     #[instrument(level = "info")]
-    async fn propagate(&mut self, event: Event) {
-        let tasks: Vec<_> = self
-            .peers
-            .iter_mut()
-            .map(|(_, peer)| peer.send(&event))
-            .collect();
+    async fn watch(&mut self) {
+        let text = match self.shared.as_mut().map(Shared::poll) {
+            Some(Ok(Some(text))) => text,
+            Some(Err(e)) => {
+                error!("Error reading shared file: {}.", e);
+                return;
+            }
+            _ => return,
+        };
 
-        for task in tasks {
-            if let Err(e) = task.await {
-                error!("Error sending change to peer: {}.", e);
+        info!("Shared file changed on disk.");
+
+        if let Err(e) = self.rewrite(DEFAULT_DOCUMENT, &text).await {
+            error!("Error applying change made on disk: {}.", e.message);
+        }
+    }
+
+    /// Turns the content of `document` into `text` with as few edits as possible.
+    /// The edits are made as if they were typed in the editor, so they're sent to peers (and refused the same way,
+    /// e.g. if the text is locked), and the editor is told about each one.
+    /// Every edit is checked before any of them is made, so if one would be refused, then nothing is changed.
+    #[instrument(level = "info")]
+    async fn rewrite(&mut self, document: &str, text: &str) -> Result<(), Failure> {
+        let file = self
+            .workspace
+            .get(document)
+            .ok_or_else(|| not_open(document))?;
+        let edits = diff::edits(&file.document.content(), text);
+
+        if !edits.is_empty() && !self.roles.own().can_edit() {
+            return Err(Failure::rejected("Viewers are not allowed to edit."));
+        }
+
+        // The edits go from the end of the text to the start, so none of them moves the text before it and each one
+        // can be checked against the document as it is now. An insert lands next to the end of what's deleted.
+        for (range, text) in &edits {
+            let deleted = Some(range)
+                .filter(|range| range.start != range.end)
+                .and_then(|range| file.document.locked_range(range));
+            let inserted = Some(&range.end)
+                .filter(|_| !text.is_empty())
+                .and_then(|point| file.document.locked_point(point));
+
+            if let Some(lock) = deleted.or(inserted) {
+                let reason = format!("Text is locked by site {}.", lock.site());
+                return Err(Failure::rejected(reason));
             }
         }
+
+        for (range, text) in edits {
+            let document = document.to_string();
+
+            if range.start != range.end {
+                self.delete(&document, &range).await?;
+            }
+
+            if !text.is_empty() {
+                let at = Range {
+                    start: range.start.clone(),
+                    end: range.start.clone(),
+                };
+
+                if let Err(e) = self.insert(&document, &at, &text).await {
+                    // The text was still deleted, which the editor has to be told about.
+                    let text = String::new();
+                    self.notify(Notification::Change {
+                        document,
+                        range,
+                        text,
+                    })
+                    .await;
+                    return Err(e);
+                }
+            }
+
+            self.notify(Notification::Change {
+                document,
+                range,
+                text,
+            })
+            .await;
+        }
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::config::Client;
-    use super::Node;
+    /// Joins the session of the node at `addr`, asking for the role that we were configured with.
+    /// The node answers with the role that it grants us, along with what's in the documents so that we catch up.
+    #[instrument(level = "info")]
+    pub async fn join(&mut self, addr: &config::Client) -> io::Result<()> {
+        let conn = TcpStream::connect((addr.host.as_str(), addr.port)).await?;
+        let peer = conn.peer_addr()?;
+        let role = self.roles.own();
+        let join = Event::Join {
+            id: self.id,
+            role,
+            signature: self.keys.sign_event("join", self.id, &role),
+        };
+        let buf = Envelope::seal(&join, self.capabilities);
 
-    #[tokio::test]
-    async fn test_add_node() -> Result<(), Box<dyn std::error::Error>> {
-        let addr = Client::new("localhost".to_string(), 2000);
-        let client = Client::new("localhost".to_string(), 2001);
-        let mut n1 = Node::init(addr, client).await;
+        info!("Joining {}:{} as {:?}.", addr.host, addr.port, role);
 
-        n1.run().await?;
+        protocol::send(self.connect(conn, peer), &buf).await
+    }
+
+    /// Keeps the connection of a peer, whose messages are read by a task of their own and handed to the node.
+    /// Returns the end of the connection that we write to.
+    fn connect(&mut self, conn: TcpStream, addr: SocketAddr) -> &mut OwnedWriteHalf {
+        let (reader, writer) = conn.into_split();
+
+        task::spawn(read(reader, addr, self.outbox.clone()));
+
+        self.connections.entry(addr).or_insert(writer)
+    }
+
+    /// Drops the connection from `addr`, along with the peer that it belonged to.
+    #[instrument(level = "info")]
+    fn disconnect(&mut self, addr: SocketAddr) {
+        info!("Peer {} disconnected.", addr);
+
+        self.connections.remove(&addr);
+        self.negotiated.remove(&addr);
+        self.peers.retain(|_, peer| peer.addr != addr);
+    }
+
+    /// Handles each of the events inside of a message sent by a peer.
+    /// The connection stays with us until the peer tells us its site.
+    #[instrument(level = "info", skip(buf))]
+    async fn receive(&mut self, addr: SocketAddr, buf: &[u8]) {
+        let mut origin = Origin::new(addr, self.connections.remove(&addr));
+
+        self.open(buf, &mut origin).await;
+
+        if let Some(conn) = origin.conn.take() {
+            self.connections.insert(addr, conn);
+        }
+    }
+
+    async fn open(&mut self, buf: &[u8], origin: &mut Origin) {
+        let envelope = match Envelope::open(buf) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Error parsing message from peer: {}", e);
+                self.reject(-1, origin, &e.to_string()).await;
+                return;
+            }
+        };
+
+        origin.version = envelope.version;
+
+        // The capabilities of a connection are worked out once, rather than for every message that it carries.
+        match self.negotiated.get(&origin.addr) {
+            Some(capabilities) => origin.capabilities = *capabilities,
+            None => {
+                origin.capabilities = envelope.negotiate(self.capabilities);
+
+                // Peers without these would apply edits differently than we do, so their documents would drift
+                // from ours.
+                if !origin.capabilities.contains(Capabilities::required()) {
+                    let reason = format!(
+                        "Peers must support {:?}, but only {:?} is supported by both of us.",
+                        Capabilities::required(),
+                        origin.capabilities
+                    );
+                    self.reject(-1, origin, &reason).await;
+                    return;
+                }
+
+                self.negotiated.insert(origin.addr, origin.capabilities);
+            }
+        }
+
+        match envelope.events() {
+            Ok(events) => {
+                for event in events {
+                    self.handle(event, origin).await;
+                }
+            }
+            Err(e) => error!("Error parsing message from peer: {}", e),
+        };
+    }
+
+    /// Handles a single line sent by the editor, responding to it unless it's a notification.
+    /// Malformed requests are always answered, since their `id` may not be readable.
+    #[instrument(level = "info")]
+    async fn request(&mut self, line: &str) {
+        let (id, result) = match Request::parse(line) {
+            Ok(Request { id, call }) => (id, self.call(call).await),
+            Err((id, failure)) => (Some(id.unwrap_or(Value::Null)), Err(failure)),
+        };
+
+        if let Some(id) = id {
+            if let Err(e) = self.client.respond(id, result).await {
+                error!("Error responding to editor: {}.", e);
+            }
+        }
+    }
+
+    /// Performs a call made by the editor.
+    /// Edits are refused if we are a viewer or if the text is locked.
+    /// Calls about a document that isn't open are refused.
+    #[instrument(level = "info")]
+    async fn call(&mut self, call: Call) -> Result<Value, Failure> {
+        match call {
+            Call::Open { document } => {
+                let subscribe = !self.workspace.is_open(&document);
+                let content = self.workspace.open(&document).document.content();
+
+                // Peers only send us the changes to the documents that we've subscribed to.
+                if subscribe {
+                    let event = self.subscribe(document);
+                    self.propagate(event).await;
+                }
+
+                Ok(json!({ "content": content }))
+            }
+
+            Call::Close { document } => {
+                if document == DEFAULT_DOCUMENT {
+                    let reason = "The default document can't be closed.";
+                    return Err(Failure::new(frontend::INVALID_PARAMS, reason));
+                }
+
+                if self.workspace.close(&document).is_some() {
+                    if matches!(self.presence, Some((ref open, _)) if *open == document) {
+                        self.presence = None;
+                    }
+
+                    let event = self.unsubscribe(document);
+                    self.propagate(event).await;
+                }
+
+                Ok(Value::Null)
+            }
+
+            Call::Insert {
+                document,
+                range,
+                text,
+            } => {
+                self.insert(&document, &range, &text).await?;
+                Ok(Value::Null)
+            }
+
+            Call::Delete { document, range } => {
+                self.delete(&document, &range).await?;
+                Ok(Value::Null)
+            }
+
+            Call::Lock { document, range } => {
+                if !self.roles.own().can_edit() {
+                    return Err(Failure::rejected("Viewers are not allowed to lock text."));
+                }
+
+                let file = self
+                    .workspace
+                    .get_mut(&document)
+                    .ok_or_else(|| not_open(&document))?;
+
+                match file.document.local_lock(&range) {
+                    Some(lock) => {
+                        let id = lock.id.clone();
+                        let signature = self.keys.sign_event("lock", self.id, &(&document, &lock));
+                        self.propagate(Event::RemoteLock {
+                            id: self.id,
+                            document,
+                            lock,
+                            signature,
+                        })
+                        .await;
+                        Ok(json!({ "lock": id }))
+                    }
+                    None => Err(Failure::new(frontend::INVALID_PARAMS, "Nothing to lock.")),
+                }
+            }
+
+            Call::Unlock { document, lock } => {
+                if lock.site != self.id && !self.roles.own().can_manage() {
+                    return Err(Failure::rejected("Only the owner can remove other locks."));
+                }
+
+                let file = self
+                    .workspace
+                    .get_mut(&document)
+                    .ok_or_else(|| not_open(&document))?;
+
+                if file.document.unlock(&lock).is_some() {
+                    let signature = self.keys.sign_event("unlock", self.id, &(&document, &lock));
+                    self.propagate(Event::RemoteUnlock {
+                        id: self.id,
+                        document,
+                        lock,
+                        signature,
+                    })
+                    .await;
+                }
+
+                Ok(Value::Null)
+            }
+
+            Call::SetRole { site, role } => {
+                if !self.roles.set(self.id, site, role) {
+                    return Err(Failure::rejected("Only the owner can change roles."));
+                }
+
+                info!("Changed the role of site {} to {:?}.", site, role);
+
+                let signature = self.keys.sign_event("set_role", self.id, &(site, role));
+                self.propagate(Event::SetRole {
+                    id: self.id,
+                    site,
+                    role,
+                    signature,
+                })
+                .await;
+
+                let granted = self.granted(site, role);
+
+                if let Some(peer) = self.peers.get_mut(&site) {
+                    if let Err(e) = peer.send(&granted).await {
+                        error!("Error sending role to site {}: {}", site, e);
+                    }
+                }
+
+                Ok(Value::Null)
+            }
+
+            Call::Cursor { document, range } => {
+                let file = self
+                    .workspace
+                    .get(&document)
+                    .ok_or_else(|| not_open(&document))?;
+                let presence = Presence::new(&self.identity, &file.document, &range);
+
+                self.presence = Some((document.clone(), presence.clone()));
+
+                let event = self.presence(document, presence);
+                self.propagate(event).await;
+
+                Ok(Value::Null)
+            }
+
+            Call::Comment {
+                document,
+                range,
+                text,
+            } => {
+                let file = self
+                    .workspace
+                    .get_mut(&document)
+                    .ok_or_else(|| not_open(&document))?;
+                let change = file
+                    .threads
+                    .start(&file.document, &range, &self.identity.name, &text);
+                let thread = change.thread().clone();
+                let event = self.comment(document, change);
+
+                self.propagate(event).await;
+
+                Ok(json!({ "thread": thread }))
+            }
+
+            Call::Reply {
+                document,
+                thread,
+                text,
+            } => {
+                let file = self
+                    .workspace
+                    .get_mut(&document)
+                    .ok_or_else(|| not_open(&document))?;
+
+                match file.threads.reply(&thread, &self.identity.name, &text) {
+                    Some(change) => {
+                        let event = self.comment(document, change);
+
+                        self.propagate(event).await;
+                        Ok(Value::Null)
+                    }
+                    None => Err(Failure::new(frontend::INVALID_PARAMS, "Unknown thread.")),
+                }
+            }
+
+            Call::Resolve { document, thread } => {
+                let file = self
+                    .workspace
+                    .get_mut(&document)
+                    .ok_or_else(|| not_open(&document))?;
+                let site = match file.threads.get(&thread) {
+                    Some(thread) => thread.site(),
+                    None => return Err(Failure::new(frontend::INVALID_PARAMS, "Unknown thread.")),
+                };
+
+                if site != self.id && !self.roles.own().can_edit() {
+                    return Err(Failure::rejected(
+                        "Viewers can only resolve their own threads.",
+                    ));
+                }
+
+                if let Some(change) = file.threads.resolve(&thread) {
+                    let event = self.comment(document, change);
+
+                    self.propagate(event).await;
+                }
+
+                Ok(Value::Null)
+            }
+
+            Call::Comments { document } => {
+                let file = self
+                    .workspace
+                    .get(&document)
+                    .ok_or_else(|| not_open(&document))?;
+                let threads: Vec<_> = file
+                    .threads
+                    .iter()
+                    .map(|thread| thread.view(&file.document))
+                    .collect();
+
+                Ok(json!({ "threads": threads }))
+            }
+
+            Call::Create { path, kind } => {
+                if !self.roles.own().can_edit() {
+                    return Err(Failure::rejected(
+                        "Viewers are not allowed to create files.",
+                    ));
+                }
+
+                let change = self.workspace.tree.create(&path, kind).map_err(invalid)?;
+                let event = self.tree(change);
+
+                self.propagate(event).await;
+
+                Ok(Value::Null)
+            }
+
+            Call::Rename { from, to } => {
+                if !self.roles.own().can_edit() {
+                    return Err(Failure::rejected(
+                        "Viewers are not allowed to rename files.",
+                    ));
+                }
+
+                let (change, moved) = self.workspace.change_tree(|tree| tree.rename(&from, &to));
+                let event = self.tree(change.map_err(invalid)?);
+
+                self.follow(moved);
+                self.propagate(event).await;
+
+                Ok(Value::Null)
+            }
+
+            Call::Remove { path } => {
+                if !self.roles.own().can_edit() {
+                    return Err(Failure::rejected(
+                        "Viewers are not allowed to delete files.",
+                    ));
+                }
+
+                let change = self.workspace.tree.delete(&path).map_err(invalid)?;
+                let event = self.tree(change);
+
+                self.propagate(event).await;
+
+                Ok(Value::Null)
+            }
+
+            Call::Files {} => Ok(json!({ "files": self.workspace.tree.files() })),
+
+            Call::Patch {
+                document,
+                diff,
+                fuzz,
+            } => {
+                let patch = Patch::parse(&diff)
+                    .map_err(|e| Failure::new(frontend::INVALID_PARAMS, e.to_string()))?;
+                let mut applied = Vec::new();
+                let mut rejected = Vec::new();
+                let mut offset = 0;
+
+                // Each hunk is applied on its own, so that a hunk that's refused (e.g. since its text is locked) is
+                // rejected without affecting the others.
+                for i in 0..patch.hunks.len() {
+                    let content = self
+                        .workspace
+                        .get(&document)
+                        .ok_or_else(|| not_open(&document))?
+                        .document
+                        .content();
+                    let step = match patch.apply_hunk(i, &content, offset, fuzz) {
+                        Some(step) => step,
+                        None => {
+                            rejected.push(patch.reject(i, Rejected::NOT_FOUND));
+                            continue;
+                        }
+                    };
+
+                    match self.rewrite(&document, &step.text).await {
+                        Ok(()) => {
+                            offset = step.offset;
+                            applied.push(step.applied);
+                        }
+                        Err(e) => rejected.push(patch.reject(i, &e.message)),
+                    }
+                }
+
+                Ok(json!({ "applied": applied, "rejected": rejected }))
+            }
+
+            Call::History {
+                document,
+                from,
+                to,
+                by_site,
+            } => {
+                let history = &self
+                    .workspace
+                    .get(&document)
+                    .ok_or_else(|| not_open(&document))?
+                    .history;
+                let resolve = |moment: &Moment| {
+                    history.resolve(moment).ok_or_else(|| {
+                        let reason = "The history only goes back to the newest snapshot.";
+                        Failure::new(frontend::INVALID_PARAMS, reason)
+                    })
+                };
+                let from = match from {
+                    Some(from) => resolve(&from)?,
+                    None => Version::new(),
+                };
+                let to = match to {
+                    Some(to) => resolve(&to)?,
+                    None => history.version().clone(),
+                };
+                let path = self.path(&document);
+
+                if by_site {
+                    let patches: Vec<Value> = history
+                        .patches(&path, &from, &to)
+                        .into_iter()
+                        .map(|(site, diff)| json!({ "site": site, "diff": diff }))
+                        .collect();
+
+                    Ok(json!({ "version": history.version(), "patches": patches }))
+                } else {
+                    let diff = history.diff(&path, &from, &to);
+
+                    Ok(json!({ "version": history.version(), "diff": diff }))
+                }
+            }
+
+            Call::Blame { document, range } => {
+                let file = self
+                    .workspace
+                    .get(&document)
+                    .ok_or_else(|| not_open(&document))?;
+                let name = |site: i64| {
+                    if site == self.id {
+                        self.identity.name.clone()
+                    } else {
+                        file.presences
+                            .name(site)
+                            .map_or_else(|| format!("site {}", site), String::from)
+                    }
+                };
+                let blame = blame::blame(&file.document.atoms(), range.as_ref(), name);
+
+                Ok(json!(blame))
+            }
+        }
+    }
+
+    /// The path that diffs of `document` are written for, which is the shared file for the default document.
+    fn path(&self, document: &str) -> String {
+        match &self.shared {
+            Some(shared) if document == DEFAULT_DOCUMENT => shared
+                .path()
+                .file_name()
+                .map_or_else(Default::default, |name| name.to_string_lossy().into_owned()),
+            _ => document.to_string(),
+        }
+    }
+
+    /// Inserts `text` at `range.start` of `document`, as typed in the editor.
+    /// Inserting no text is refused as invalid, so that the editor knows nothing changed.
+    #[instrument(level = "info")]
+    async fn insert(&mut self, document: &str, range: &Range, text: &str) -> Result<(), Failure> {
+        if !self.roles.own().can_edit() {
+            return Err(Failure::rejected("Viewers are not allowed to edit."));
+        }
+
+        let file = self
+            .workspace
+            .get_mut(document)
+            .ok_or_else(|| not_open(document))?;
+
+        if let Some(lock) = file.document.locked_point(&range.start) {
+            let reason = format!("Text is locked by site {}.", lock.site());
+            return Err(Failure::rejected(reason));
+        }
+
+        let lines: Vec<char> = text.chars().collect();
+        let lines = file
+            .document
+            .local_insert(range, &lines)
+            .ok_or_else(|| Failure::new(frontend::INVALID_PARAMS, "Nothing to insert."))?;
+
+        let seq = file.history.next(self.id);
+
+        record(
+            &mut self.log,
+            file,
+            document,
+            self.id,
+            seq,
+            Operation::Insert,
+            &lines,
+        );
+
+        let signature = self
+            .keys
+            .sign(Operation::Insert, self.id, document, seq, &lines);
+        let event = Event::RemoteInsert {
+            id: self.id,
+            document: document.to_string(),
+            seq,
+            lines,
+            signature,
+        };
+        self.propagate(event).await;
+        self.show_presences(document).await;
+
+        Ok(())
+    }
+
+    /// Deletes the text from `range.start` until `range.end` of `document`, as typed in the editor.
+    /// An empty range is refused as invalid, so that the editor knows nothing changed.
+    #[instrument(level = "info")]
+    async fn delete(&mut self, document: &str, range: &Range) -> Result<(), Failure> {
+        if !self.roles.own().can_edit() {
+            return Err(Failure::rejected("Viewers are not allowed to edit."));
+        }
+
+        let file = self
+            .workspace
+            .get_mut(document)
+            .ok_or_else(|| not_open(document))?;
+
+        if let Some(lock) = file.document.locked_range(range) {
+            let reason = format!("Text is locked by site {}.", lock.site());
+            return Err(Failure::rejected(reason));
+        }
+
+        let lines = file
+            .document
+            .local_delete(range)
+            .ok_or_else(|| Failure::new(frontend::INVALID_PARAMS, "Nothing to delete."))?;
+
+        let seq = file.history.next(self.id);
+
+        record(
+            &mut self.log,
+            file,
+            document,
+            self.id,
+            seq,
+            Operation::Delete,
+            &lines,
+        );
+
+        let signature = self
+            .keys
+            .sign(Operation::Delete, self.id, document, seq, &lines);
+        let event = Event::RemoteDelete {
+            id: self.id,
+            document: document.to_string(),
+            seq,
+            lines,
+            signature,
+        };
+        self.propagate(event).await;
+        self.show_presences(document).await;
+
+        Ok(())
+    }
+
+    /// Handles a single event.
+    /// Operations that aren't signed by a trusted key for the site that sent them are dropped.
+    /// Edits from viewers are dropped and the sender is told why.
+    /// Events about documents that we don't have open are dropped, since we can't make sense of them.
+    #[instrument(level = "info")]
+    async fn handle(&mut self, event: Event, origin: &mut Origin) {
+        if let Some(document) = event.document() {
+            if !self.workspace.is_open(document) {
+                info!("Dropped event about {:?}, which isn't open.", document);
+                return;
+            }
+        }
+
+        match event {
+            Event::RemoteInsert {
+                id,
+                ref document,
+                seq,
+                ref lines,
+                ref signature,
+            } => {
+                if let Err(e) =
+                    self.keys
+                        .verify(Operation::Insert, id, document, seq, lines, signature)
+                {
+                    error!("Rejected insert from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.of(id).can_edit() {
+                    self.reject(id, origin, "Viewers are not allowed to edit.").await;
+                    return;
+                }
+
+                // The site that placed a lock may still edit inside of it, as may those who manage the session.
+                let locked = self
+                    .workspace
+                    .get(document)
+                    .and_then(|file| file.document.locked_atoms(lines, id))
+                    .map(Lock::site);
+
+                if let Some(site) = locked.filter(|_| !self.roles.of(id).can_manage()) {
+                    let reason = format!("Text is locked by site {}.", site);
+                    self.reject(id, origin, &reason).await;
+                    return;
+                }
+
+                let file = self.workspace.open(document);
+
+                let inserted = file.document.remote_insert(lines);
+
+                if inserted.is_some() {
+                    record(
+                        &mut self.log,
+                        file,
+                        document,
+                        id,
+                        seq,
+                        Operation::Insert,
+                        lines,
+                    );
+                }
+
+                self.add_peer(id, origin);
+                if let Some((text, range)) = inserted {
+                    let text = text.into_iter().collect();
+                    self.notify(Notification::Change {
+                        document: document.clone(),
+                        range,
+                        text,
+                    })
+                    .await;
+                    self.show_presences(document).await;
+                }
+            }
+
+            Event::RemoteDelete {
+                id,
+                ref document,
+                seq,
+                ref lines,
+                ref signature,
+            } => {
+                if let Err(e) =
+                    self.keys
+                        .verify(Operation::Delete, id, document, seq, lines, signature)
+                {
+                    error!("Rejected delete from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.of(id).can_edit() {
+                    self.reject(id, origin, "Viewers are not allowed to edit.").await;
+                    return;
+                }
+
+                // The site that placed a lock may still edit inside of it, as may those who manage the session.
+                let locked = self
+                    .workspace
+                    .get(document)
+                    .and_then(|file| file.document.locked_atoms(lines, id))
+                    .map(Lock::site);
+
+                if let Some(site) = locked.filter(|_| !self.roles.of(id).can_manage()) {
+                    let reason = format!("Text is locked by site {}.", site);
+                    self.reject(id, origin, &reason).await;
+                    return;
+                }
+
+                let file = self.workspace.open(document);
+
+                let deleted = file.document.remote_delete(lines);
+
+                if deleted.is_some() {
+                    record(
+                        &mut self.log,
+                        file,
+                        document,
+                        id,
+                        seq,
+                        Operation::Delete,
+                        lines,
+                    );
+                }
+
+                self.add_peer(id, origin);
+                if let Some((_, range)) = deleted {
+                    let text = String::new();
+                    self.notify(Notification::Change {
+                        document: document.clone(),
+                        range,
+                        text,
+                    })
+                    .await;
+                    self.show_presences(document).await;
+                }
+            }
+
+            Event::Join {
+                id,
+                role,
+                signature,
+            } => {
+                // Every node needs its own site, or their operations and versions would be mixed up.
+                if id == self.id {
+                    let reason = format!("Site {} is already taken by this node.", id);
+                    self.reject(id, origin, &reason).await;
+                    return;
+                }
+
+                if let Err(e) = self.keys.verify_event("join", id, &role, &signature) {
+                    error!("Rejected join from site {}: {}", id, e);
+                    return;
+                }
+
+                let role = self.roles.grant(id, role);
+
+                info!(
+                    "Site {} joined from {} as {:?} using protocol version {} with {:?}.",
+                    id, origin.addr, role, origin.version, origin.capabilities
+                );
+
+                self.add_peer(id, origin);
+
+                let subscriptions: Vec<_> = self
+                    .workspace
+                    .documents()
+                    .filter(|document| *document != DEFAULT_DOCUMENT)
+                    .map(|document| self.subscribe(document.clone()))
+                    .collect();
+                // Everyone has the default document open, so a peer that joins late is sent what's in it.
+                let snapshot = self.catch_up(DEFAULT_DOCUMENT);
+                // Along with the tree, of which only the owner may pass on changes made by others.
+                let tree: Vec<_> = self
+                    .workspace
+                    .tree
+                    .changes()
+                    .into_iter()
+                    .filter(|change| {
+                        self.roles.own().can_manage()
+                            || change.author().is_none_or(|author| author == self.id)
+                    })
+                    .map(|change| self.tree(change))
+                    .collect();
+
+                let granted = self.granted(id, role);
+                let presence = self
+                    .presence
+                    .clone()
+                    .map(|(document, presence)| self.presence(document, presence));
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    if let Err(e) = peer.send(&granted).await {
+                        error!("Error sending role to site {}: {}", id, e);
+                    }
+
+                    if let Some(snapshot) = snapshot {
+                        if let Err(e) = peer.queue(snapshot).await {
+                            error!("Error sending snapshot to site {}: {}", id, e);
+                        }
+                    }
+
+                    if peer.capabilities.contains(Capabilities::FILES) {
+                        for event in tree {
+                            if let Err(e) = peer.queue(event).await {
+                                error!("Error sending files to site {}: {}", id, e);
+                            }
+                        }
+                    }
+
+                    // Tell the new peer which documents we have open, so that it sends us their changes.
+                    for event in subscriptions {
+                        if let Err(e) = peer.queue(event).await {
+                            error!("Error subscribing to site {}: {}", id, e);
+                        }
+                    }
+
+                    // Show the new peer where our cursor is, rather than waiting for it to move.
+                    if let Some(event) = presence {
+                        if peer.capabilities.contains(Capabilities::PRESENCE) {
+                            if let Err(e) = peer.queue(event).await {
+                                error!("Error sending presence to site {}: {}", id, e);
+                            }
+                        }
+                    }
+                }
+            }
+
+            Event::Granted {
+                id,
+                site,
+                role,
+                signature,
+            } => {
+                if let Err(e) = self
+                    .keys
+                    .verify_event("granted", id, &(site, role), &signature)
+                {
+                    error!("Rejected role granted by site {}: {}", id, e);
+                    return;
+                }
+
+                // Only an owner hands out roles, so that nobody else can take away our privileges.
+                if !self.roles.of(id).can_manage() {
+                    self.reject(id, origin, "Only the owner can grant roles.").await;
+                    return;
+                }
+
+                info!("Site {} granted {:?} to site {}.", id, role, site);
+
+                self.roles.admit(id);
+                self.add_peer(id, origin);
+
+                if site == self.id {
+                    self.roles.accept(role);
+                }
+            }
+
+            Event::SetRole {
+                id,
+                site,
+                role,
+                signature,
+            } => {
+                if let Err(e) = self
+                    .keys
+                    .verify_event("set_role", id, &(site, role), &signature)
+                {
+                    error!("Rejected role change from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.set(id, site, role) {
+                    self.reject(id, origin, "Only the owner can change roles.").await;
+                    return;
+                }
+
+                info!("Site {} changed the role of site {} to {:?}.", id, site, role);
+
+                let granted = self.granted(site, role);
+
+                if let Some(peer) = self.peers.get_mut(&site) {
+                    if let Err(e) = peer.send(&granted).await {
+                        error!("Error sending role to site {}: {}", site, e);
+                    }
+                }
+            }
+
+            Event::Rejected { reason } => {
+                error!("Peer rejected our event: {}", reason);
+                self.notify(Notification::Rejected { reason }).await;
+            }
+
+            Event::RemoteLock {
+                id,
+                document,
+                lock,
+                signature,
+            } => {
+                if let Err(e) = self
+                    .keys
+                    .verify_event("lock", id, &(&document, &lock), &signature)
+                {
+                    error!("Rejected lock from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.of(id).can_edit() || lock.site() != id {
+                    self.reject(id, origin, "Not allowed to place this lock.").await;
+                    return;
+                }
+
+                self.workspace.open(&document).document.remote_lock(lock);
+            }
+
+            Event::RemoteUnlock {
+                id,
+                document,
+                lock,
+                signature,
+            } => {
+                if let Err(e) =
+                    self.keys
+                        .verify_event("unlock", id, &(&document, &lock), &signature)
+                {
+                    error!("Rejected unlock from site {}: {}", id, e);
+                    return;
+                }
+
+                // Only the site that placed a lock or the owner may remove it.
+                if lock.site != id && !self.roles.of(id).can_manage() {
+                    self.reject(id, origin, "Not allowed to remove this lock.").await;
+                    return;
+                }
+
+                self.workspace.open(&document).document.unlock(&lock);
+            }
+
+            Event::Comment {
+                id,
+                document,
+                change,
+                signature,
+            } => {
+                if let Err(e) =
+                    self.keys
+                        .verify_event("comment", id, &(&document, &change), &signature)
+                {
+                    error!("Rejected comment from site {}: {}", id, e);
+                    return;
+                }
+
+                if change.author().is_some_and(|author| author != id) {
+                    self.reject(id, origin, "Comments can only be made in your own name.").await;
+                    return;
+                }
+
+                // The id of a thread names the site that started it, so this doesn't depend on the thread having arrived.
+                if let Change::Resolve { ref thread } = change {
+                    if thread.site != id && !self.roles.of(id).can_edit() {
+                        self.reject(id, origin, "Viewers can only resolve their own threads.")
+                            .await;
+                        return;
+                    }
+                }
+
+                self.add_peer(id, origin);
+
+                let file = self.workspace.open(&document);
+
+                if let Some(thread) = file.threads.apply(change) {
+                    let thread = thread.view(&file.document);
+                    self.notify(Notification::Thread { document, thread }).await;
+                }
+            }
+
+            Event::RemotePresence {
+                id,
+                document,
+                presence,
+                signature,
+            } => {
+                if let Err(e) =
+                    self.keys
+                        .verify_event("presence", id, &(&document, &presence), &signature)
+                {
+                    error!("Rejected presence from site {}: {}", id, e);
+                    return;
+                }
+
+                self.add_peer(id, origin);
+                self.workspace
+                    .open(&document)
+                    .presences
+                    .update(id, presence);
+                self.show_presences(&document).await;
+            }
+
+            Event::Tree {
+                id,
+                change,
+                signature,
+            } => {
+                if let Err(e) = self.keys.verify_event("tree", id, &change, &signature) {
+                    error!("Rejected change to the files from site {}: {}", id, e);
+                    return;
+                }
+
+                // The owner passes on the whole tree to sites that join late, including the changes of others.
+                if change.author().is_some_and(|author| author != id)
+                    && !self.roles.of(id).can_manage()
+                {
+                    self.reject(id, origin, "Files can only be changed in your own name.").await;
+                    return;
+                }
+
+                if !self.roles.of(id).can_edit() {
+                    self.reject(id, origin, "Viewers are not allowed to change files.").await;
+                    return;
+                }
+
+                self.add_peer(id, origin);
+
+                let (changed, moved) = self.workspace.change_tree(|tree| tree.apply(change));
+
+                self.follow(moved);
+
+                if changed {
+                    let files = self.workspace.tree.files();
+                    self.notify(Notification::Files { files }).await;
+                }
+            }
+
+            Event::Subscribe {
+                id,
+                document,
+                signature,
+            } => {
+                if let Err(e) = self
+                    .keys
+                    .verify_event("subscribe", id, &document, &signature)
+                {
+                    error!("Rejected subscription from site {}: {}", id, e);
+                    return;
+                }
+
+                // Sites that were never let into the session are sent nothing.
+                if !self.roles.can_view(id) {
+                    self.reject(id, origin, "Only participants can view documents.").await;
+                    return;
+                }
+
+                self.add_peer(id, origin);
+
+                // Send what we have, so that the peer doesn't start out with an empty document.
+                let snapshot = self.catch_up(&document);
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.subscriptions.insert(document);
+
+                    if let Some(snapshot) = snapshot {
+                        if let Err(e) = peer.queue(snapshot).await {
+                            error!("Error sending snapshot to site {}: {}", id, e);
+                        }
+                    }
+                }
+            }
+
+            Event::Unsubscribe {
+                id,
+                document,
+                signature,
+            } => {
+                if let Err(e) = self
+                    .keys
+                    .verify_event("unsubscribe", id, &document, &signature)
+                {
+                    error!("Rejected unsubscription from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.can_view(id) {
+                    self.reject(id, origin, "Only participants can view documents.").await;
+                    return;
+                }
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.subscriptions.remove(&document);
+                }
+            }
+
+            Event::Snapshot {
+                id,
+                document,
+                version,
+                mut lines,
+                mut locks,
+                mut threads,
+                signature,
+            } => {
+                if let Err(e) = self.keys.verify_event(
+                    "snapshot",
+                    id,
+                    &(&document, &lines, &locks, &threads, &version),
+                    &signature,
+                ) {
+                    error!("Rejected snapshot from site {}: {}", id, e);
+                    return;
+                }
+
+                if !self.roles.of(id).can_edit() {
+                    self.reject(id, origin, "Viewers are not allowed to send snapshots.").await;
+                    return;
+                }
+
+                // Anyone could credit text to another site in a snapshot, so only the owner may pass on the text of
+                // others. Everyone else only catches us up on their own text, locks and comments.
+                if !self.roles.of(id).can_manage() {
+                    lines.retain(|atom| atom.site() == Some(id));
+                    locks.retain(|lock| lock.site() == id);
+                    threads.retain(|thread| thread.site() == id);
+
+                    for thread in &mut threads {
+                        thread.comments.retain(|comment| comment.id.site == id);
+                    }
+                }
+
+                self.add_peer(id, origin);
+
+                // Only what we're missing is inserted, so local edits are kept. A snapshot can't tell us about
+                // deletes, since deleted atoms are simply gone.
+                let file = self.workspace.open(&document);
+                let runs = file.document.missing(&lines);
+
+                for lock in locks {
+                    file.document.remote_lock(lock);
+                }
+
+                // We may already have part of a thread, so it's rebuilt from its changes rather than replaced.
+                let mut changed = Vec::new();
+
+                for thread in threads {
+                    let mut updated = false;
+
+                    for change in thread.changes() {
+                        updated |= file.threads.apply(change).is_some();
+                    }
+
+                    if updated {
+                        changed.push(thread.id);
+                    }
+                }
+
+                // A snapshot holds the text of every site, so each site is recorded as inserting its own atoms, as of
+                // the last of its operations that the snapshot includes.
+                let mut sites: BTreeMap<i64, Vec<Atom>> = BTreeMap::new();
+
+                for atom in runs.iter().flatten() {
+                    if let Some(site) = atom.site() {
+                        sites.entry(site).or_default().push(atom.clone());
+                    }
+                }
+
+                for (site, atoms) in sites {
+                    let seq = version
+                        .get(&site)
+                        .copied()
+                        .unwrap_or_else(|| file.history.next(site));
+
+                    record(
+                        &mut self.log,
+                        file,
+                        &document,
+                        site,
+                        seq,
+                        Operation::Insert,
+                        &atoms,
+                    );
+                }
+
+                let changes: Vec<_> = runs
+                    .iter()
+                    .filter_map(|run| file.document.remote_insert(run))
+                    .collect();
+
+                for (text, range) in changes {
+                    self.notify(Notification::Change {
+                        document: document.clone(),
+                        range,
+                        text: text.into_iter().collect(),
+                    })
+                    .await;
+                }
+
+                // Threads are shown once the text that they're about is there.
+                let threads: Vec<_> = match self.workspace.get(&document) {
+                    Some(file) => changed
+                        .iter()
+                        .filter_map(|id| file.threads.get(id))
+                        .map(|thread| thread.view(&file.document))
+                        .collect(),
+                    None => Vec::new(),
+                };
+
+                for thread in threads {
+                    self.notify(Notification::Thread {
+                        document: document.clone(),
+                        thread,
+                    })
+                    .await;
+                }
+
+                self.show_presences(&document).await;
+            }
+
+            Event::Batch { .. } | Event::Compressed { .. } => {
+                error!("Batches can't be nested.");
+            }
+        }
+    }
+
+    /// Add a peer to the network.
+    /// Peers are identified by their GUID.
+    /// If a peer is unidentified (i.e. their GUID is either -1 (unitialized) or unknown), then it will be added to the network.
+    /// Otherwise, it is ignored.
+    #[instrument(level = "info")]
+    fn add_peer(&mut self, id: i64, origin: &mut Origin) {
+        if self.peers.contains_key(&id) {
+            return;
+        }
+
+        if let Some(conn) = origin.conn.take() {
+            let peer = Peer::new(origin.addr, conn, origin.capabilities, self.window);
+            self.peers.insert(id, peer);
+        }
+    }
+
+    /// Tells `site` that it was granted `role`, signed so that it knows the role came from us.
+    fn granted(&self, site: i64, role: Role) -> Event {
+        Event::Granted {
+            id: self.id,
+            site,
+            role,
+            signature: self.keys.sign_event("granted", self.id, &(site, role)),
+        }
+    }
+
+    /// A signed snapshot of `document`, or `None` if we have nothing to catch up on.
+    fn catch_up(&self, document: &str) -> Option<Event> {
+        let file = self.workspace.get(document)?;
+        let lines = file.document.atoms();
+        let locks = file.document.locks().to_vec();
+        let threads: Vec<_> = file.threads.iter().cloned().collect();
+        let version = file.history.version().clone();
+
+        if lines.is_empty() && threads.is_empty() {
+            return None;
+        }
+
+        let signature = self.keys.sign_event(
+            "snapshot",
+            self.id,
+            &(document, &lines, &locks, &threads, &version),
+        );
+
+        Some(Event::Snapshot {
+            id: self.id,
+            document: document.to_string(),
+            version,
+            lines,
+            locks,
+            threads,
+            signature,
+        })
+    }
+
+    /// Asks peers for the changes to `document`, signed so that nobody else can subscribe in our name.
+    fn subscribe(&self, document: DocumentId) -> Event {
+        let signature = self.keys.sign_event("subscribe", self.id, &document);
+
+        Event::Subscribe {
+            id: self.id,
+            document,
+            signature,
+        }
+    }
+
+    /// Tells peers to stop sending the changes to `document`, signed for the same reason as `subscribe`.
+    fn unsubscribe(&self, document: DocumentId) -> Event {
+        let signature = self.keys.sign_event("unsubscribe", self.id, &document);
+
+        Event::Unsubscribe {
+            id: self.id,
+            document,
+            signature,
+        }
+    }
+
+    /// Tells others about a change to the files, signed so that nobody else can change them in our name.
+    fn tree(&self, change: tree::Change) -> Event {
+        let signature = self.keys.sign_event("tree", self.id, &change);
+
+        Event::Tree {
+            id: self.id,
+            change,
+            signature,
+        }
+    }
+
+    /// Keeps track of the documents that moved because the files they belong to were renamed or moved, so that our
+    /// cursor stays in the document and peers are still sent the changes to the documents they have open.
+    fn follow(&mut self, moved: Vec<(DocumentId, DocumentId)>) {
+        for (from, to) in moved {
+            info!("Document {} moved to {}.", from, to);
+
+            if let Some((ref mut document, _)) = self.presence {
+                if *document == from {
+                    *document = to.clone();
+                }
+            }
+
+            for peer in self.peers.values_mut() {
+                if peer.subscriptions.remove(&from) {
+                    peer.subscriptions.insert(to.clone());
+                }
+            }
+        }
+    }
+
+    /// Tells others about a change to the threads in `document`, signed so that nobody else can comment in our name.
+    fn comment(&self, document: DocumentId, change: Change) -> Event {
+        let signature = self
+            .keys
+            .sign_event("comment", self.id, &(&document, &change));
+
+        Event::Comment {
+            id: self.id,
+            document,
+            change,
+            signature,
+        }
+    }
+
+    /// Tells others that our cursor in `document` is at `presence`, signed so that nobody else can move it.
+    fn presence(&self, document: DocumentId, presence: Presence) -> Event {
+        let signature = self
+            .keys
+            .sign_event("presence", self.id, &(&document, &presence));
+
+        Event::RemotePresence {
+            id: self.id,
+            document,
+            presence,
+            signature,
+        }
+    }
+
+    /// Sends a notification to the editor.
+    #[instrument(level = "info")]
+    async fn notify(&mut self, notification: Notification) {
+        if let Err(e) = self.client.notify(&notification).await {
+            error!("Error notifying editor: {}.", e);
+        }
+    }
+
+    /// Shows the editor every cursor in `document` that moved, either because its participant moved it or because
+    /// the text around it changed.
+    #[instrument(level = "info")]
+    async fn show_presences(&mut self, document: &str) {
+        let notifications = match self.workspace.get_mut(document) {
+            Some(file) => file.presences.moved(document, &file.document),
+            None => return,
+        };
+
+        for notification in notifications {
+            self.notify(notification).await;
+        }
+    }
+
+    /// Tells the sender of an event why it was dropped.
+    /// This uses the connection the event arrived on, unless it already belongs to a peer.
+    #[instrument(level = "info")]
+    async fn reject(&mut self, id: i64, origin: &mut Origin, reason: &str) {
+        error!("Rejected event from site {}: {}", id, reason);
+
+        let event = Event::Rejected {
+            reason: reason.to_string(),
+        };
+
+        let res = match (origin.conn.as_mut(), self.peers.get_mut(&id)) {
+            (Some(conn), _) => {
+                let buf = Envelope::seal(&event, origin.capabilities);
+                protocol::send(conn, &buf).await
+            }
+            (None, Some(peer)) => peer.send(&event).await,
+            (None, None) => Ok(()),
+        };
+
+        if let Err(e) = res {
+            error!("Error notifying site {} of rejection: {}.", id, e);
+        }
+    }
+
+    /// Sends every batch whose window has elapsed.
+    #[instrument(level = "info")]
+    async fn flush(&mut self) {
+        let now = Instant::now();
+
+        for (id, peer) in self.peers.iter_mut() {
+            if !peer.batch.is_due(now) {
+                continue;
+            }
+
+            if let Err(e) = peer.flush().await {
+                error!("Error sending batch to site {}: {}.", id, e);
+            }
+        }
+    }
+
+    /// The earliest time at which one of the batches has to be sent.
+    fn next_flush(&self) -> Instant {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.batch.deadline())
+            .min()
+            .unwrap_or_else(|| Instant::now() + IDLE)
+    }
+
+    /// Queue the change to be sent to each peer.
+    /// Peers that didn't negotiate the capabilities needed for the event are skipped, as are peers that aren't
+    /// subscribed to the document that the event is about.
+    #[instrument(level = "info")]
+    async fn propagate(&mut self, event: Event) {
+        let tasks: Vec<_> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| peer.capabilities.contains(event.required()))
+            .filter(|(_, peer)| {
+                event
+                    .document()
+                    .is_none_or(|document| peer.subscriptions.contains(document))
+            })
+            .map(|(_, peer)| peer.queue(event.clone()))
+            .collect();
+
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("Error sending change to peer: {}.", e);
+            }
+        }
+    }
+}
+
+/// Reads the messages that a peer sends over `conn` and hands them to the node through `outbox`, until the peer
+/// disconnects or sends something that isn't a message.
+async fn read(mut conn: OwnedReadHalf, addr: SocketAddr, outbox: flume::Sender<Message>) {
+    loop {
+        let message = match protocol::recv(&mut conn).await {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error reading from peer {}: {}.", addr, e);
+                None
+            }
+        };
+        let closed = message.is_none();
+
+        // The node is gone once nobody is left to receive.
+        if outbox.send_async((addr, message)).await.is_err() || closed {
+            return;
+        }
+    }
+}
+
+/// The failure returned for changes to the tree that don't make sense, e.g. creating a file that already exists.
+fn invalid(e: tree::Error) -> Failure {
+    Failure::new(frontend::INVALID_PARAMS, e.to_string())
+}
+
+/// The documents that are in a `snapshot` of the log or the `entries` after it.
+fn logged<'a>(snapshot: Option<&'a Snapshot>, entries: &'a [Entry]) -> BTreeSet<&'a str> {
+    snapshot
+        .iter()
+        .flat_map(|snapshot| &snapshot.documents)
+        .map(|image| image.document.as_str())
+        .chain(entries.iter().map(|entry| entry.document.as_str()))
+        .collect()
+}
+
+/// Records an operation on `document` in its history, and appends it to the log if there is one.
+/// An operation that can't be logged is still applied, since peers have applied it already.
+fn record(
+    log: &mut Option<Log>,
+    file: &mut File,
+    document: &str,
+    site: i64,
+    seq: u64,
+    operation: Operation,
+    atoms: &[Atom],
+) {
+    let time = history::now();
+
+    file.history.record(site, seq, operation, atoms, time);
+
+    if let Some(log) = log {
+        let entry = Entry {
+            document: document.to_string(),
+            site,
+            seq,
+            time,
+            operation,
+            atoms: atoms.to_vec(),
+        };
+
+        if let Err(e) = log.append(&entry) {
+            error!("Unable to log an operation: {}", e);
+        }
+    }
+}
+
+/// The failure returned for calls about a document that the editor hasn't opened.
+fn not_open(document: &str) -> Failure {
+    let reason = format!("Document {:?} isn't open.", document);
+    Failure::new(frontend::INVALID_PARAMS, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config;
+    use super::Keys;
+    use super::Node;
+    use super::{
+        Capabilities, Client, Envelope, Event, Identity, Origin, Role, Roles, DEFAULT_DOCUMENT,
+    };
+    use crate::{
+        config::{Assignment, TrustedKey},
+        document::Document,
+        range::Range,
+        signature::Operation,
+        tree::Kind,
+        wal::{Entry, Log},
+    };
+    use ed25519_dalek::Keypair;
+    use rand::rngs::OsRng;
+    use std::{env, fs, iter, os::unix::fs::PermissionsExt, process, time::Duration};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+    };
+
+    /// Plays the part of a browser, talking to the node through a WebSocket.
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn test_websocket_frontend() -> Result<(), Box<dyn std::error::Error>> {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let client = tokio::spawn(async move { Client::websocket(listener, &[]).await });
+        let (mut browser, _) = connect_async(url).await?;
+        let mut client = client.await??;
+
+        browser
+            .send(Message::Text(
+                "{\n  \"jsonrpc\": \"2.0\",\n  \"id\": 1,\n  \"method\": \"open\"\n}".to_string(),
+            ))
+            .await?;
+
+        let line = client.recv().await?.unwrap();
+
+        client
+            .respond(serde_json::json!(1), Ok(serde_json::Value::Null))
+            .await?;
+
+        let response = browser.next().await.unwrap()?;
+
+        assert!(!line.contains('\n'));
+        assert!(line.contains("\"method\": \"open\""));
+        assert!(matches!(response, Message::Text(ref text) if text.contains("\"result\":null")));
+
+        browser.close(None).await?;
+
+        assert!(client.recv().await?.is_none());
+
+        Ok(())
+    }
+
+    /// A page from another site must not be able to talk to the node through the user's browser.
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn test_websocket_origin() -> Result<(), Box<dyn std::error::Error>> {
+        use tokio_tungstenite::{connect_async, tungstenite::http};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let origins = vec!["http://localhost:8000".to_string()];
+        let client = tokio::spawn(async move { Client::websocket(listener, &origins).await });
+        let request = |origin: &str| {
+            http::Request::builder()
+                .uri(&url)
+                .header("Origin", origin)
+                .body(())
+        };
+
+        let refused = connect_async(request("http://evil.example")?).await;
+        let accepted = connect_async(request("http://localhost:8000")?).await;
+
+        assert!(refused.is_err());
+        assert!(accepted.is_ok());
+        client.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_frontend() -> Result<(), Box<dyn std::error::Error>> {
+        let path = env::temp_dir().join(format!("liveshare-{}.sock", process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let listening = path.clone();
+        let client = tokio::spawn(async move { Client::listen(&listening).await });
+
+        // Wait for the socket to be created.
+        while fs::metadata(&path).is_err() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let mut editor = UnixStream::connect(&path).await?;
+
+        editor
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"open\"}\n")
+            .await?;
+
+        let mut client = client.await??;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        let line = client.recv().await?;
+
+        client
+            .respond(serde_json::json!(1), Ok(serde_json::Value::Null))
+            .await?;
+
+        let mut response = String::new();
+        BufReader::new(editor).read_line(&mut response).await?;
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            line.as_deref(),
+            Some("{\"jsonrpc\":\"2.0\",\"method\":\"open\"}")
+        );
+        assert!(response.contains("\"result\":null"));
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_keeps_other_files() -> Result<(), Box<dyn std::error::Error>> {
+        let path = env::temp_dir().join(format!("liveshare-{}.txt", process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        fs::write(&path, "not a socket")?;
+
+        assert!(Client::listen(&path).await.is_err());
+        assert_eq!(fs::read_to_string(&path)?, "not a socket");
+
+        fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_node() -> Result<(), Box<dyn std::error::Error>> {
+        let addr = config::Client::parse("localhost:0");
+        let editor = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let client = Client::connect("127.0.0.1", editor.local_addr()?.port()).await?;
+
+        // The node stops once the editor disconnects.
+        drop(editor.accept().await?);
+        let mut n1 = Node::init(
+            1,
+            addr,
+            client,
+            Keys::generate(1),
+            Roles::new(1, Role::Owner, Role::Editor, &[]),
+            Identity::new(1, None, None),
+            Capabilities::supported(),
+            Duration::from_millis(20),
+        )
+        .await;
+
+        n1.run().await?;
+
+        Ok(())
+    }
+
+    /// Starts a node for `site` with `keys`, along with the editor that it talks to.
+    async fn node(
+        site: i64,
+        keys: Keys,
+    ) -> Result<(Node, tokio::net::TcpStream), Box<dyn std::error::Error>> {
+        node_with(site, keys, Roles::new(site, Role::Owner, Role::Editor, &[])).await
+    }
+
+    /// Starts a node for `site` with `keys` and `roles`, along with the editor that it talks to.
+    async fn node_with(
+        site: i64,
+        keys: Keys,
+        roles: Roles,
+    ) -> Result<(Node, tokio::net::TcpStream), Box<dyn std::error::Error>> {
+        let editor = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let client = Client::connect("127.0.0.1", editor.local_addr()?.port()).await?;
+        let node = Node::init(
+            site,
+            config::Client::parse("localhost:0"),
+            client,
+            keys,
+            roles,
+            Identity::new(site, None, None),
+            Capabilities::supported(),
+            Duration::from_millis(20),
+        )
+        .await;
+        let (editor, _) = editor.accept().await?;
+
+        Ok((node, editor))
+    }
+
+    /// A connection from a peer, as the node would accept it.
+    async fn origin() -> Result<Origin, Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let _peer = tokio::net::TcpStream::connect(listener.local_addr()?).await?;
+        let (conn, addr) = listener.accept().await?;
+        let (_, conn) = conn.into_split();
+        let mut origin = Origin::new(addr, Some(conn));
+
+        origin.capabilities = Capabilities::supported();
+        Ok(origin)
+    }
+
+    #[tokio::test]
+    async fn test_sites() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let (mut n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node(2, Keys::load(2, None, &trusted)?).await?;
+
+        n1.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "a")
+            .await
+            .map_err(|e| e.message)?;
+        n2.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "b")
+            .await
+            .map_err(|e| e.message)?;
+
+        // Each node's operations are its own.
+        let site = |node: &Node| {
+            node.workspace
+                .get(DEFAULT_DOCUMENT)
+                .unwrap()
+                .document
+                .atoms()[0]
+                .site()
+        };
+
+        assert_eq!(site(&n1), Some(1));
+        assert_eq!(site(&n2), Some(2));
+
+        let join = |keys: &Keys, id: i64| Event::Join {
+            id,
+            role: Role::Editor,
+            signature: keys.sign_event("join", id, &Role::Editor),
+        };
+        // The second join is signed with the key of site 2, which is the site of the node it joins.
+        let (joined, taken) = (join(&n1.keys, 1), join(&n2.keys, 2));
+
+        n2.handle(joined, &mut origin().await?).await;
+        n2.handle(taken, &mut origin().await?).await;
+
+        // A node can't join under the site of the node it joins.
+        assert!(n2.peers.contains_key(&1));
+        assert!(!n2.peers.contains_key(&2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_join() -> Result<(), Box<dyn std::error::Error>> {
+        let (k1, k2) = (Keypair::generate(&mut OsRng), Keypair::generate(&mut OsRng));
+        let (p1, p2) = (k1.public, k2.public);
+        let owner = [Assignment {
+            site: 1,
+            role: Role::Owner,
+        }];
+        let (mut n1, e1) = node_with(
+            1,
+            Keys::new(1, k1, iter::once((2, p2)).collect()),
+            Roles::new(1, Role::Owner, Role::Viewer, &[]),
+        )
+        .await?;
+        let (mut n2, e2) = node_with(
+            2,
+            Keys::new(2, k2, iter::once((1, p1)).collect()),
+            Roles::new(2, Role::Owner, Role::Viewer, &owner),
+        )
+        .await?;
+
+        n1.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "hello")
+            .await
+            .map_err(|e| e.message)?;
+        n2.join(&config::Client {
+            host: "127.0.0.1".to_string(),
+            port: n1.socket.local_addr()?.port(),
+        })
+        .await?;
+
+        let n1 = tokio::spawn(async move { n1.run().await.map(|_| n1) });
+        let n2 = tokio::spawn(async move { n2.run().await.map(|_| n2) });
+
+        // The owner grants us a role and sends what's in the default document, which shows up in the editor.
+        let mut lines = BufReader::new(e2).lines();
+        let line = lines.next_line().await?.unwrap_or_default();
+
+        assert!(line.contains("change"), "{}", line);
+        assert!(line.contains("hello"), "{}", line);
+
+        drop((e1, lines));
+
+        let (n1, n2) = (n1.await??, n2.await??);
+
+        // We asked to be an owner, but the owner only lets anybody else view.
+        assert_eq!(n2.roles.own(), Role::Viewer);
+        assert!(n1.peers.contains_key(&2));
+        assert!(n2.peers.contains_key(&1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_granted_by_non_owner() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let editor = [Assignment {
+            site: 1,
+            role: Role::Editor,
+        }];
+        let (n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node_with(
+            2,
+            Keys::load(2, None, &trusted)?,
+            Roles::new(2, Role::Owner, Role::Viewer, &editor),
+        )
+        .await?;
+        let granted = |site: i64, role: Role| Event::Granted {
+            id: 1,
+            site,
+            role,
+            signature: n1.keys.sign_event("granted", 1, &(site, role)),
+        };
+
+        // Site 1 may edit, but not take away our privileges.
+        n2.handle(granted(2, Role::Viewer), &mut origin().await?).await;
+
+        assert_eq!(n2.roles.own(), Role::Owner);
+        assert!(!n2.peers.contains_key(&1));
+
+        // Nor may a site grant roles under the signature of another.
+        let mut forged = granted(2, Role::Viewer);
+
+        if let Event::Granted { ref mut id, .. } = forged {
+            *id = 3;
+        }
+
+        n2.handle(forged, &mut origin().await?).await;
+
+        assert_eq!(n2.roles.own(), Role::Owner);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_edit_inside_lock() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let (n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node(2, Keys::load(2, None, &trusted)?).await?;
+
+        n2.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "hello")
+            .await
+            .map_err(|e| e.message)?;
+
+        let file = n2.workspace.get_mut(DEFAULT_DOCUMENT).unwrap();
+        let lock = file
+            .document
+            .local_lock(&Range::new((0, 1), (0, 3)))
+            .unwrap();
+        let inside = vec![file.document.atoms()[2].clone()];
+        let delete = |seq: u64| Event::RemoteDelete {
+            id: 1,
+            document: DEFAULT_DOCUMENT.to_string(),
+            seq,
+            lines: inside.clone(),
+            signature: n1
+                .keys
+                .sign(Operation::Delete, 1, DEFAULT_DOCUMENT, seq, &inside),
+        };
+        let content = |node: &Node| {
+            node.workspace
+                .get(DEFAULT_DOCUMENT)
+                .unwrap()
+                .document
+                .content()
+        };
+
+        n2.handle(delete(1), &mut origin().await?).await;
+
+        assert_eq!(content(&n2), "hello");
+
+        // Once the lock is gone, so is the text.
+        n2.workspace
+            .get_mut(DEFAULT_DOCUMENT)
+            .unwrap()
+            .document
+            .unlock(&lock.id);
+        n2.handle(delete(2), &mut origin().await?).await;
+
+        assert_eq!(content(&n2), "helo");
+
+        Ok(())
+    }
+
+    /// Peers that join late are told about the locks as well as the text.
+    #[tokio::test]
+    async fn test_catch_up_sends_locks() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let (mut n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node(2, Keys::load(2, None, &trusted)?).await?;
+
+        n1.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "hello")
+            .await
+            .map_err(|e| e.message)?;
+        n1.workspace
+            .get_mut(DEFAULT_DOCUMENT)
+            .unwrap()
+            .document
+            .local_lock(&Range::new((0, 1), (0, 3)));
+
+        let snapshot = n1.catch_up(DEFAULT_DOCUMENT).unwrap();
+
+        n2.handle(snapshot, &mut origin().await?).await;
+
+        let file = n2.workspace.get(DEFAULT_DOCUMENT).unwrap();
+
+        assert_eq!(file.document.content(), "hello");
+        assert_eq!(file.document.locks().len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_comments() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let (mut n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node(2, Keys::load(2, None, &trusted)?).await?;
+
+        n1.insert(DEFAULT_DOCUMENT, &Range::new((0, 0), (0, 0)), "hello")
+            .await
+            .map_err(|e| e.message)?;
+
+        let file = n1.workspace.get_mut(DEFAULT_DOCUMENT).unwrap();
+        let start = file
+            .threads
+            .start(&file.document, &Range::new((0, 1), (0, 3)), "mark", "Why?");
+        let id = start.thread().clone();
+        file.threads.reply(&id, "mark", "Anyone?");
+
+        // A late joiner is sent the threads along with the text.
+        n2.handle(n1.catch_up(DEFAULT_DOCUMENT).unwrap(), &mut origin().await?).await;
+
+        let thread = |node: &Node| {
+            node.workspace
+                .get(DEFAULT_DOCUMENT)
+                .unwrap()
+                .threads
+                .get(&id)
+                .cloned()
+        };
+
+        assert_eq!(thread(&n2), thread(&n1));
+
+        // A comment has to be signed by the site that it's from, and can't be changed on the way.
+        let file = n1.workspace.get_mut(DEFAULT_DOCUMENT).unwrap();
+        let (signed, sent) = (
+            file.threads.reply(&id, "mark", "Hello?").unwrap(),
+            file.threads.reply(&id, "mark", "Goodbye.").unwrap(),
+        );
+        let mut forged = n1.comment(DEFAULT_DOCUMENT.to_string(), signed.clone());
+
+        if let Event::Comment { ref mut change, .. } = forged {
+            *change = sent;
+        }
+
+        n2.handle(forged, &mut origin().await?).await;
+
+        assert_eq!(thread(&n2).unwrap().comments.len(), 2);
+
+        n2.handle(
+            n1.comment(DEFAULT_DOCUMENT.to_string(), signed),
+            &mut origin().await?,
+        )
+        .await;
+
+        assert_eq!(thread(&n2).unwrap().comments[2].text, "Hello?");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tree() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(1, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 1,
+            key: keys.public_key(),
+        }];
+        let (mut n1, _e1) = node(1, keys).await?;
+        let (mut n2, _e2) = node(2, Keys::load(2, None, &trusted)?).await?;
+
+        let create = n1.workspace.tree.create("a.rs", Kind::File)?;
+        let rename = n1.workspace.tree.rename("a.rs", "b.rs")?;
+
+        n2.handle(n1.tree(create), &mut origin().await?).await;
+        n2.workspace.open("a.rs").document.seed("hello");
+
+        // A change to the files has to be signed by the site that it's from.
+        let mut forged = n1.tree(rename.clone());
+
+        if let Event::Tree { ref mut id, .. } = forged {
+            *id = 2;
+        }
+
+        n2.handle(forged, &mut origin().await?).await;
+
+        assert!(n2.workspace.tree.lookup("a.rs").is_some());
+
+        // The open document follows its file.
+        n2.handle(n1.tree(rename), &mut origin().await?).await;
+
+        assert!(n2.workspace.tree.lookup("b.rs").is_some());
+        assert!(!n2.workspace.is_open("a.rs"));
+        assert_eq!(
+            n2.workspace.get("b.rs").unwrap().document.content(),
+            "hello"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = Keys::load(2, None, &[])?;
+        let trusted = [TrustedKey {
+            site: 2,
+            key: keys.public_key(),
+        }];
+        let (mut n1, _e1) = node(1, Keys::load(1, None, &trusted)?).await?;
+        let (n2, _e2) = node(2, keys).await?;
+        let subscribed = |node: &Node| {
+            node.peers
+                .get(&2)
+                .is_some_and(|peer| peer.subscriptions.contains("a.rs"))
+        };
+
+        // Site 2 hasn't joined yet.
+        n1.handle(n2.subscribe("a.rs".to_string()), &mut origin().await?).await;
+
+        assert!(!subscribed(&n1));
+
+        let join = Event::Join {
+            id: 2,
+            role: Role::Editor,
+            signature: n2.keys.sign_event("join", 2, &Role::Editor),
+        };
+
+        n1.handle(join, &mut origin().await?).await;
+
+        // Nor can anyone subscribe in its name.
+        let mut forged = n2.subscribe("b.rs".to_string());
+
+        if let Event::Subscribe {
+            ref mut document, ..
+        } = forged
+        {
+            *document = "a.rs".to_string();
+        }
+
+        n1.handle(forged, &mut origin().await?).await;
+
+        assert!(!subscribed(&n1));
+
+        n1.handle(n2.subscribe("a.rs".to_string()), &mut origin().await?).await;
+
+        assert!(subscribed(&n1));
+
+        n1.handle(n2.unsubscribe("a.rs".to_string()), &mut origin().await?).await;
+
+        assert!(!subscribed(&n1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_once() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut n, _e) = node(1, Keys::generate(1)).await?;
+        let addr = "127.0.0.1:1".parse()?;
+        let event = Event::Rejected {
+            reason: "test".to_string(),
+        };
+        let without = Capabilities::supported().without(Capabilities::COMPRESSION);
+
+        // A connection that can't support what every peer must is refused, and tried again with its next message.
+        n.receive(addr, &Envelope::seal(&event, Capabilities::NONE)).await;
+
+        assert!(!n.negotiated.contains_key(&addr));
+
+        n.receive(addr, &Envelope::seal(&event, without)).await;
+        n.receive(addr, &Envelope::seal(&event, Capabilities::supported())).await;
+
+        // The capabilities are the ones negotiated from the first message that was let through.
+        assert_eq!(n.negotiated.get(&addr), Some(&without));
+
+        n.disconnect(addr);
+
+        assert!(!n.negotiated.contains_key(&addr));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_closed_documents() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = env::temp_dir().join(format!("liveshare-closed-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let editor = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let client = Client::connect("127.0.0.1", editor.local_addr()?.port()).await?;
+        let mut node = Node::init(
+            1,
+            config::Client::parse("localhost:0"),
+            client,
+            Keys::generate(1),
+            Roles::new(1, Role::Owner, Role::Editor, &[]),
+            Identity::new(1, None, None),
+            Capabilities::supported(),
+            Duration::from_millis(20),
+        )
+        .await;
+
+        let atoms = Document::new(2)
+            .local_insert(&Range::new((0, 0), (0, 0)), &['h', 'i'])
+            .unwrap();
+        let (mut log, _, _) = Log::open(&dir)?;
+
+        log.append(&Entry {
+            document: "a.rs".to_string(),
+            site: 2,
+            seq: 1,
+            time: 100,
+            operation: Operation::Insert,
+            atoms,
+        })?;
+        log.commit()?;
+        drop(log);
+
+        let (log, snapshot, entries) = Log::open(&dir)?;
+
+        node.recover(log, snapshot, entries, Duration::from_secs(60));
+        node.workspace.close("a.rs");
+        node.snapshot();
+
+        // The operations on the closed document are gone from the log, but the snapshot still holds its text.
+        let (snapshot, entries) = Log::read(&dir)?;
+        let image = snapshot
+            .unwrap()
+            .documents
+            .into_iter()
+            .find(|image| image.document == "a.rs")
+            .unwrap();
+
+        assert!(entries.is_empty());
+        assert_eq!(image.version.get(&2), Some(&1));
+        assert_eq!(image.atoms.len(), 2);
+
+        fs::remove_dir_all(&dir)?;
 
         Ok(())
     }
@@ -0,0 +1,529 @@
+use {
+    crate::diff,
+    serde::Serialize,
+    snafu::{ensure, OptionExt, Snafu},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Malformed hunk header on line {}: {}", line, header))]
+    MalformedHeader { line: usize, header: String },
+
+    #[snafu(display("Unexpected line {} in a hunk: {}", line, text))]
+    UnexpectedLine { line: usize, text: String },
+
+    #[snafu(display("The patch changes more than one file"))]
+    SeveralFiles,
+
+    #[snafu(display("The patch has no hunks"))]
+    NoHunks,
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The number of context lines that may be ignored by default when a hunk doesn't match, like GNU patch does.
+pub const DEFAULT_FUZZ: usize = 2;
+
+/// A span of lines that a patch replaces.
+/// Lines keep their "\n", unless they're the last line of a file that doesn't end with one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hunk {
+    /// The "@@ -1,2 +1,3 @@" line that the hunk starts with.
+    pub header: String,
+    /// Where the hunk starts in the original file, counting lines from 0.
+    start: usize,
+    /// The lines that the hunk replaces, including the context around them.
+    old: Vec<String>,
+    /// The lines that replace them, including the same context.
+    new: Vec<String>,
+    /// The number of context lines before the first change.
+    leading: usize,
+    /// The number of context lines after the last change.
+    trailing: usize,
+}
+
+impl Hunk {
+    /// The lines to replace and their replacement, without up to `fuzz` lines of context on either side.
+    /// Returns the number of lines dropped from the start as well.
+    fn trim(&self, fuzz: usize) -> (usize, &[String], &[String]) {
+        let (leading, trailing) = (fuzz.min(self.leading), fuzz.min(self.trailing));
+
+        (
+            leading,
+            &self.old[leading..self.old.len() - trailing],
+            &self.new[leading..self.new.len() - trailing],
+        )
+    }
+}
+
+/// A hunk that was applied.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Applied {
+    /// The index of the hunk in the patch.
+    pub hunk: usize,
+    /// The line (counting from 1) that the hunk was applied at, which may differ from its header if the text moved.
+    pub line: usize,
+    /// The number of context lines that had to be ignored on either side for the hunk to match.
+    pub fuzz: usize,
+}
+
+/// A hunk that couldn't be applied, e.g. since its lines weren't found, not even with fuzz.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Rejected {
+    /// The index of the hunk in the patch.
+    pub hunk: usize,
+    pub header: String,
+    pub reason: String,
+}
+
+impl Rejected {
+    /// The reason that hunks whose lines weren't found are rejected with.
+    pub const NOT_FOUND: &'static str = "Its lines weren't found.";
+}
+
+/// A single hunk that was applied to the text.
+#[derive(Debug)]
+pub struct Step {
+    pub applied: Applied,
+    /// The text with the hunk applied.
+    pub text: String,
+    /// How far the text has moved compared to the original, to be passed on to the next hunk.
+    pub offset: isize,
+}
+
+/// The result of applying a patch.
+#[derive(Debug)]
+pub struct Outcome {
+    /// The patched text, which only has the hunks that were applied.
+    pub text: String,
+    pub applied: Vec<Applied>,
+    pub rejected: Vec<Rejected>,
+}
+
+/// A unified diff of a single file, such as the ones made by `diff -u` or `git diff`.
+#[derive(Debug)]
+pub struct Patch {
+    /// The path of the file after the change, as given by the "+++" line (without git's "b/"), if there is one.
+    pub path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl Patch {
+    /// Parses a unified diff. Anything outside of the hunks, like "diff --git" and "index" lines, is ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut path = None;
+        let mut hunks: Vec<Hunk> = Vec::new();
+        // The number of old and new lines that the current hunk still has.
+        let mut remaining = (0, 0);
+        // Whether the current hunk has changed a line yet, so that the context before the first change is counted.
+        let mut changed = false;
+        let mut last = ' ';
+
+        for (i, line) in text.lines().enumerate() {
+            if line.starts_with('\\') {
+                // "\ No newline at end of file" is about the line before it.
+                if let Some(hunk) = hunks.last_mut() {
+                    if last != '+' {
+                        if let Some(line) = hunk.old.last_mut() {
+                            line.pop();
+                        }
+                    }
+                    if last != '-' {
+                        if let Some(line) = hunk.new.last_mut() {
+                            line.pop();
+                        }
+                    }
+                }
+            } else if remaining != (0, 0) {
+                let hunk = hunks.last_mut().unwrap();
+                let (kind, content) = match line.chars().next() {
+                    Some(kind @ ' ') | Some(kind @ '-') | Some(kind @ '+') => (kind, &line[1..]),
+                    // Some tools strip the space of empty context lines.
+                    None => (' ', ""),
+                    _ => {
+                        return UnexpectedLine {
+                            line: i + 1,
+                            text: line,
+                        }
+                        .fail()
+                    }
+                };
+                let content = format!("{}\n", content);
+
+                if kind != '+' {
+                    ensure!(
+                        remaining.0 > 0,
+                        UnexpectedLine {
+                            line: i + 1,
+                            text: line
+                        }
+                    );
+                    remaining.0 -= 1;
+                    hunk.old.push(content.clone());
+                }
+
+                if kind != '-' {
+                    ensure!(
+                        remaining.1 > 0,
+                        UnexpectedLine {
+                            line: i + 1,
+                            text: line
+                        }
+                    );
+                    remaining.1 -= 1;
+                    hunk.new.push(content);
+                }
+
+                if kind == ' ' {
+                    hunk.trailing += 1;
+                } else {
+                    if !changed {
+                        hunk.leading = hunk.trailing;
+                        changed = true;
+                    }
+                    hunk.trailing = 0;
+                }
+
+                last = kind;
+            } else if line.starts_with("@@") {
+                let (start, old, new) = header(line).context(MalformedHeader {
+                    line: i + 1,
+                    header: line,
+                })?;
+
+                remaining = (old, new);
+                changed = false;
+                hunks.push(Hunk {
+                    header: line.to_string(),
+                    start,
+                    old: Vec::new(),
+                    new: Vec::new(),
+                    leading: 0,
+                    trailing: 0,
+                });
+            } else if let Some(name) = line.strip_prefix("+++ ") {
+                ensure!(path.is_none(), SeveralFiles);
+
+                let name = name.split('\t').next().unwrap_or_default();
+                path = Some(name.strip_prefix("b/").unwrap_or(name).to_string());
+            }
+        }
+
+        ensure!(!hunks.is_empty(), NoHunks);
+
+        // A hunk without any changes is all context, which counts as leading.
+        for hunk in &mut hunks {
+            if hunk.old == hunk.new {
+                hunk.leading = hunk.old.len();
+                hunk.trailing = 0;
+            }
+        }
+
+        Ok(Self { path, hunks })
+    }
+
+    /// Applies the patch to `text`, skipping the hunks that don't match.
+    /// Each hunk is first looked for where its header says it is (moved by as much as the hunks before it moved the
+    /// text), and then further and further away from it. If it isn't found, it's looked for again without up to
+    /// `fuzz` lines of its context on either side.
+    pub fn apply(&self, text: &str, fuzz: usize) -> Outcome {
+        let mut text = text.to_string();
+        let mut applied = Vec::new();
+        let mut rejected = Vec::new();
+        // How far the text has moved compared to the original, due to the hunks that were applied.
+        let mut offset = 0;
+
+        for i in 0..self.hunks.len() {
+            match self.apply_hunk(i, &text, offset, fuzz) {
+                Some(step) => {
+                    text = step.text;
+                    offset = step.offset;
+                    applied.push(step.applied);
+                }
+                None => rejected.push(self.reject(i, Rejected::NOT_FOUND)),
+            }
+        }
+
+        Outcome {
+            text,
+            applied,
+            rejected,
+        }
+    }
+
+    /// Applies only the `i`-th hunk to `text`, where the hunks before it moved the text by `offset`.
+    /// This lets the caller decide whether to keep each hunk, e.g. since it can't be applied to a document after all.
+    /// Returns `None` if the hunk's lines weren't found.
+    pub fn apply_hunk(&self, i: usize, text: &str, offset: isize, fuzz: usize) -> Option<Step> {
+        let hunk = &self.hunks[i];
+        let mut lines: Vec<&str> = diff::lines(text);
+        let (fuzz, expected, at, old, new) = (0..=fuzz).find_map(|fuzz| {
+            let (leading, old, new) = hunk.trim(fuzz);
+            let expected = hunk.start + leading;
+            let at = locate(&lines, old, (expected as isize + offset).max(0) as usize)?;
+
+            Some((fuzz, expected, at, old, new))
+        })?;
+
+        lines.splice(at..at + old.len(), new.iter().map(String::as_str));
+
+        Some(Step {
+            applied: Applied {
+                hunk: i,
+                line: at + 1,
+                fuzz,
+            },
+            text: lines.concat(),
+            offset: at as isize - expected as isize + new.len() as isize - old.len() as isize,
+        })
+    }
+
+    /// Rejects the `i`-th hunk for `reason`.
+    pub fn reject(&self, i: usize, reason: &str) -> Rejected {
+        Rejected {
+            hunk: i,
+            header: self.hunks[i].header.clone(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// The number of unchanged lines that `unified` shows around each change, like `diff -u` does.
+pub const CONTEXT: usize = 3;
+
+/// Writes the unified diff that turns `old` into `new`, which are both the content of `path`.
+/// Returns an empty string if they're the same.
+pub fn unified(path: &str, old: &str, new: &str) -> String {
+    let (old, new) = (diff::lines(old), diff::lines(new));
+    let mut groups: Vec<Vec<diff::Hunk>> = Vec::new();
+    let mut res = String::new();
+
+    // Changes that are close enough for their context to overlap are written as a single hunk.
+    for hunk in diff::diff(&old, &new) {
+        match groups.last_mut() {
+            Some(group) if hunk.old.start - group[group.len() - 1].old.end <= 2 * CONTEXT => {
+                group.push(hunk)
+            }
+            _ => groups.push(vec![hunk]),
+        }
+    }
+
+    if groups.is_empty() {
+        return res;
+    }
+
+    res.push_str(&format!("--- a/{}\n+++ b/{}\n", path, path));
+
+    for group in groups {
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        // The lines before the first change and after the last one are the same in both texts.
+        let before = first.old.start.min(CONTEXT);
+        let after = (old.len() - last.old.end).min(CONTEXT);
+        let (old_start, new_start) = (first.old.start - before, first.new.start - before);
+        let span = |start: usize, len: usize| match len {
+            0 => format!("{},0", start),
+            _ => format!("{},{}", start + 1, len),
+        };
+
+        res.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            span(old_start, last.old.end + after - old_start),
+            span(new_start, last.new.end + after - new_start)
+        ));
+
+        let mut at = old_start;
+
+        for hunk in &group {
+            write(&mut res, ' ', &old[at..hunk.old.start]);
+            write(&mut res, '-', &old[hunk.old.clone()]);
+            write(&mut res, '+', &new[hunk.new.clone()]);
+            at = hunk.old.end;
+        }
+
+        write(&mut res, ' ', &old[at..last.old.end + after]);
+    }
+
+    res
+}
+
+/// Writes each of `lines` after `kind`, marking a last line without "\n" the way diff does.
+fn write(res: &mut String, kind: char, lines: &[&str]) {
+    for line in lines {
+        res.push(kind);
+        res.push_str(line);
+
+        if !line.ends_with('\n') {
+            res.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// Parses "@@ -start,count +start,count @@", returning where the hunk starts (counting from 0) and its number of old
+/// and new lines. A count of 1 may be left out.
+fn header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut ranges = line.trim_start_matches('@').split_whitespace();
+    let span = |range: &str| -> Option<(usize, usize)> {
+        let mut parts = range.splitn(2, ',');
+        let start = parts.next()?.parse().ok()?;
+        let count = parts.next().map_or(Some(1), |count| count.parse().ok())?;
+
+        Some((start, count))
+    };
+    let (start, old) = span(ranges.next()?.strip_prefix('-')?)?;
+    let (_, new) = span(ranges.next()?.strip_prefix('+')?)?;
+
+    // A hunk without old lines starts after the line in its header, rather than at it.
+    let start = if old == 0 {
+        start
+    } else {
+        start.checked_sub(1)?
+    };
+
+    Some((start, old, new))
+}
+
+/// Finds `old` in `lines`, starting at `expected` and moving further away from it in both directions.
+fn locate(lines: &[&str], old: &[String], expected: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(old.len())?;
+    let expected = expected.min(last);
+    let matches = |at: usize| lines[at..at + old.len()] == *old;
+
+    (0..=last).find_map(|distance| {
+        let after = expected + distance;
+        let before = expected.checked_sub(distance);
+
+        match (
+            after <= last && matches(after),
+            before.filter(|at| matches(*at)),
+        ) {
+            (true, _) => Some(after),
+            (false, before) => before,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unified, Applied, Patch, Rejected};
+
+    const PATCH: &str = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,4 +1,4 @@
+ fn a() {
+-    1
++    2
+ }
+
+@@ -7,3 +7,4 @@ fn b() {
+ fn c() {
+     3
++    4
+ }
+";
+
+    const TEXT: &str = "fn a() {\n    1\n}\n\nfn b() {\n}\nfn c() {\n    3\n}\n";
+
+    #[test]
+    fn test_apply() {
+        let patch = Patch::parse(PATCH).unwrap();
+        let outcome = patch.apply(TEXT, 0);
+
+        assert_eq!(patch.path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(patch.hunks.len(), 2);
+        assert!(outcome.rejected.is_empty());
+        assert_eq!(
+            outcome.text,
+            "fn a() {\n    2\n}\n\nfn b() {\n}\nfn c() {\n    3\n    4\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_hunk() {
+        let patch = Patch::parse(PATCH).unwrap();
+        // The first hunk isn't kept, so the second one is applied to the original text.
+        let step = patch.apply_hunk(1, TEXT, 0, 0).unwrap();
+
+        assert_eq!(step.applied.line, 7);
+        assert_eq!(step.offset, 1);
+        assert!(step.text.starts_with("fn a() {\n    1\n"));
+        assert!(step.text.ends_with("    3\n    4\n}\n"));
+        assert!(patch.apply_hunk(0, &step.text, 0, 0).is_some());
+        assert!(patch.apply_hunk(1, "", 0, 0).is_none());
+    }
+
+    #[test]
+    fn test_apply_moved_text() {
+        let patch = Patch::parse(PATCH).unwrap();
+        let text = format!("// Header\n\n{}", TEXT);
+        let outcome = patch.apply(&text, 0);
+
+        assert_eq!(
+            outcome.applied,
+            vec![
+                Applied {
+                    hunk: 0,
+                    line: 3,
+                    fuzz: 0
+                },
+                Applied {
+                    hunk: 1,
+                    line: 9,
+                    fuzz: 0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_with_fuzz() {
+        let patch = Patch::parse(PATCH).unwrap();
+        // The context after the second hunk changed, so it only matches once that context is ignored.
+        let text = TEXT.replace("    3\n}\n", "    3\n};\n");
+
+        let outcome = patch.apply(&text, 0);
+
+        assert_eq!(
+            outcome.rejected,
+            vec![Rejected {
+                hunk: 1,
+                header: "@@ -7,3 +7,4 @@ fn b() {".to_string(),
+                reason: Rejected::NOT_FOUND.to_string(),
+            }]
+        );
+
+        let outcome = patch.apply(&text, 1);
+
+        assert!(outcome.rejected.is_empty());
+        assert_eq!(outcome.applied[1].fuzz, 1);
+        assert!(outcome.text.ends_with("    3\n    4\n};\n"));
+    }
+
+    #[test]
+    fn test_no_newline_at_end_of_file() {
+        let patch = Patch::parse(
+            "--- a\n+++ b\n@@ -1 +1,2 @@\n-a\n\\ No newline at end of file\n+a\n+b\n\\ No newline at end of file\n",
+        )
+        .unwrap();
+
+        assert_eq!(patch.apply("a", 0).text, "a\nb");
+        assert!(Patch::parse("--- a\n+++ b\n").is_err());
+        assert!(Patch::parse("@@ -x +1 @@\n").is_err());
+    }
+
+    #[test]
+    fn test_unified() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12";
+        let new = "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let diff = unified("a.txt", old, new);
+
+        assert!(diff.starts_with(
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,4 @@\n+0\n 1\n 2\n 3\n@@ -9,4 +10,4 @@\n"
+        ));
+        assert!(diff.ends_with("-12\n\\ No newline at end of file\n+12\n"));
+        assert_eq!(Patch::parse(&diff).unwrap().apply(old, 0).text, new);
+        assert_eq!(unified("a.txt", old, old), "");
+    }
+}
@@ -20,13 +20,7 @@ impl Ord for Position {
             }
         }
 
-        if len1 < len2 {
-            return Ordering::Less;
-        } else if len1 > len2 {
-            return Ordering::Greater;
-        } else {
-            return Ordering::Equal;
-        }
+        len1.cmp(&len2)
     }
 }
 
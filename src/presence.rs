@@ -0,0 +1,180 @@
+use {
+    crate::{document::Document, frontend::Notification, position::Position, range::Range},
+    serde::{Deserialize, Serialize},
+    std::{collections::HashMap, env},
+};
+
+/// Colours handed out to participants that didn't pick one.
+const PALETTE: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+];
+
+/// How a participant is shown to everyone else.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub colour: String,
+}
+
+impl Identity {
+    /// Falls back to the name of the current user, and to a colour picked by `site`.
+    pub fn new(site: i64, name: Option<String>, colour: Option<String>) -> Self {
+        let name = name
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| "anonymous".to_string());
+        let colour = colour.unwrap_or_else(|| {
+            let i = site.rem_euclid(PALETTE.len() as i64) as usize;
+            PALETTE[i].to_string()
+        });
+
+        Self { name, colour }
+    }
+}
+
+/// Who a participant is and where their cursor is.
+/// The cursor is anchored to position identifiers rather than rows and columns, so it stays next to the same
+/// characters while others edit the document.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Presence {
+    pub name: String,
+    pub colour: String,
+    /// Where the selection started. This is the same as `head` if nothing is selected.
+    pub anchor: Position,
+    /// Where the cursor is.
+    pub head: Position,
+}
+
+impl Presence {
+    /// Anchors the selection from `range.start` until `range.end` to the atoms of `document`.
+    pub fn new(identity: &Identity, document: &Document, range: &Range) -> Self {
+        Self {
+            name: identity.name.clone(),
+            colour: identity.colour.clone(),
+            anchor: document.position(&range.start),
+            head: document.position(&range.end),
+        }
+    }
+
+    /// The current range of the selection, from its anchor until its head.
+    pub fn range(&self, document: &Document) -> Range {
+        Range {
+            start: document.point(&self.anchor),
+            end: document.point(&self.head),
+        }
+    }
+}
+
+/// The presence of every other participant, along with the range that the editor was last told about.
+#[derive(Debug, Default)]
+pub struct Presences {
+    others: HashMap<i64, (Presence, Option<Range>)>,
+}
+
+impl Presences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the presence of `site`, which will be shown to the editor again.
+    pub fn update(&mut self, site: i64, presence: Presence) {
+        self.others.insert(site, (presence, None));
+    }
+
+    /// The name that `site` is shown with, if its presence is known.
+    pub fn name(&self, site: i64) -> Option<&str> {
+        self.others
+            .get(&site)
+            .map(|(presence, _)| presence.name.as_str())
+    }
+
+    /// Resolves every presence in `document` (identified by `id`).
+    /// Returns a notification for each one that isn't where the editor last showed it.
+    pub fn moved(&mut self, id: &str, document: &Document) -> Vec<Notification> {
+        let mut res = Vec::new();
+
+        for (site, (presence, shown)) in self.others.iter_mut() {
+            let range = presence.range(document);
+
+            if shown.as_ref() == Some(&range) {
+                continue;
+            }
+
+            *shown = Some(range.clone());
+
+            res.push(Notification::Presence {
+                document: id.to_string(),
+                site: *site,
+                name: presence.name.clone(),
+                colour: presence.colour.clone(),
+                range,
+            });
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identity, Presence, Presences};
+    use crate::{document::Document, frontend::Notification, range::Range};
+
+    #[test]
+    fn test_identity() {
+        let identity = Identity::new(-1, Some("mark".to_string()), None);
+        let other = Identity::new(2, None, Some("#000000".to_string()));
+
+        assert_eq!(identity.name, "mark");
+        assert!(identity.colour.starts_with('#'));
+        assert_eq!(other.colour, "#000000");
+    }
+
+    #[test]
+    fn test_moved() {
+        let doc = Document::new(0);
+        let identity = Identity::new(1, Some("mark".to_string()), None);
+        let range = Range::new((0, 0), (0, 0));
+        let mut presences = Presences::new();
+
+        presences.update(1, Presence::new(&identity, &doc, &range));
+
+        assert_eq!(presences.moved("a.rs", &doc).len(), 1);
+        assert!(presences.moved("a.rs", &doc).is_empty());
+
+        presences.update(1, Presence::new(&identity, &doc, &range));
+
+        assert!(matches!(
+            presences.moved("a.rs", &doc).as_slice(),
+            [Notification::Presence { site: 1, ref name, .. }] if name == "mark"
+        ));
+    }
+
+    #[test]
+    fn test_moved_by_remote_insert() {
+        let mut doc = Document::new(0);
+        let mut other = Document::new(2);
+        let identity = Identity::new(1, Some("mark".to_string()), None);
+        let mut presences = Presences::new();
+
+        let atoms = doc
+            .local_insert(&Range::new((0, 0), (0, 0)), &['a', 'b', 'c'])
+            .unwrap();
+        other.remote_insert(&atoms).unwrap();
+        presences.update(
+            1,
+            Presence::new(&identity, &doc, &Range::new((0, 1), (0, 2))),
+        );
+        presences.moved("a.rs", &doc);
+
+        // Typing before the selection pushes it along with the characters it's anchored to.
+        let atoms = other
+            .local_insert(&Range::new((0, 0), (0, 0)), &['x', 'y'])
+            .unwrap();
+        doc.remote_insert(&atoms).unwrap();
+
+        assert!(matches!(
+            presences.moved("a.rs", &doc).as_slice(),
+            [Notification::Presence { ref range, .. }] if *range == Range::new((0, 3), (0, 4))
+        ));
+    }
+}
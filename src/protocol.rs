@@ -0,0 +1,344 @@
+use {
+    crate::{
+        codec::{self, COMPRESSION_THRESHOLD},
+        node::Event,
+    },
+    bincode::{deserialize, serialize},
+    serde::{Deserialize, Serialize},
+    snafu::{ensure, ResultExt, Snafu},
+    std::{
+        io,
+        ops::{BitAnd, BitOr},
+    },
+    tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// The protocol version spoken by this node, which is the only one that it talks to.
+/// There's no compatibility between versions, since each of the changes below changed what events carry or how they're
+/// encoded, so every node in a session has to run the same version.
+/// - 2: Atoms within an operation are delta encoded.
+/// - 3: Atoms within an operation use the compact varint encoding.
+/// - 4: Events are tagged with the document that they belong to.
+/// - 5: Events that aren't edits are signed as well, such as joins, role changes, locks, cursors and snapshots.
+/// - 6: Edits carry the number that their site gave them, and snapshots carry the version that they're at.
+/// - 7: Envelopes are prefixed by their length, so that a connection can carry any number of them.
+/// - 8: Comments, changes to the files and subscriptions are signed, and snapshots carry the threads of comments on the
+///   document.
+pub const VERSION: u16 = 8;
+
+/// The largest envelope that a peer may send, and the most that a compressed payload may decompress to, so that a peer
+/// can't make us allocate without bounds.
+pub const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Malformed envelope: {}", source))]
+    MalformedEnvelope { source: bincode::Error },
+
+    #[snafu(display("Protocol version {} is not supported (expected {})", version, VERSION))]
+    UnsupportedVersion { version: u16 },
+
+    #[snafu(display("Unknown event for protocol version {}: {}", version, source))]
+    UnknownEvent {
+        version: u16,
+        source: bincode::Error,
+    },
+
+    #[snafu(display("Unable to decompress payload: {}", source))]
+    Decompress { source: io::Error },
+}
+
+/// A set of optional protocol features.
+/// Peers only use the features that both of them support, which are negotiated from the first message that a
+/// connection carries.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Operations are signed by the site that created them.
+    pub const SIGNATURES: Capabilities = Capabilities(1);
+    /// Participants have roles which restrict what they may do.
+    pub const ROLES: Capabilities = Capabilities(1 << 1);
+    /// Spans of the document can be locked.
+    pub const LOCKS: Capabilities = Capabilities(1 << 2);
+    /// Several events can be sent as a single message.
+    pub const BATCHING: Capabilities = Capabilities(1 << 3);
+    /// Large messages are compressed.
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 4);
+    /// Participants share where their cursors are.
+    pub const PRESENCE: Capabilities = Capabilities(1 << 5);
+    /// Spans of the document can be discussed in threads of comments.
+    pub const COMMENTS: Capabilities = Capabilities(1 << 6);
+    /// Files and directories can be created, renamed and deleted.
+    pub const FILES: Capabilities = Capabilities(1 << 7);
+
+    /// Every capability that this node supports.
+    pub fn supported() -> Self {
+        Self::SIGNATURES
+            | Self::ROLES
+            | Self::LOCKS
+            | Self::BATCHING
+            | Self::COMPRESSION
+            | Self::PRESENCE
+            | Self::COMMENTS
+            | Self::FILES
+    }
+
+    /// The capabilities that every peer must support, since the documents would end up different on each site if
+    /// some peers applied edits that others refuse, or skipped events that change what may be edited.
+    pub fn required() -> Self {
+        Self::SIGNATURES | Self::ROLES | Self::LOCKS
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self & other == other
+    }
+
+    pub fn without(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+/// Wraps every event that is sent between peers.
+/// The layout of the envelope itself never changes, so a peer can always read the version of a message
+/// before trying to make sense of its payload.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Envelope {
+    pub version: u16,
+    pub capabilities: Capabilities,
+    payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wraps `event`, compressing it if it's large and compression is one of the `capabilities`.
+    pub fn new(event: &Event, capabilities: Capabilities) -> Self {
+        let mut payload = serialize(event).expect("Unable to serialize event.");
+
+        if capabilities.contains(Capabilities::COMPRESSION) && payload.len() > COMPRESSION_THRESHOLD
+        {
+            let event = Event::Compressed {
+                payload: codec::compress(&payload),
+            };
+            payload = serialize(&event).expect("Unable to serialize event.");
+        }
+
+        Self {
+            version: VERSION,
+            capabilities,
+            payload,
+        }
+    }
+
+    /// Serializes `event` into an envelope that's ready to be sent.
+    pub fn seal(event: &Event, capabilities: Capabilities) -> Vec<u8> {
+        serialize(&Self::new(event, capabilities)).expect("Unable to serialize envelope.")
+    }
+
+    /// Reads an envelope, rejecting it if its version isn't supported.
+    pub fn open(buf: &[u8]) -> Result<Self, Error> {
+        let envelope: Envelope = deserialize(buf).context(MalformedEnvelope)?;
+        let version = envelope.version;
+
+        ensure!(version == VERSION, UnsupportedVersion { version });
+
+        Ok(envelope)
+    }
+
+    /// Reads the events carried by the envelope, decompressing and unpacking batches as needed.
+    pub fn events(&self) -> Result<Vec<Event>, Error> {
+        let event = match self.decode(&self.payload)? {
+            Event::Compressed { payload } => {
+                let payload = codec::decompress(&payload).context(Decompress)?;
+                self.decode(&payload)?
+            }
+            event => event,
+        };
+
+        match event {
+            Event::Batch { events } => Ok(events),
+            event => Ok(vec![event]),
+        }
+    }
+
+    /// The capabilities that both `ours` and the sender of the envelope support.
+    pub fn negotiate(&self, ours: Capabilities) -> Capabilities {
+        self.capabilities & ours
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Event, Error> {
+        deserialize(payload).context(UnknownEvent {
+            version: self.version,
+        })
+    }
+}
+
+/// Writes a sealed envelope, prefixed by its length so that the peer knows where it ends.
+pub async fn send<W>(writer: &mut W, buf: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(4 + buf.len());
+
+    frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+    frame.extend_from_slice(buf);
+    writer.write_all(&frame).await
+}
+
+/// Reads the next sealed envelope, or `None` once the peer has disconnected.
+/// Envelopes larger than `MAX_MESSAGE` are refused before anything is allocated for them.
+pub async fn recv<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0; 4];
+
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+
+    if len > MAX_MESSAGE {
+        let reason = format!(
+            "Envelope of {} bytes is larger than {} bytes.",
+            len, MAX_MESSAGE
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    }
+
+    let mut buf = vec![0; len];
+
+    reader.read_exact(&mut buf).await?;
+
+    Ok(Some(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recv, send, Capabilities, Envelope, Error, MAX_MESSAGE, VERSION};
+    use crate::node::Event;
+    use bincode::serialize;
+    use tokio::io::{self, AsyncWriteExt};
+
+    #[test]
+    fn test_seal_and_open() {
+        let event = Event::Rejected {
+            reason: "test".to_string(),
+        };
+        let buf = Envelope::seal(&event, Capabilities::supported());
+        let envelope = Envelope::open(&buf).unwrap();
+        let events = envelope.events().unwrap();
+
+        assert_eq!(envelope.version, VERSION);
+        assert!(matches!(
+            events.as_slice(),
+            [Event::Rejected { ref reason }] if reason == "test"
+        ));
+    }
+
+    /// A connection carries any number of envelopes, one after the other.
+    #[tokio::test]
+    async fn test_frames() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut reader, mut writer) = io::duplex(1024);
+
+        send(&mut writer, b"first").await?;
+        send(&mut writer, b"second").await?;
+        drop(writer);
+
+        assert_eq!(recv(&mut reader).await?, Some(b"first".to_vec()));
+        assert_eq!(recv(&mut reader).await?, Some(b"second".to_vec()));
+        assert_eq!(recv(&mut reader).await?, None);
+
+        let (mut reader, mut writer) = io::duplex(1024);
+
+        writer
+            .write_all(&(MAX_MESSAGE as u32 + 1).to_be_bytes())
+            .await?;
+
+        assert!(recv(&mut reader).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_batch() {
+        let events = (0..100)
+            .map(|_| Event::Rejected {
+                reason: "test".to_string(),
+            })
+            .collect();
+        let buf = Envelope::seal(&Event::Batch { events }, Capabilities::supported());
+        let envelope = Envelope::open(&buf).unwrap();
+
+        assert!(matches!(
+            envelope.decode(&envelope.payload),
+            Ok(Event::Compressed { .. })
+        ));
+        assert_eq!(envelope.events().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_reject_unsupported_version() {
+        let mut envelope = Envelope::new(
+            &Event::Rejected {
+                reason: "test".to_string(),
+            },
+            Capabilities::NONE,
+        );
+        envelope.version = VERSION + 1;
+
+        let buf = serialize(&envelope).unwrap();
+
+        assert!(matches!(
+            Envelope::open(&buf),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+
+        // Nor are older versions understood.
+        envelope.version = VERSION - 1;
+
+        let buf = serialize(&envelope).unwrap();
+
+        assert!(matches!(
+            Envelope::open(&buf),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let envelope = Envelope::new(
+            &Event::Rejected {
+                reason: "test".to_string(),
+            },
+            Capabilities::LOCKS | Capabilities(1 << 31),
+        );
+
+        let ours = Capabilities::supported().without(Capabilities::COMPRESSION);
+
+        assert_eq!(envelope.negotiate(ours), Capabilities::LOCKS);
+        assert!(ours.contains(envelope.negotiate(ours)));
+        assert!(!ours.contains(Capabilities::COMPRESSION));
+        assert!(ours.contains(Capabilities::required()));
+        assert!(!envelope.negotiate(ours).contains(Capabilities::required()));
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::ops::Add;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Point {
     pub row: usize,
     pub column: usize,
@@ -35,7 +35,7 @@ impl Point {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Range {
     pub start: Point,
     pub end: Point,
@@ -0,0 +1,153 @@
+use {
+    crate::config::Assignment,
+    serde::{Deserialize, Serialize},
+    std::{
+        cmp::min,
+        collections::{HashMap, HashSet},
+    },
+};
+
+/// The permissions a participant has within a session.
+/// Roles are ordered by privilege, so `Viewer < Editor < Owner`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    /// Can only watch the document change.
+    Viewer,
+    /// Can edit the document.
+    #[default]
+    Editor,
+    /// Can edit the document and change the role of any other participant.
+    Owner,
+}
+
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+
+    pub fn can_manage(self) -> bool {
+        self == Role::Owner
+    }
+}
+
+/// Keeps track of the role of every site in the session, including our own.
+#[derive(Debug)]
+pub struct Roles {
+    site: i64,
+    default: Role,
+    assigned: HashMap<i64, Role>,
+    /// The sites that were let into the session, which are the only ones that may view its documents.
+    admitted: HashSet<i64>,
+}
+
+impl Roles {
+    /// Creates the role table for `site`, which starts out with the role `own`.
+    /// Sites without an assignment are given `default` when they join.
+    pub fn new(site: i64, own: Role, default: Role, assignments: &[Assignment]) -> Self {
+        let mut assigned: HashMap<_, _> = assignments.iter().map(|a| (a.site, a.role)).collect();
+        assigned.insert(site, own);
+
+        Self {
+            site,
+            default,
+            assigned,
+            admitted: HashSet::from([site]),
+        }
+    }
+
+    /// Our own role.
+    pub fn own(&self) -> Role {
+        self.of(self.site)
+    }
+
+    /// The role of `site`.
+    pub fn of(&self, site: i64) -> Role {
+        self.assigned.get(&site).copied().unwrap_or(self.default)
+    }
+
+    /// Decides which role a joining site receives during the handshake.
+    /// A site never receives more than it asked for, nor more than it has been assigned.
+    pub fn grant(&mut self, site: i64, requested: Role) -> Role {
+        let role = min(requested, self.of(site));
+        self.assigned.insert(site, role);
+        self.admitted.insert(site);
+        role
+    }
+
+    /// Lets `site` into the session with the role that it already has, e.g. the owner that granted us our role.
+    pub fn admit(&mut self, site: i64) {
+        self.admitted.insert(site);
+    }
+
+    /// Whether `site` may view the documents of the session, which it may once it joined or let us join.
+    /// Being given a role, e.g. in the config file, isn't enough.
+    pub fn can_view(&self, site: i64) -> bool {
+        self.admitted.contains(&site)
+    }
+
+    /// Takes on the role that we were granted by a peer.
+    pub fn accept(&mut self, role: Role) {
+        self.assigned.insert(self.site, role);
+    }
+
+    /// Changes the role of `site` on behalf of `issuer`.
+    /// Only an owner may change roles, otherwise `false` is returned and nothing changes.
+    pub fn set(&mut self, issuer: i64, site: i64, role: Role) -> bool {
+        if !self.of(issuer).can_manage() {
+            return false;
+        }
+
+        self.assigned.insert(site, role);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, Roles};
+    use crate::config::Assignment;
+
+    #[test]
+    fn test_grant_never_exceeds_request_or_assignment() {
+        let assignments = vec![Assignment {
+            site: 2,
+            role: Role::Viewer,
+        }];
+        let mut roles = Roles::new(1, Role::Owner, Role::Editor, &assignments);
+
+        assert_eq!(roles.grant(2, Role::Owner), Role::Viewer);
+        assert_eq!(roles.grant(3, Role::Owner), Role::Editor);
+        assert_eq!(roles.grant(4, Role::Viewer), Role::Viewer);
+    }
+
+    #[test]
+    fn test_only_owner_can_set_roles() {
+        let mut roles = Roles::new(1, Role::Owner, Role::Editor, &[]);
+
+        roles.grant(2, Role::Editor);
+
+        assert!(!roles.set(2, 1, Role::Viewer));
+        assert!(roles.set(1, 2, Role::Viewer));
+        assert_eq!(roles.of(2), Role::Viewer);
+        assert!(!roles.of(2).can_edit());
+    }
+
+    #[test]
+    fn test_only_admitted_sites_can_view() {
+        let assignments = vec![Assignment {
+            site: 2,
+            role: Role::Owner,
+        }];
+        let mut roles = Roles::new(1, Role::Editor, Role::Viewer, &assignments);
+
+        assert!(roles.can_view(1));
+        assert!(!roles.can_view(2));
+        assert!(!roles.can_view(3));
+
+        roles.admit(2);
+        roles.grant(3, Role::Viewer);
+
+        assert!(roles.can_view(2));
+        assert!(roles.can_view(3));
+    }
+}
@@ -0,0 +1,307 @@
+use {
+    snafu::{OptionExt, ResultExt, Snafu},
+    std::{
+        fs, io,
+        path::{Path, PathBuf},
+    },
+    tracing::info,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to read {}: {}", path.display(), source))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to write {}: {}", path.display(), source))]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("{} isn't UTF-8 or UTF-16 text", path.display()))]
+    UnknownEncoding { path: PathBuf },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// How the text of a file is encoded. Files are written back with the encoding they were read with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 that starts with a byte order mark, as some Windows editors write it.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+/// Which line endings a file uses. The document always uses "\n", so they're converted when reading and writing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+/// Everything about the way a file is stored that the document doesn't keep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+}
+
+impl Format {
+    /// Decodes the content of a file, detecting its format.
+    /// The encoding is told by the byte order mark, and is UTF-8 if there isn't one.
+    /// The line ending is the one that most lines use, and is converted to "\n".
+    pub fn decode(bytes: &[u8]) -> Option<(String, Self)> {
+        let (text, encoding) = match bytes {
+            [0xEF, 0xBB, 0xBF, rest @ ..] => {
+                (String::from_utf8(rest.to_vec()).ok()?, Encoding::Utf8Bom)
+            }
+            [0xFF, 0xFE, rest @ ..] => (utf16(rest, u16::from_le_bytes)?, Encoding::Utf16Le),
+            [0xFE, 0xFF, rest @ ..] => (utf16(rest, u16::from_be_bytes)?, Encoding::Utf16Be),
+            _ => (String::from_utf8(bytes.to_vec()).ok()?, Encoding::Utf8),
+        };
+
+        let crlf = text.matches("\r\n").count();
+        let line_ending = if crlf > 0 && crlf * 2 >= text.matches('\n').count() {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Lf
+        };
+        let format = Self {
+            encoding,
+            line_ending,
+        };
+
+        // A carriage return on its own line is left alone when most lines end in "\n", so that it's written back.
+        match line_ending {
+            LineEnding::Lf => Some((text, format)),
+            LineEnding::CrLf => Some((text.replace("\r\n", "\n"), format)),
+        }
+    }
+
+    /// Encodes the content of the document the way the file was stored.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let text = match self.line_ending {
+            LineEnding::Lf => text.to_string(),
+            LineEnding::CrLf => text.replace('\n', "\r\n"),
+        };
+
+        match self.encoding {
+            Encoding::Utf8 => text.into_bytes(),
+            Encoding::Utf8Bom => [&[0xEF, 0xBB, 0xBF][..], text.as_bytes()].concat(),
+            Encoding::Utf16Le => [0xFF, 0xFE]
+                .iter()
+                .copied()
+                .chain(
+                    text.encode_utf16()
+                        .flat_map(|unit| unit.to_le_bytes().to_vec()),
+                )
+                .collect(),
+            Encoding::Utf16Be => [0xFE, 0xFF]
+                .iter()
+                .copied()
+                .chain(
+                    text.encode_utf16()
+                        .flat_map(|unit| unit.to_be_bytes().to_vec()),
+                )
+                .collect(),
+        }
+    }
+}
+
+/// Decodes UTF-16 text, whose byte order is given by `unit`.
+fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| unit([pair[0], pair[1]]))
+        .collect();
+
+    String::from_utf16(&units).ok()
+}
+
+/// A file on disk that is shared as the default document.
+#[derive(Debug)]
+pub struct Shared {
+    path: PathBuf,
+    format: Format,
+    /// The content as it was last read or written, so that unchanged content isn't written again, and so that
+    /// changes made by something else can be told apart.
+    saved: String,
+}
+
+impl Shared {
+    /// Reads the file at `path`, returning it along with its content.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, String)> {
+        let path = path.as_ref().to_path_buf();
+        let bytes = fs::read(&path).context(Read { path: &path })?;
+        let (text, format) = Format::decode(&bytes).context(UnknownEncoding { path: &path })?;
+
+        info!("Sharing {} as {:?}.", path.display(), format);
+
+        let shared = Self {
+            path,
+            format,
+            saved: text.clone(),
+        };
+
+        Ok((shared, text))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `text` to the file, unless it's what the file already holds.
+    /// The file isn't written either if something else changed it since it was last read or written, since that
+    /// change would be lost. It has to be picked up with `poll` first.
+    /// The text is written to a temporary file next to it first, which then replaces it, so that the file is never
+    /// left half written. Returns whether the file was written.
+    pub fn save(&mut self, text: &str) -> Result<bool> {
+        if text == self.saved {
+            return Ok(false);
+        }
+
+        if let Some((current, _)) = self.read()? {
+            if current != self.saved {
+                info!(
+                    "{} changed on disk, so it isn't saved yet.",
+                    self.path.display()
+                );
+                return Ok(false);
+            }
+        }
+
+        let name = self
+            .path
+            .file_name()
+            .map_or_else(Default::default, |name| name.to_string_lossy());
+        let temp = self.path.with_file_name(format!(".{}.liveshare", name));
+
+        fs::write(&temp, self.format.encode(text)).context(Write { path: &temp })?;
+
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            fs::set_permissions(&temp, metadata.permissions()).context(Write { path: &temp })?;
+        }
+
+        fs::rename(&temp, &self.path).context(Write { path: &self.path })?;
+        self.saved = text.to_string();
+
+        Ok(true)
+    }
+
+    /// Reads the file again to find out whether something other than us changed it since it was last read or written,
+    /// e.g. a formatter or `git checkout`. Returns the new content, or `None` if the content didn't change.
+    /// The content is compared rather than the modification time, which misses writes made within its resolution.
+    pub fn poll(&mut self) -> Result<Option<String>> {
+        let (text, format) = match self.read()? {
+            Some((text, _)) if text == self.saved => return Ok(None),
+            Some(read) => read,
+            None => return Ok(None),
+        };
+
+        self.format = format;
+        self.saved = text.clone();
+
+        Ok(Some(text))
+    }
+
+    /// Reads and decodes the file, or returns `None` if it was deleted.
+    fn read(&self) -> Result<Option<(String, Format)>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(Read { path: &self.path }),
+        };
+
+        Format::decode(&bytes)
+            .context(UnknownEncoding { path: &self.path })
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, Format, LineEnding, Shared};
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_round_trip() {
+        let files: Vec<&[u8]> = vec![
+            b"fn main() {\n}\n",
+            b"fn main() {\r\n}\r\n",
+            b"\xEF\xBB\xBFfn",
+            b"\xFF\xFEf\x00n\x00\r\x00\n\x00",
+            b"\xFE\xFF\x00f\x00n",
+        ];
+
+        for bytes in files {
+            let (text, format) = Format::decode(bytes).unwrap();
+
+            assert!(!text.contains('\r'));
+            assert_eq!(format.encode(&text), bytes);
+        }
+
+        let (text, format) = Format::decode(b"\xFF\xFEf\x00n\x00\r\x00\n\x00").unwrap();
+
+        assert_eq!(text, "fn\n");
+        assert_eq!(format.encoding, Encoding::Utf16Le);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert!(Format::decode(b"\xC3\x28").is_none());
+        assert!(Format::decode(b"\xFF\xFEf").is_none());
+    }
+
+    #[test]
+    fn test_save() {
+        let path = env::temp_dir().join(format!("liveshare-share-{}.rs", process::id()));
+
+        fs::write(&path, "a\r\nb\r\n").unwrap();
+
+        let (mut shared, text) = Shared::open(&path).unwrap();
+
+        assert_eq!(text, "a\nb\n");
+        assert!(!shared.save("a\nb\n").unwrap());
+        assert!(shared.save("a\nc\n").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\r\nc\r\n");
+
+        // A change made by something else isn't overwritten before it's picked up.
+        fs::write(&path, "d\r\n").unwrap();
+
+        assert!(!shared.save("a\ne\n").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "d\r\n");
+        assert_eq!(shared.poll().unwrap(), Some("d\n".to_string()));
+        assert!(shared.save("a\ne\n").unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_poll() {
+        let path = env::temp_dir().join(format!("liveshare-poll-{}.rs", process::id()));
+
+        fs::write(&path, "a\n").unwrap();
+
+        let (mut shared, _) = Shared::open(&path).unwrap();
+
+        assert_eq!(shared.poll().unwrap(), None);
+
+        shared.save("b\n").unwrap();
+
+        assert_eq!(shared.poll().unwrap(), None);
+
+        // Written right after the save, so the modification time may well be the same.
+        fs::write(&path, "c\n").unwrap();
+
+        assert_eq!(shared.poll().unwrap(), Some("c\n".to_string()));
+        assert_eq!(shared.poll().unwrap(), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use {
+    crate::{atom::Atom, config::TrustedKey},
+    bincode::serialize,
+    ed25519_dalek::{Keypair, PublicKey, Signature, SignatureError, Signer, Verifier},
+    rand::rngs::OsRng,
+    serde::{Deserialize, Serialize},
+    snafu::{ensure, OptionExt, ResultExt, Snafu},
+    std::{collections::HashMap, convert::TryFrom, fmt, fs, io, path::Path},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("No public key is known for site {}", site))]
+    UnknownSite { site: i64 },
+
+    #[snafu(display("Malformed signature from site {}: {}", site, source))]
+    MalformedSignature { site: i64, source: SignatureError },
+
+    #[snafu(display("Signature from site {} does not match the operation", site))]
+    InvalidSignature { site: i64, source: SignatureError },
+
+    #[snafu(display("Site {} sent an atom authored by site {}", site, author))]
+    ForgedAuthor { site: i64, author: i64 },
+
+    #[snafu(display("Site {} sent an atom without an author", site))]
+    MissingAuthor { site: i64 },
+
+    #[snafu(display("Unable to read keypair from {}: {}", path, source))]
+    ReadKeypair { path: String, source: io::Error },
+
+    #[snafu(display("Unable to write keypair to {}: {}", path, source))]
+    WriteKeypair { path: String, source: io::Error },
+
+    #[snafu(display("Invalid key: {}", reason))]
+    InvalidKey { reason: String },
+}
+
+/// The kind of operation being signed.
+/// This is part of the signed message so that a signed insert can't be replayed as a delete.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Operation {
+    Insert,
+    Delete,
+}
+
+/// Holds this site's signing key along with the public keys of every site that we trust.
+/// Operations from sites without a trusted key are rejected.
+pub struct Keys {
+    keypair: Keypair,
+    trusted: HashMap<i64, PublicKey>,
+}
+
+/// The secret key must never end up in the logs, so only the public parts are printed.
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("public", &self.public_key())
+            .field("trusted", &self.trusted.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keys {
+    pub fn new(site: i64, keypair: Keypair, mut trusted: HashMap<i64, PublicKey>) -> Self {
+        trusted.insert(site, keypair.public);
+        Self { keypair, trusted }
+    }
+
+    /// Creates a fresh keypair that only trusts itself.
+    #[cfg(test)]
+    pub fn generate(site: i64) -> Self {
+        Self::new(site, Keypair::generate(&mut OsRng), HashMap::new())
+    }
+
+    /// Loads the keypair stored at `path`, generating and saving a new one if the file doesn't exist yet.
+    /// Each entry in `trusted` is a base64 encoded public key for the given site.
+    pub fn load(site: i64, path: Option<&str>, trusted: &[TrustedKey]) -> Result<Self, Error> {
+        let keypair = match path {
+            Some(path) if Path::new(path).exists() => {
+                let bytes = fs::read(path).context(ReadKeypair { path })?;
+                Keypair::from_bytes(&bytes).map_err(|e| Error::InvalidKey {
+                    reason: e.to_string(),
+                })?
+            }
+            Some(path) => {
+                let keypair = Keypair::generate(&mut OsRng);
+                fs::write(path, &keypair.to_bytes()[..]).context(WriteKeypair { path })?;
+                keypair
+            }
+            None => Keypair::generate(&mut OsRng),
+        };
+
+        let mut keys = HashMap::new();
+
+        for TrustedKey { site, key } in trusted {
+            let bytes = base64::decode(key).map_err(|e| Error::InvalidKey {
+                reason: e.to_string(),
+            })?;
+            let key = PublicKey::from_bytes(&bytes).map_err(|e| Error::InvalidKey {
+                reason: e.to_string(),
+            })?;
+            keys.insert(*site, key);
+        }
+
+        Ok(Self::new(site, keypair, keys))
+    }
+
+    /// The base64 encoded public key for this site, suitable for sharing with other peers.
+    pub fn public_key(&self) -> String {
+        base64::encode(self.keypair.public.as_bytes())
+    }
+
+    /// Signs an operation on `document` originating from `site`, which is its `seq`-th operation on the document.
+    /// The document is part of the signature, so that an operation can't be replayed on another document, and so is
+    /// the sequence number, so that every site agrees on which of the site's operations a version includes.
+    pub fn sign(
+        &self,
+        operation: Operation,
+        site: i64,
+        document: &str,
+        seq: u64,
+        lines: &[Atom],
+    ) -> Vec<u8> {
+        let signature = self
+            .keypair
+            .sign(&Self::message(operation, site, document, seq, lines));
+        signature.to_bytes().to_vec()
+    }
+
+    /// Verifies that an operation was signed by `site` and that it hasn't been tampered with.
+    /// Inserted atoms must also be authored by `site`, since a peer may only create atoms under its own ID.
+    pub fn verify(
+        &self,
+        operation: Operation,
+        site: i64,
+        document: &str,
+        seq: u64,
+        lines: &[Atom],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let key = self.trusted.get(&site).context(UnknownSite { site })?;
+        let signature = Signature::try_from(signature).context(MalformedSignature { site })?;
+
+        key.verify(
+            &Self::message(operation, site, document, seq, lines),
+            &signature,
+        )
+        .context(InvalidSignature { site })?;
+
+        if let Operation::Insert = operation {
+            for atom in lines {
+                let author = atom.site().context(MissingAuthor { site })?;
+                ensure!(author == site, ForgedAuthor { site, author });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Signs an event other than an edit that `site` sends, such as a role change, over its `fields`.
+    /// The `kind` of event is part of the signature, so that one kind of event can't be replayed as another.
+    pub fn sign_event<T: Serialize>(&self, kind: &str, site: i64, fields: &T) -> Vec<u8> {
+        let signature = self.keypair.sign(&Self::event(kind, site, fields));
+        signature.to_bytes().to_vec()
+    }
+
+    /// Verifies that an event other than an edit was signed by `site` and that it hasn't been tampered with.
+    pub fn verify_event<T: Serialize>(
+        &self,
+        kind: &str,
+        site: i64,
+        fields: &T,
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let key = self.trusted.get(&site).context(UnknownSite { site })?;
+        let signature = Signature::try_from(signature).context(MalformedSignature { site })?;
+
+        key.verify(&Self::event(kind, site, fields), &signature)
+            .context(InvalidSignature { site })
+    }
+
+    fn event<T: Serialize>(kind: &str, site: i64, fields: &T) -> Vec<u8> {
+        serialize(&(kind, site, fields)).expect("Unable to serialize event.")
+    }
+
+    fn message(
+        operation: Operation,
+        site: i64,
+        document: &str,
+        seq: u64,
+        lines: &[Atom],
+    ) -> Vec<u8> {
+        serialize(&(operation, site, document, seq, lines)).expect("Unable to serialize operation.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Keys, Operation};
+    use crate::{atom::Atom, id::Id, position::Position};
+
+    fn atoms(site: i64) -> Vec<Atom> {
+        vec![
+            Atom::new(Position::new(&[Id::new(1, site)]), 0, 'h'),
+            Atom::new(Position::new(&[Id::new(2, site)]), 0, 'i'),
+        ]
+    }
+
+    #[test]
+    fn test_verify_signed_operation() {
+        let keys = Keys::generate(1);
+        let lines = atoms(1);
+        let signature = keys.sign(Operation::Insert, 1, "a.rs", 1, &lines);
+
+        assert!(keys
+            .verify(Operation::Insert, 1, "a.rs", 1, &lines, &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_reject_tampered_operation() {
+        let keys = Keys::generate(1);
+        let mut lines = atoms(1);
+        let signature = keys.sign(Operation::Insert, 1, "a.rs", 1, &lines);
+
+        lines[0].val = 'x';
+
+        assert!(matches!(
+            keys.verify(Operation::Insert, 1, "a.rs", 1, &lines, &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+        assert!(matches!(
+            keys.verify(Operation::Delete, 1, "a.rs", 1, &atoms(1), &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+        assert!(matches!(
+            keys.verify(Operation::Insert, 1, "b.rs", 1, &atoms(1), &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+        assert!(matches!(
+            keys.verify(Operation::Insert, 1, "a.rs", 2, &atoms(1), &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_signed_event() {
+        let keys = Keys::generate(1);
+        let signature = keys.sign_event("set_role", 1, &(2, "viewer"));
+
+        assert!(keys
+            .verify_event("set_role", 1, &(2, "viewer"), &signature)
+            .is_ok());
+        assert!(matches!(
+            keys.verify_event("set_role", 1, &(2, "owner"), &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+        assert!(matches!(
+            keys.verify_event("granted", 1, &(2, "viewer"), &signature),
+            Err(Error::InvalidSignature { .. })
+        ));
+        assert!(matches!(
+            keys.verify_event("set_role", 2, &(2, "viewer"), &signature),
+            Err(Error::UnknownSite { site: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_reject_forged_author() {
+        let keys = Keys::generate(1);
+        let lines = atoms(2);
+        let signature = keys.sign(Operation::Insert, 1, "a.rs", 1, &lines);
+
+        assert!(matches!(
+            keys.verify(Operation::Insert, 1, "a.rs", 1, &lines, &signature),
+            Err(Error::ForgedAuthor { site: 1, author: 2 })
+        ));
+        assert!(matches!(
+            keys.verify(Operation::Insert, 2, "a.rs", 1, &lines, &signature),
+            Err(Error::UnknownSite { site: 2 })
+        ));
+    }
+}
@@ -0,0 +1,591 @@
+use {
+    crate::id::Id,
+    serde::{Deserialize, Serialize},
+    snafu::{ensure, OptionExt, Snafu},
+    std::collections::BTreeMap,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid path: {:?}", path))]
+    InvalidPath { path: String },
+
+    #[snafu(display("No such file or directory: {}", path))]
+    NotFound { path: String },
+
+    #[snafu(display("Not a directory: {}", path))]
+    NotADirectory { path: String },
+
+    #[snafu(display("Already exists: {}", path))]
+    AlreadyExists { path: String },
+
+    #[snafu(display("Can't move {} into itself", path))]
+    IntoItself { path: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    #[default]
+    File,
+    Directory,
+}
+
+/// Where an entry is in the tree, i.e. its parent (or the root if `None`) and its name.
+/// The placement with the greatest stamp wins, so concurrent renames and moves of the same entry converge.
+#[derive(Clone, Debug)]
+struct Placement {
+    parent: Option<Id>,
+    name: String,
+    stamp: Id,
+}
+
+/// A file or directory, which keeps its identity while it's renamed or moved.
+#[derive(Clone, Debug)]
+struct Entry {
+    kind: Kind,
+    placement: Placement,
+    /// Entries are never removed, so that moves and creates that arrive after a delete still find their parent.
+    deleted: bool,
+}
+
+/// A file or directory as it's shown to the editor.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct View {
+    pub id: Id,
+    pub path: String,
+    pub kind: Kind,
+}
+
+/// A change to the tree, which is replicated to every peer.
+/// Like the changes to threads, applying the same change twice has no effect, and changes to an entry that doesn't
+/// exist yet are held back until it's created.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Change {
+    Create {
+        id: Id,
+        kind: Kind,
+        parent: Option<Id>,
+        name: String,
+    },
+    /// Renames and moves are the same change, since both only replace the placement of the entry.
+    Move {
+        id: Id,
+        parent: Option<Id>,
+        name: String,
+        stamp: Id,
+    },
+    Delete {
+        id: Id,
+    },
+}
+
+impl Change {
+    /// The entry that the change applies to.
+    pub fn id(&self) -> &Id {
+        match self {
+            Change::Create { id, .. } | Change::Move { id, .. } | Change::Delete { id } => id,
+        }
+    }
+
+    /// The site that made the change, if it can be told from the change itself.
+    pub fn author(&self) -> Option<i64> {
+        match self {
+            Change::Create { id, .. } => Some(id.site),
+            Change::Move { stamp, .. } => Some(stamp.site),
+            Change::Delete { .. } => None,
+        }
+    }
+}
+
+/// The files and directories of the session.
+///
+/// Conflicts are settled the same way by every site, whatever order the changes arrive in:
+/// - Concurrent moves of the same entry: the one with the greatest stamp wins.
+/// - A delete and a concurrent move of the same entry: the delete wins.
+/// - Deleting a directory hides everything in it, except for entries that were concurrently moved out of it.
+/// - Two entries with the same name in the same directory: the one that was created first (by id) keeps the name,
+///   and the others are shown as `name~site`.
+/// - Concurrent moves that put directories inside of each other: every directory in the cycle is shown at the root.
+#[derive(Debug)]
+pub struct Tree {
+    site: i64,
+    /// A Lamport clock, used to create the ids of entries and the stamps of moves.
+    clock: u64,
+    entries: BTreeMap<Id, Entry>,
+    /// Moves and deletes that arrived before the entry they refer to.
+    pending: Vec<Change>,
+}
+
+impl Tree {
+    pub fn new(site: i64) -> Self {
+        Self {
+            site,
+            clock: 0,
+            entries: BTreeMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Creates a file or directory at `path`, whose parent directory has to exist.
+    pub fn create(&mut self, path: &str, kind: Kind) -> Result<Change> {
+        let (parent, name) = self.place(path)?;
+        let change = Change::Create {
+            id: self.next_id(),
+            kind,
+            parent,
+            name,
+        };
+
+        self.apply(change.clone());
+
+        Ok(change)
+    }
+
+    /// Renames or moves the entry at `from` to `to`, whose parent directory has to exist.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<Change> {
+        let id = self.find(from)?;
+        let (parent, name) = self.place(to)?;
+
+        let mut ancestor = parent.clone();
+        while let Some(next) = ancestor {
+            ensure!(next != id, IntoItself { path: from });
+            ancestor = self.parent(&next);
+        }
+
+        let change = Change::Move {
+            id,
+            parent,
+            name,
+            stamp: self.next_id(),
+        };
+
+        self.apply(change.clone());
+
+        Ok(change)
+    }
+
+    /// Deletes the entry at `path`, along with everything in it.
+    pub fn delete(&mut self, path: &str) -> Result<Change> {
+        let change = Change::Delete {
+            id: self.find(path)?,
+        };
+
+        self.apply(change.clone());
+
+        Ok(change)
+    }
+
+    /// Applies a change made by any site, returning whether anything changed.
+    /// A move or delete of an entry that doesn't exist yet is kept until the entry is created, since events from
+    /// different sites may arrive in any order.
+    pub fn apply(&mut self, change: Change) -> bool {
+        self.witness(&change);
+
+        match change {
+            Change::Move { ref id, .. } | Change::Delete { ref id }
+                if !self.entries.contains_key(id) =>
+            {
+                self.pending.push(change);
+                false
+            }
+            Change::Create { .. } => {
+                let id = change.id().clone();
+                let (pending, rest) = self
+                    .pending
+                    .drain(..)
+                    .partition(|change: &Change| *change.id() == id);
+
+                self.pending = rest;
+
+                let created = self.update(change);
+
+                for change in pending {
+                    self.update(change);
+                }
+
+                created
+            }
+            change => self.update(change),
+        }
+    }
+
+    /// The changes that recreate the whole tree, e.g. for a site that joins late.
+    /// Entries are created where they are now, and then moved with the stamp of their placement, so that any moves
+    /// that the site makes concurrently are settled in the same way as everywhere else.
+    pub fn changes(&self) -> Vec<Change> {
+        let mut res = Vec::new();
+
+        for (id, entry) in &self.entries {
+            let placement = &entry.placement;
+
+            res.push(Change::Create {
+                id: id.clone(),
+                kind: entry.kind,
+                parent: placement.parent.clone(),
+                name: placement.name.clone(),
+            });
+
+            if placement.stamp != *id {
+                res.push(Change::Move {
+                    id: id.clone(),
+                    parent: placement.parent.clone(),
+                    name: placement.name.clone(),
+                    stamp: placement.stamp.clone(),
+                });
+            }
+
+            if entry.deleted {
+                res.push(Change::Delete { id: id.clone() });
+            }
+        }
+
+        res
+    }
+
+    /// Applies a change to an entry that exists, or creates it. Returns `false` if nothing changed.
+    fn update(&mut self, change: Change) -> bool {
+        match change {
+            Change::Create {
+                id,
+                kind,
+                parent,
+                name,
+            } => {
+                if self.entries.contains_key(&id) {
+                    return false;
+                }
+
+                let placement = Placement {
+                    parent,
+                    name,
+                    stamp: id.clone(),
+                };
+                let entry = Entry {
+                    kind,
+                    placement,
+                    deleted: false,
+                };
+
+                self.entries.insert(id, entry);
+                true
+            }
+            Change::Move {
+                id,
+                parent,
+                name,
+                stamp,
+            } => match self.entries.get_mut(&id) {
+                Some(entry) if stamp > entry.placement.stamp => {
+                    entry.placement = Placement {
+                        parent,
+                        name,
+                        stamp,
+                    };
+                    true
+                }
+                _ => false,
+            },
+            Change::Delete { id } => match self.entries.get_mut(&id) {
+                Some(entry) if !entry.deleted => {
+                    entry.deleted = true;
+                    true
+                }
+                _ => false,
+            },
+        }
+    }
+
+    /// Every file and directory that isn't deleted, sorted by path.
+    pub fn files(&self) -> Vec<View> {
+        let mut names: BTreeMap<&Id, String> = BTreeMap::new();
+        let mut siblings: BTreeMap<Option<Id>, BTreeMap<&str, Vec<&Id>>> = BTreeMap::new();
+
+        for (id, entry) in &self.entries {
+            if self.is_visible(id) {
+                siblings
+                    .entry(self.parent(id))
+                    .or_default()
+                    .entry(&entry.placement.name)
+                    .or_default()
+                    .push(id);
+            }
+        }
+
+        // The entries are visited in order of their ids, so the first one with a name is the one that keeps it.
+        for ids in siblings.values().flat_map(|names| names.values()) {
+            for (i, id) in ids.iter().enumerate() {
+                let name = &self.entries[*id].placement.name;
+                let name = match i {
+                    0 => name.clone(),
+                    _ => format!("{}~{}", name, id.site),
+                };
+
+                names.insert(id, name);
+            }
+        }
+
+        let mut files: Vec<View> = names
+            .keys()
+            .map(|id| {
+                let mut path = vec![names[id].as_str()];
+                let mut ancestor = self.parent(id);
+
+                while let Some(next) = ancestor {
+                    path.push(&names[&next]);
+                    ancestor = self.parent(&next);
+                }
+
+                path.reverse();
+
+                View {
+                    id: (*id).clone(),
+                    path: path.join("/"),
+                    kind: self.entries[*id].kind,
+                }
+            })
+            .collect();
+
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+
+    /// The id of the entry at `path`.
+    pub fn lookup(&self, path: &str) -> Option<Id> {
+        self.files()
+            .into_iter()
+            .find(|file| file.path == path)
+            .map(|file| file.id)
+    }
+
+    /// The directory that an entry is shown in, or `None` for the root.
+    /// An entry that ends up inside of itself after concurrent moves is shown at the root instead.
+    fn parent(&self, id: &Id) -> Option<Id> {
+        let parent = self.entries.get(id)?.placement.parent.as_ref();
+        let mut ancestor = parent;
+
+        // A chain that is longer than the number of entries runs through a cycle that doesn't contain `id`.
+        for _ in 0..self.entries.len() {
+            match ancestor {
+                Some(next) if next == id => return None,
+                Some(next) => {
+                    ancestor = self
+                        .entries
+                        .get(next)
+                        .and_then(|entry| entry.placement.parent.as_ref())
+                }
+                None => break,
+            }
+        }
+
+        parent.cloned()
+    }
+
+    /// Whether the entry and every directory above it exist and aren't deleted.
+    fn is_visible(&self, id: &Id) -> bool {
+        let mut ancestor = Some(id.clone());
+
+        while let Some(next) = ancestor {
+            match self.entries.get(&next) {
+                Some(entry) if !entry.deleted => ancestor = self.parent(&next),
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    /// The id of the entry at `path`, which has to exist.
+    fn find(&self, path: &str) -> Result<Id> {
+        self.lookup(path).context(NotFound { path })
+    }
+
+    /// The parent directory and name that `path` would have, if nothing exists there yet.
+    fn place(&self, path: &str) -> Result<(Option<Id>, String)> {
+        let valid = |name: &str| !name.is_empty() && name != "." && name != "..";
+        ensure!(path.split('/').all(valid), InvalidPath { path });
+        ensure!(self.lookup(path).is_none(), AlreadyExists { path });
+
+        match path.rfind('/') {
+            Some(i) => {
+                let (dir, name) = (&path[..i], &path[i + 1..]);
+                let parent = self.find(dir)?;
+
+                ensure!(
+                    self.entries[&parent].kind == Kind::Directory,
+                    NotADirectory { path: dir }
+                );
+
+                Ok((Some(parent), name.to_string()))
+            }
+            None => Ok((None, path.to_string())),
+        }
+    }
+
+    /// Moves the clock past the ids in `change`, so that our next move wins over the ones we've seen.
+    fn witness(&mut self, change: &Change) {
+        let digit = match change {
+            Change::Create { id, .. } => id.digit,
+            Change::Move { stamp, .. } => stamp.digit,
+            Change::Delete { .. } => 0,
+        };
+
+        self.clock = self.clock.max(digit);
+    }
+
+    fn next_id(&mut self) -> Id {
+        self.clock += 1;
+        Id::new(self.clock, self.site)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kind, Tree};
+
+    fn paths(tree: &Tree) -> Vec<String> {
+        tree.files().into_iter().map(|file| file.path).collect()
+    }
+
+    #[test]
+    fn test_create_rename_delete() {
+        let mut tree = Tree::new(1);
+
+        tree.create("src", Kind::Directory).unwrap();
+        tree.create("src/main.rs", Kind::File).unwrap();
+        tree.create("README.org", Kind::File).unwrap();
+
+        assert!(tree.create("src/main.rs", Kind::File).is_err());
+        assert!(tree.create("README.org/x", Kind::File).is_err());
+        assert!(tree.create("lib/x.rs", Kind::File).is_err());
+        assert!(tree.create("src/../x", Kind::File).is_err());
+        assert!(tree.rename("src", "src/inner").is_err());
+
+        tree.rename("src/main.rs", "src/lib.rs").unwrap();
+        tree.create("docs", Kind::Directory).unwrap();
+        tree.rename("src", "docs/src").unwrap();
+
+        assert_eq!(
+            paths(&tree),
+            vec!["README.org", "docs", "docs/src", "docs/src/lib.rs"]
+        );
+
+        tree.delete("docs").unwrap();
+
+        assert_eq!(paths(&tree), vec!["README.org"]);
+    }
+
+    #[test]
+    fn test_concurrent_create() {
+        let mut ours = Tree::new(1);
+        let mut theirs = Tree::new(2);
+
+        let a = ours.create("a.rs", Kind::File).unwrap();
+        let b = theirs.create("a.rs", Kind::File).unwrap();
+
+        assert!(ours.apply(b.clone()));
+        assert!(theirs.apply(a.clone()));
+        assert!(!theirs.apply(a));
+        assert_eq!(ours.files(), theirs.files());
+        assert_eq!(paths(&ours), vec!["a.rs", "a.rs~2"]);
+    }
+
+    #[test]
+    fn test_concurrent_moves() {
+        let mut ours = Tree::new(1);
+        let mut theirs = Tree::new(2);
+
+        for change in [
+            ours.create("a", Kind::Directory).unwrap(),
+            ours.create("b", Kind::Directory).unwrap(),
+            ours.create("c.rs", Kind::File).unwrap(),
+        ] {
+            theirs.apply(change);
+        }
+
+        // Each site moves one directory into the other, and renames the same file.
+        let ours_a = ours.rename("a", "b/a").unwrap();
+        let ours_c = ours.rename("c.rs", "d.rs").unwrap();
+        let theirs_b = theirs.rename("b", "a/b").unwrap();
+        let theirs_c = theirs.rename("c.rs", "e.rs").unwrap();
+
+        ours.apply(theirs_b);
+        ours.apply(theirs_c);
+        theirs.apply(ours_c);
+        theirs.apply(ours_a);
+
+        assert_eq!(ours.files(), theirs.files());
+        assert_eq!(paths(&ours), vec!["a", "b", "e.rs"]);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut ours = Tree::new(1);
+        let mut theirs = Tree::new(2);
+        let mut late = Tree::new(3);
+
+        let create = ours.create("a.rs", Kind::File).unwrap();
+        theirs.apply(create.clone());
+        let rename = theirs.rename("a.rs", "b.rs").unwrap();
+
+        // The rename overtook the create it depends on.
+        assert!(!late.apply(rename));
+        assert!(paths(&late).is_empty());
+        assert!(late.apply(create));
+        assert_eq!(paths(&late), vec!["b.rs"]);
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut ours = Tree::new(1);
+        let mut late = Tree::new(2);
+
+        ours.create("src", Kind::Directory).unwrap();
+        ours.create("src/a.rs", Kind::File).unwrap();
+        ours.create("b.rs", Kind::File).unwrap();
+        ours.rename("src/a.rs", "c.rs").unwrap();
+        ours.delete("src").unwrap();
+
+        for change in ours.changes() {
+            late.apply(change);
+        }
+
+        assert_eq!(late.files(), ours.files());
+
+        // A site that joined late still agrees on which of two concurrent renames wins.
+        let theirs = late.rename("c.rs", "d.rs").unwrap();
+        let mine = ours.rename("c.rs", "e.rs").unwrap();
+
+        ours.apply(theirs);
+        late.apply(mine);
+
+        assert_eq!(late.files(), ours.files());
+    }
+
+    #[test]
+    fn test_delete_wins() {
+        let mut ours = Tree::new(1);
+        let mut theirs = Tree::new(2);
+
+        for change in [
+            ours.create("src", Kind::Directory).unwrap(),
+            ours.create("src/a.rs", Kind::File).unwrap(),
+            ours.create("src/b.rs", Kind::File).unwrap(),
+        ] {
+            theirs.apply(change);
+        }
+
+        let delete = ours.delete("src").unwrap();
+        let rename = theirs.rename("src/a.rs", "src/c.rs").unwrap();
+        let moved = theirs.rename("src/b.rs", "b.rs").unwrap();
+
+        ours.apply(rename);
+        ours.apply(moved);
+        theirs.apply(delete);
+
+        assert_eq!(ours.files(), theirs.files());
+        assert_eq!(paths(&ours), vec!["b.rs"]);
+    }
+}
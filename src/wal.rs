@@ -0,0 +1,738 @@
+use {
+    crate::{
+        atom::Atom,
+        history::{self, Version},
+        signature::Operation,
+        workspace::DocumentId,
+    },
+    flate2::Crc,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    snafu::{ensure, ResultExt, Snafu},
+    std::{
+        convert::TryInto,
+        fs::{self, File, OpenOptions},
+        io::Write as _,
+        path::{Path, PathBuf},
+    },
+    tracing::{info, warn},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to open {}: {}", path.display(), source))]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to read {}: {}", path.display(), source))]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to write {}: {}", path.display(), source))]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to encode a record: {}", source))]
+    Encode { source: bincode::Error },
+
+    #[snafu(display(
+        "{} is corrupt after byte {}, which isn't the end of the log.",
+        path.display(),
+        len
+    ))]
+    Corrupt { path: PathBuf, len: u64 },
+
+    #[snafu(display(
+        "Entries {} to {} are missing from the log, so {} can't be replayed.",
+        from,
+        to,
+        path.display()
+    ))]
+    Missing { path: PathBuf, from: u64, to: u64 },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The size of the header in front of every record, which holds the length of the record and its CRC-32.
+const HEADER: usize = 8;
+
+/// Once a segment grows past this many bytes, new entries go to a new segment.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// An operation that was applied to a document, as it's kept in the log.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Entry {
+    pub document: DocumentId,
+    pub site: i64,
+    /// The number that `site` gave the operation, counting its operations on the document from 1.
+    pub seq: u64,
+    /// When the operation was applied, in milliseconds since the Unix epoch.
+    pub time: u64,
+    pub operation: Operation,
+    pub atoms: Vec<Atom>,
+}
+
+/// The state of a document when a snapshot was taken.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Image {
+    pub document: DocumentId,
+    pub version: Version,
+    pub atoms: Vec<Atom>,
+}
+
+/// The state of every open document after the first `index` entries of the log.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Snapshot {
+    pub index: u64,
+    /// When the snapshot was taken, in milliseconds since the Unix epoch.
+    pub time: u64,
+    pub documents: Vec<Image>,
+}
+
+/// A write-ahead log of every operation applied to the documents, so that they can be rebuilt after a crash.
+///
+/// The log is a directory of segments, each named after the index of its first entry, e.g.
+/// "00000000000000000042.log". A record is the length of an entry, its CRC-32 and then the entry itself, encoded with
+/// bincode. Snapshots are kept next to the segments, named after the number of entries that they cover, e.g.
+/// "00000000000000000042.snapshot". Once a snapshot is written, the segments that it covers are deleted.
+///
+/// Appended entries are kept in memory until they're committed, so that many of them can be written to disk at once.
+#[derive(Debug)]
+pub struct Log {
+    dir: PathBuf,
+    /// The segment that entries are appended to.
+    file: File,
+    path: PathBuf,
+    /// The size of that segment.
+    len: u64,
+    /// The index of the next entry.
+    index: u64,
+    /// The records of the entries that weren't committed yet, and how many there are.
+    buffer: Vec<u8>,
+    buffered: u64,
+    /// The number of entries that the newest snapshot covers.
+    covered: u64,
+}
+
+impl Log {
+    /// Opens the log in the directory `dir`, creating it if it doesn't exist.
+    /// Returns the newest snapshot that can be read, if any, along with the entries that came after it.
+    ///
+    /// A record at the end of the last segment that was only partly written because the node stopped while writing
+    /// it, or whose checksum doesn't match, is cut off along with everything after it, so that new records are
+    /// appended after the last good one. A bad record anywhere else is an error, and nothing is changed, since the
+    /// entries after it can't be replayed in order.
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, Option<Snapshot>, Vec<Entry>)> {
+        let dir = dir.as_ref().to_path_buf();
+
+        fs::create_dir_all(&dir).context(Write { path: &dir })?;
+
+        let scan = scan(&dir)?;
+
+        if let Some((ref path, len)) = scan.torn {
+            warn!(
+                "Truncated torn or corrupt records from the end of {}.",
+                path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_len(len))
+                .context(Write { path })?;
+        }
+
+        let (path, len) = match scan.torn.or(scan.last) {
+            Some((path, len)) => (path, len),
+            None => (segment(&dir, scan.index), 0),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .context(Open { path: &path })?;
+        let covered = scan.snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+
+        info!(
+            "Read {} operations after a snapshot of {} from {}.",
+            scan.entries.len(),
+            covered,
+            dir.display()
+        );
+
+        let log = Self {
+            dir,
+            file,
+            path,
+            len,
+            index: scan.index,
+            buffer: Vec::new(),
+            buffered: 0,
+            covered,
+        };
+
+        Ok((log, scan.snapshot, scan.entries))
+    }
+
+    /// Reads the log in the directory `dir` like `open` does, but without changing anything in it, e.g. while a node is
+    /// still appending to it.
+    pub fn read(dir: impl AsRef<Path>) -> Result<(Option<Snapshot>, Vec<Entry>)> {
+        let scan = scan(dir.as_ref())?;
+
+        Ok((scan.snapshot, scan.entries))
+    }
+
+    /// Reads back the newest snapshot and the entries after it that are on disk, i.e. the ones that were committed.
+    pub fn contents(&self) -> Result<(Option<Snapshot>, Vec<Entry>)> {
+        Self::read(&self.dir)
+    }
+
+    /// Appends `entry` to the log. It's only on disk once it's committed.
+    pub fn append(&mut self, entry: &Entry) -> Result<()> {
+        self.buffer.extend(encode(entry)?);
+        self.buffered += 1;
+        self.index += 1;
+
+        Ok(())
+    }
+
+    /// Whether there are entries that weren't committed yet.
+    pub fn uncommitted(&self) -> bool {
+        self.buffered > 0
+    }
+
+    /// Writes the entries that were appended since the last commit, returning once they're on disk.
+    /// If that fails, they're kept, so that they're written by the next commit. Whatever part of them made it to the
+    /// segment is cut off again first, so that the next commit doesn't write them after a partial copy of themselves.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+
+        if self.len >= SEGMENT_SIZE {
+            self.rotate(self.index - self.buffered)?;
+        }
+
+        let written = self
+            .file
+            .write_all(&self.buffer)
+            .and_then(|_| self.file.sync_data());
+
+        if let Err(source) = written {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|file| file.set_len(self.len))
+                .context(Write { path: &self.path })?;
+
+            return Err(Error::Write {
+                path: self.path.clone(),
+                source,
+            });
+        }
+
+        self.len += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.buffered = 0;
+
+        Ok(())
+    }
+
+    /// The number of entries that were appended since the newest snapshot.
+    pub fn pending(&self) -> u64 {
+        self.index - self.covered
+    }
+
+    /// Writes a snapshot of `documents`, which must be their state after every entry appended so far.
+    /// Those entries are committed first. The snapshot is written to a temporary file, which then replaces it, so that
+    /// it's never left half written. The segments and snapshots that it makes obsolete are deleted afterwards.
+    pub fn snapshot(&mut self, documents: Vec<Image>) -> Result<()> {
+        self.commit()?;
+
+        let snapshot = Snapshot {
+            index: self.index,
+            time: history::now(),
+            documents,
+        };
+        let path = self.dir.join(format!("{:020}.snapshot", snapshot.index));
+        let temp = self.dir.join(format!(".{:020}.snapshot", snapshot.index));
+        let mut file = File::create(&temp).context(Write { path: &temp })?;
+
+        file.write_all(&encode(&snapshot)?)
+            .context(Write { path: &temp })?;
+        file.sync_all().context(Write { path: &temp })?;
+        fs::rename(&temp, &path).context(Write { path: &path })?;
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .context(Write { path: &self.dir })?;
+
+        // New entries go to a new segment, so that every segment before it is covered by the snapshot.
+        if self.len > 0 {
+            self.rotate(self.index)?;
+        }
+
+        for (_, old) in list(&self.dir, "log")?
+            .into_iter()
+            .chain(list(&self.dir, "snapshot")?)
+        {
+            if old != self.path && old != path {
+                fs::remove_file(&old).context(Write { path: &old })?;
+            }
+        }
+
+        self.covered = snapshot.index;
+        info!("Wrote a snapshot of {} operations.", snapshot.index);
+
+        Ok(())
+    }
+
+    /// Starts a new segment, whose first entry is the one at `index`.
+    fn rotate(&mut self, index: u64) -> Result<()> {
+        let path = segment(&self.dir, index);
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .context(Open { path: &path })?;
+        self.path = path;
+        self.len = 0;
+
+        Ok(())
+    }
+}
+
+/// What's in the directory of a log.
+struct Scan {
+    /// The newest snapshot that can be read.
+    snapshot: Option<Snapshot>,
+    /// The entries after the snapshot, up to the torn record at the end, if any.
+    entries: Vec<Entry>,
+    /// The index of the entry after them.
+    index: u64,
+    /// The last segment, along with where its records end.
+    last: Option<(PathBuf, u64)>,
+    /// The last segment if it ends with a torn record, along with where its good records end.
+    torn: Option<(PathBuf, u64)>,
+}
+
+/// Reads the newest snapshot in `dir` that's intact, and the entries after it.
+/// Only the last segment may end with a torn record, since the node only ever writes to that one.
+///
+/// The segments have to follow on from each other and from the snapshot. A snapshot that can't be read is skipped, but
+/// only if the segments still hold every entry that it covers, since they're deleted once the snapshot is written.
+fn scan(dir: &Path) -> Result<Scan> {
+    let mut skipped = None;
+    let snapshot = list(dir, "snapshot")?
+        .into_iter()
+        .rev()
+        .find_map(|(index, path)| match read_snapshot(&path) {
+            Ok(Some(snapshot)) => Some(snapshot),
+            Ok(None) => {
+                warn!("Skipped torn or corrupt snapshot {}.", path.display());
+                skipped.get_or_insert((index, path));
+                None
+            }
+            Err(e) => {
+                warn!("Skipped snapshot {}: {}", path.display(), e);
+                skipped.get_or_insert((index, path));
+                None
+            }
+        });
+    let covered = snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+    let mut scan = Scan {
+        snapshot,
+        entries: Vec::new(),
+        index: covered,
+        last: None,
+        torn: None,
+    };
+    let segments = list(dir, "log")?;
+    let count = segments.len();
+
+    for (i, (start, path)) in segments.into_iter().enumerate() {
+        // The first segment may start before the snapshot, since it's only deleted once it's covered entirely.
+        let contiguous = match i {
+            0 => start <= covered,
+            _ => start == scan.index,
+        };
+
+        ensure!(
+            contiguous,
+            Missing {
+                path,
+                from: scan.index,
+                to: start,
+            }
+        );
+
+        let bytes = fs::read(&path).context(Read { path: &path })?;
+        let (records, len) = decode::<Entry>(&bytes);
+
+        scan.index = scan.index.max(start + records.len() as u64);
+        scan.entries.extend(
+            records
+                .into_iter()
+                .zip(start..)
+                .filter(|(_, i)| *i >= covered)
+                .map(|(entry, _)| entry),
+        );
+
+        if len < bytes.len() && i + 1 < count {
+            return Corrupt {
+                path,
+                len: len as u64,
+            }
+            .fail();
+        } else if len < bytes.len() {
+            scan.torn = Some((path, len as u64));
+        } else {
+            scan.last = Some((path, len as u64));
+        }
+    }
+
+    if let Some((index, path)) = skipped {
+        ensure!(
+            scan.index >= index,
+            Missing {
+                path,
+                from: scan.index,
+                to: index,
+            }
+        );
+    }
+
+    Ok(scan)
+}
+
+/// The path of the segment whose first entry is `index`.
+fn segment(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", index))
+}
+
+/// The files in `dir` with the `extension`, along with the index in their name, ordered by it.
+/// Temporary files, whose name starts with ".", are left out.
+fn list(dir: &Path, extension: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let mut res = Vec::new();
+
+    for entry in fs::read_dir(dir).context(Read { path: dir })? {
+        let path = entry.context(Read { path: dir })?.path();
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+
+        if let Some(index) = index {
+            if path.extension().is_some_and(|ext| ext == extension) {
+                res.push((index, path));
+            }
+        }
+    }
+
+    res.sort();
+
+    Ok(res)
+}
+
+/// Reads the snapshot at `path`, which is `None` if it's torn or corrupt.
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>> {
+    let bytes = fs::read(path).context(Read { path })?;
+
+    match decode(&bytes) {
+        (mut records, len) if len == bytes.len() && records.len() == 1 => Ok(records.pop()),
+        _ => Ok(None),
+    }
+}
+
+/// Encodes `value` as a record.
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(value).context(Encode)?;
+    let mut res = Vec::with_capacity(HEADER + payload.len());
+
+    res.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    res.extend_from_slice(&checksum(&payload).to_le_bytes());
+    res.extend_from_slice(&payload);
+
+    Ok(res)
+}
+
+/// Reads the records in `bytes` up to the first one that's incomplete or corrupt.
+/// Returns their values along with the number of bytes that they take up.
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> (Vec<T>, usize) {
+    let mut res = Vec::new();
+    let mut at = 0;
+
+    while let Some(header) = bytes.get(at..at + HEADER) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+        let payload = match bytes.get(at + HEADER..at + HEADER + len) {
+            Some(payload) if checksum(payload) == sum => payload,
+            _ => break,
+        };
+
+        match bincode::deserialize(payload) {
+            Ok(value) => res.push(value),
+            Err(_) => break,
+        }
+
+        at += HEADER + len;
+    }
+
+    (res, at)
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+
+    crc.update(bytes);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Entry, Error, Image, Log};
+    use crate::{atom::Atom, id::Id, position::Position, signature::Operation};
+    use std::{
+        env,
+        fs::{self, File, OpenOptions},
+        io::Write,
+        mem,
+        path::Path,
+        process,
+    };
+
+    fn entry(val: char) -> Entry {
+        Entry {
+            document: String::new(),
+            site: 1,
+            seq: 1,
+            time: 100,
+            operation: Operation::Insert,
+            atoms: vec![Atom::new(Position::new(&[Id::new(10, 1)]), 0, val)],
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut res: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = env::temp_dir().join(format!("liveshare-wal-{}", process::id()));
+        let segment = dir.join(format!("{:020}.log", 0));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut log, snapshot, entries) = Log::open(&dir).unwrap();
+
+        assert!(snapshot.is_none());
+        assert!(entries.is_empty());
+
+        log.append(&entry('a')).unwrap();
+        log.append(&entry('b')).unwrap();
+        assert!(log.uncommitted());
+        log.commit().unwrap();
+        assert!(!log.uncommitted());
+        drop(log);
+
+        let len = fs::metadata(&segment).unwrap().len();
+
+        // A crash in the middle of writing the third record leaves only part of it.
+        OpenOptions::new()
+            .append(true)
+            .open(&segment)
+            .unwrap()
+            .write_all(&[12, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let (mut log, _, entries) = Log::open(&dir).unwrap();
+
+        assert_eq!(entries, vec![entry('a'), entry('b')]);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+
+        log.append(&entry('c')).unwrap();
+        log.commit().unwrap();
+        drop(log);
+
+        // Flipping a byte of the last record makes its checksum fail.
+        let mut bytes = fs::read(&segment).unwrap();
+        let last = bytes.len() - 1;
+
+        bytes[last] ^= 0xFF;
+        fs::write(&segment, bytes).unwrap();
+
+        let (_, _, entries) = Log::open(&dir).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_commit() {
+        let dir = env::temp_dir().join(format!("liveshare-failed-{}", process::id()));
+        let segment = dir.join(format!("{:020}.log", 0));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut log, _, _) = Log::open(&dir).unwrap();
+
+        log.append(&entry('a')).unwrap();
+        log.commit().unwrap();
+
+        let len = fs::metadata(&segment).unwrap().len();
+
+        // The write fails after part of the record made it to the segment.
+        log.append(&entry('b')).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&segment)
+            .unwrap()
+            .write_all(&log.buffer[..4])
+            .unwrap();
+        let file = mem::replace(&mut log.file, File::open(&segment).unwrap());
+
+        assert!(matches!(log.commit(), Err(Error::Write { .. })));
+        assert!(log.uncommitted());
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+
+        // The next commit writes the record once, after the last one that made it.
+        log.file = file;
+        log.commit().unwrap();
+        drop(log);
+
+        let (_, _, entries) = Log::open(&dir).unwrap();
+
+        assert_eq!(entries, vec![entry('a'), entry('b')]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dir = env::temp_dir().join(format!("liveshare-corrupt-{}", process::id()));
+        let segment = dir.join(format!("{:020}.log", 0));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut log, _, _) = Log::open(&dir).unwrap();
+
+        log.append(&entry('a')).unwrap();
+        log.append(&entry('b')).unwrap();
+        log.commit().unwrap();
+        drop(log);
+
+        fs::write(
+            dir.join(format!("{:020}.log", 2)),
+            encode(&entry('c')).unwrap(),
+        )
+        .unwrap();
+
+        // A bad record in a segment that isn't the last one can't be a torn write, so the log is left for repair.
+        let mut bytes = fs::read(&segment).unwrap();
+        let last = bytes.len() - 1;
+
+        bytes[last] ^= 0xFF;
+        fs::write(&segment, &bytes).unwrap();
+
+        assert!(matches!(Log::open(&dir), Err(Error::Corrupt { .. })));
+        assert_eq!(fs::read(&segment).unwrap(), bytes);
+        assert_eq!(files(&dir).len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = env::temp_dir().join(format!("liveshare-snapshot-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut log, _, _) = Log::open(&dir).unwrap();
+        let image = Image {
+            document: String::new(),
+            version: vec![(1, 2)].into_iter().collect(),
+            atoms: entry('a').atoms,
+        };
+
+        log.append(&entry('a')).unwrap();
+        log.append(&entry('b')).unwrap();
+        log.snapshot(vec![image.clone()]).unwrap();
+        log.append(&entry('c')).unwrap();
+        log.commit().unwrap();
+
+        assert_eq!(log.pending(), 1);
+        // The first segment is covered by the snapshot, so it's gone.
+        assert_eq!(
+            files(&dir),
+            vec![format!("{:020}.log", 2), format!("{:020}.snapshot", 2)]
+        );
+
+        drop(log);
+
+        let (log, snapshot, entries) = Log::open(&dir).unwrap();
+        let snapshot = snapshot.unwrap();
+
+        assert_eq!(snapshot.index, 2);
+        assert_eq!(snapshot.documents, vec![image]);
+        assert_eq!(entries, vec![entry('c')]);
+        assert_eq!(log.pending(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_entries() {
+        let dir = env::temp_dir().join(format!("liveshare-missing-{}", process::id()));
+        let snapshot = dir.join(format!("{:020}.snapshot", 2));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (mut log, _, _) = Log::open(&dir).unwrap();
+
+        log.append(&entry('a')).unwrap();
+        log.append(&entry('b')).unwrap();
+        log.snapshot(Vec::new()).unwrap();
+        log.append(&entry('c')).unwrap();
+        log.commit().unwrap();
+        drop(log);
+
+        // The segment that the snapshot covers is gone, so the snapshot can't be skipped.
+        let bytes = fs::read(&snapshot).unwrap();
+        let last = bytes.len() - 1;
+        let mut corrupt = bytes.clone();
+
+        corrupt[last] ^= 0xFF;
+        fs::write(&snapshot, &corrupt).unwrap();
+
+        assert!(matches!(
+            Log::open(&dir),
+            Err(Error::Missing { from: 0, to: 2, .. })
+        ));
+
+        fs::write(&snapshot, &bytes).unwrap();
+
+        // Neither can a segment that doesn't follow on from the one before it.
+        fs::write(
+            dir.join(format!("{:020}.log", 4)),
+            encode(&entry('e')).unwrap(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            Log::open(&dir),
+            Err(Error::Missing { from: 3, to: 4, .. })
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
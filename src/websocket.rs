@@ -0,0 +1,100 @@
+use {
+    futures::{SinkExt, StreamExt},
+    std::{io::Error, net::SocketAddr},
+    tokio::{
+        io::{
+            self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+        },
+        try_join,
+    },
+    tokio_tungstenite::{
+        tungstenite::{
+            handshake::server::{ErrorResponse, Response},
+            http::StatusCode,
+            Message,
+        },
+        WebSocketStream,
+    },
+    tracing::{error, instrument},
+};
+
+pub use tokio_tungstenite::tungstenite::handshake::server::Request;
+
+/// Accepts the handshake of `request` unless it comes from a page that may not talk to the node.
+/// Browsers always say which page opened a WebSocket in its "Origin" header, so requests without one don't come from
+/// a page and are accepted. Pages are only accepted if they were served from the node's own address `local`, or from
+/// one of `origins`.
+/// The error can't be made smaller, since tungstenite decides the type of the callback that this is used in.
+#[allow(clippy::result_large_err)]
+pub fn check(
+    request: &Request,
+    response: Response,
+    local: SocketAddr,
+    origins: &[String],
+) -> Result<Response, ErrorResponse> {
+    let origin = match request.headers().get("Origin") {
+        Some(origin) => origin.to_str().unwrap_or_default(),
+        None => return Ok(response),
+    };
+
+    if allowed(origin, local, origins) {
+        return Ok(response);
+    }
+
+    let mut response = ErrorResponse::new(Some(format!("Origin {} is not allowed.", origin)));
+    *response.status_mut() = StatusCode::FORBIDDEN;
+
+    Err(response)
+}
+
+/// Whether a page served from `origin` may connect to the node listening on `local`.
+fn allowed(origin: &str, local: SocketAddr, origins: &[String]) -> bool {
+    let own = [
+        format!("http://{}", local),
+        format!("http://localhost:{}", local.port()),
+    ];
+
+    origins.iter().chain(&own).any(|allowed| allowed == origin)
+}
+
+/// Relays the frontend protocol between a browser's WebSocket and the node's end of `pipe`.
+/// Every text message from the browser becomes a line, and every line from the node becomes a text message, so the
+/// node can treat the browser like any other editor.
+#[instrument(level = "info", skip(ws, pipe))]
+pub async fn bridge<S>(ws: WebSocketStream<S>, pipe: DuplexStream)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut stream) = ws.split();
+    let (reader, mut writer) = io::split(pipe);
+    let mut lines = BufReader::new(reader).lines();
+
+    let incoming = async {
+        while let Some(message) = stream.next().await {
+            match message.map_err(Error::other)? {
+                Message::Text(text) => {
+                    // Raw newlines can only be whitespace between JSON tokens, so they're safe to replace.
+                    let line = text.replace(['\r', '\n'], " ");
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        writer.shutdown().await
+    };
+
+    let outgoing = async {
+        while let Some(line) = lines.next_line().await? {
+            sink.send(Message::Text(line)).await.map_err(Error::other)?;
+        }
+
+        sink.close().await.map_err(Error::other)
+    };
+
+    if let Err(e) = try_join!(incoming, outgoing) {
+        error!("WebSocket connection to the editor failed: {}.", e);
+    }
+}
@@ -0,0 +1,178 @@
+use {
+    crate::{
+        comment::Threads, document::Document, history::History, presence::Presences, tree::Tree,
+    },
+    std::collections::{BTreeMap, HashMap},
+};
+
+/// Identifies a document within the session, e.g. by its path relative to the shared directory.
+pub type DocumentId = String;
+
+/// The document that's used when the editor doesn't name one. It's always open.
+pub const DEFAULT_DOCUMENT: &str = "";
+
+/// A document along with everything that's attached to it.
+#[derive(Debug)]
+pub struct File {
+    pub document: Document,
+    pub threads: Threads,
+    /// Where everyone else's cursor is in this document.
+    pub presences: Presences,
+    /// Every operation applied to the document since it was opened.
+    pub history: History,
+}
+
+impl File {
+    fn new(site: i64) -> Self {
+        Self {
+            document: Document::new(site),
+            threads: Threads::new(site),
+            presences: Presences::new(),
+            history: History::new(),
+        }
+    }
+}
+
+/// The documents of a session that the editor has open, along with the tree of every file in the session.
+/// Peers only send us the events for these documents, so the state of any other document isn't kept.
+#[derive(Debug)]
+pub struct Workspace {
+    site: i64,
+    files: HashMap<DocumentId, File>,
+    /// The files and directories that are shared, whether they're open or not.
+    pub tree: Tree,
+}
+
+impl Workspace {
+    pub fn new(site: i64) -> Self {
+        let mut files = HashMap::new();
+
+        files.insert(DEFAULT_DOCUMENT.to_string(), File::new(site));
+
+        Self {
+            site,
+            files,
+            tree: Tree::new(site),
+        }
+    }
+
+    /// Opens `document`, starting out empty if it wasn't open yet.
+    pub fn open(&mut self, document: &str) -> &mut File {
+        let site = self.site;
+
+        self.files
+            .entry(document.to_string())
+            .or_insert_with(|| File::new(site))
+    }
+
+    /// Closes `document`, dropping its state. The default document can't be closed.
+    pub fn close(&mut self, document: &str) -> Option<File> {
+        if document == DEFAULT_DOCUMENT {
+            return None;
+        }
+
+        self.files.remove(document)
+    }
+
+    pub fn is_open(&self, document: &str) -> bool {
+        self.files.contains_key(document)
+    }
+
+    pub fn get(&self, document: &str) -> Option<&File> {
+        self.files.get(document)
+    }
+
+    pub fn get_mut(&mut self, document: &str) -> Option<&mut File> {
+        self.files.get_mut(document)
+    }
+
+    /// Changes the tree with `change`, moving the open documents of the files whose path changed along with them, so
+    /// that a renamed file keeps its text. Returns what `change` returned, along with the paths that moved.
+    /// A document isn't moved onto one that's open already, which can only happen if it isn't in the tree.
+    pub fn change_tree<T>(
+        &mut self,
+        change: impl FnOnce(&mut Tree) -> T,
+    ) -> (T, Vec<(DocumentId, DocumentId)>) {
+        let before: BTreeMap<_, _> = self
+            .tree
+            .files()
+            .into_iter()
+            .map(|file| (file.id, file.path))
+            .collect();
+        let res = change(&mut self.tree);
+        let moving: Vec<_> = self
+            .tree
+            .files()
+            .into_iter()
+            .filter_map(|file| {
+                let from = before.get(&file.id).filter(|from| **from != file.path)?;
+                let document = self.files.remove(from.as_str())?;
+                Some((from.clone(), file.path, document))
+            })
+            .collect();
+        let mut moved = Vec::new();
+
+        for (from, to, document) in moving {
+            if self.files.contains_key(&to) {
+                self.files.insert(from, document);
+            } else {
+                self.files.insert(to.clone(), document);
+                moved.push((from, to));
+            }
+        }
+
+        (res, moved)
+    }
+
+    /// The documents that are open, in no particular order.
+    pub fn documents(&self) -> impl Iterator<Item = &DocumentId> {
+        self.files.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Workspace, DEFAULT_DOCUMENT};
+    use crate::tree::Kind;
+
+    #[test]
+    fn test_open_and_close() {
+        let mut workspace = Workspace::new(1);
+
+        assert!(workspace.is_open(DEFAULT_DOCUMENT));
+        assert!(!workspace.is_open("src/main.rs"));
+
+        workspace.open("src/main.rs");
+
+        assert_eq!(workspace.documents().count(), 2);
+        assert!(workspace.close("src/main.rs").is_some());
+        assert!(workspace.close(DEFAULT_DOCUMENT).is_none());
+        assert!(workspace.get("src/main.rs").is_none());
+        assert!(workspace.get(DEFAULT_DOCUMENT).is_some());
+    }
+
+    #[test]
+    fn test_rename_open_document() {
+        let mut workspace = Workspace::new(1);
+
+        workspace.tree.create("src", Kind::Directory).unwrap();
+        workspace.tree.create("src/main.rs", Kind::File).unwrap();
+        workspace.tree.create("lib", Kind::Directory).unwrap();
+        workspace.tree.create("README.org", Kind::File).unwrap();
+        workspace.open("src/main.rs").document.seed("fn main() {}");
+        workspace.open("README.org");
+
+        let (_, moved) = workspace.change_tree(|tree| tree.rename("src", "lib/src"));
+
+        assert_eq!(
+            moved,
+            vec![("src/main.rs".to_string(), "lib/src/main.rs".to_string())]
+        );
+        assert!(!workspace.is_open("src/main.rs"));
+        assert!(workspace.is_open("README.org"));
+        assert_eq!(
+            workspace.get("lib/src/main.rs").unwrap().document.content(),
+            "fn main() {}"
+        );
+    }
+}